@@ -0,0 +1,250 @@
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use chrono::{Datelike, TimeZone, Timelike};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use crate::lostcontrol::{Repo, dprintln};
+use crate::lostcontrol::chunks::Manifest;
+use crate::lostcontrol::tree::{EntryKind, TreeEntry};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn from_name(name: &str) -> Option<ArchiveFormat> {
+        match name {
+            "tar" => Some(ArchiveFormat::Tar),
+            "tar.gz" | "tgz" => Some(ArchiveFormat::TarGz),
+            "zip" => Some(ArchiveFormat::Zip),
+            _ => None
+        }
+    }
+}
+
+// An entry as it ends up in the archive
+struct ArchiveEntry<'a> {
+    // relative to the archive root from the raw bytes of the path, directories end with a slash
+    name: PathBuf,
+    kind: EntryKind,
+    // None for the top-level directory of the prefix
+    entry: Option<&'a TreeEntry>,
+    // set for chunked files, entry.source is then the manifest
+    manifest: Option<Manifest>,
+    size: u64,
+    mode: u32,
+    // seconds since the epoch
+    mtime: i64,
+}
+
+impl ArchiveEntry<'_> {
+    fn stored(&self) -> std::io::Result<&TreeEntry> {
+        self.entry.ok_or_else(|| std::io::Error::other(format!("{} has no stored contents", self.name.display())))
+    }
+
+    // Chunked files are streamed chunk by chunk, everything else is read straight from the commit directory
+    fn open(&self) -> std::io::Result<Box<dyn Read + '_>> {
+        let entry = self.stored()?;
+        match (&entry.chunks, &self.manifest) {
+            (Some(chunk_store), Some(manifest)) => Ok(Box::new(chunk_store.open_file(manifest))),
+            _ => Ok(Box::new(File::open(&entry.source)?))
+        }
+    }
+
+    // Zip only takes UTF-8 names, bytes that aren't valid UTF-8 are replaced there
+    fn zip_name(&self) -> String {
+        self.name.to_string_lossy().into_owned()
+    }
+
+    fn link_target(&self) -> std::io::Result<std::path::PathBuf> {
+        std::fs::read_link(&self.stored()?.source)
+    }
+}
+
+#[cfg(unix)]
+fn entry_mode(metadata: &std::fs::Metadata, _kind: EntryKind) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(windows)]
+fn entry_mode(metadata: &std::fs::Metadata, kind: EntryKind) -> u32 {
+    match (kind, metadata.permissions().readonly()) {
+        (EntryKind::Directory, _) => 0o755,
+        (EntryKind::Symlink, _) => 0o777,
+        (_, true) => 0o444,
+        (_, false) => 0o644
+    }
+}
+
+fn write_tar<W: Write>(writer: W, entries: &[ArchiveEntry]) -> std::io::Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    for archive_entry in entries.iter() {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(archive_entry.mode);
+        header.set_mtime(archive_entry.mtime.max(0) as u64);
+        match archive_entry.kind {
+            EntryKind::Directory => {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                builder.append_data(&mut header, &archive_entry.name, std::io::empty())?;
+            },
+            EntryKind::Symlink => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                let target = archive_entry.link_target()?;
+                builder.append_link(&mut header, &archive_entry.name, target)?;
+            },
+            EntryKind::File => {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(archive_entry.size);
+                builder.append_data(&mut header, &archive_entry.name, archive_entry.open()?)?;
+            }
+        }
+    }
+    builder.into_inner()
+}
+
+// Zip stores the local time without a zone and can't go before 1980
+fn zip_time(mtime: i64) -> zip::DateTime {
+    let time = match chrono::Local.timestamp_opt(mtime, 0).single() {
+        Some(time) => time,
+        None => return zip::DateTime::default()
+    };
+    zip::DateTime::from_date_and_time(time.year() as u16, time.month() as u8, time.day() as u8,
+        time.hour() as u8, time.minute() as u8, time.second() as u8).unwrap_or_default()
+}
+
+fn write_zip(file: File, entries: &[ArchiveEntry]) -> zip::result::ZipResult<()> {
+    let mut writer = zip::ZipWriter::new(file);
+    for archive_entry in entries.iter() {
+        let options = zip::write::FileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .unix_permissions(archive_entry.mode)
+            .last_modified_time(zip_time(archive_entry.mtime));
+        match archive_entry.kind {
+            EntryKind::Directory => writer.add_directory(archive_entry.zip_name(), options)?,
+            EntryKind::Symlink => {
+                let target = archive_entry.link_target()?;
+                writer.add_symlink(archive_entry.zip_name(), target.to_string_lossy(), options)?;
+            },
+            EntryKind::File => {
+                writer.start_file(archive_entry.zip_name(), options)?;
+                std::io::copy(&mut archive_entry.open()?, &mut writer)?;
+            }
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+impl Repo {
+    // Writes the full tree of a commit on the current branch into an archive at output, every path below prefix.
+    // Files and symlinks keep the mode and modification time of their stored copy, which is from the commit
+    // that last changed them, chunked files the mode recorded in their manifest. Directories get the time of the archived commit. Returns the number of entries
+    pub fn archive(&self, commit_id: usize, format: ArchiveFormat, output: &Path, prefix: Option<&str>) -> Result<usize, ()> {
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let commit = match branch_config.get_commit(commit_id) {
+            Some(commit) => commit,
+            None => {
+                dprintln!("[ERROR] Commit {} does not exist on branch {}", commit_id, branch_config.name);
+                return Err(());
+            }
+        };
+        let commit_time = chrono::DateTime::parse_from_rfc3339(&commit.creation_datetime)
+            .map(|time| time.timestamp())
+            .unwrap_or_default();
+        let tree = self.get_tree(&branch_config, commit_id)?;
+
+        let prefix = match prefix {
+            Some(prefix) if !prefix.is_empty() => format!("{}/", prefix.trim_end_matches('/')),
+            _ => String::new()
+        };
+        let mut entries: Vec<ArchiveEntry> = vec![];
+        if !prefix.is_empty() {
+            // the top-level directory itself, so extracting it doesn't leave it with the default mode
+            entries.push(ArchiveEntry {
+                name: PathBuf::from(&prefix),
+                kind: EntryKind::Directory,
+                entry: None,
+                manifest: None,
+                size: 0,
+                mode: 0o755,
+                mtime: commit_time,
+            });
+        }
+        for (path, entry) in tree.iter() {
+            let metadata = match std::fs::symlink_metadata(&entry.source) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    dprintln!("[ERROR] Cannot read {} of commit {}: {}", path, commit_id, e);
+                    return Err(());
+                }
+            };
+            let mtime = match entry.kind {
+                EntryKind::Directory => commit_time,
+                _ => metadata.modified().ok()
+                    .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|since_epoch| since_epoch.as_secs() as i64)
+                    .unwrap_or(commit_time)
+            };
+            // the size and mode of a chunked file are in its manifest, the metadata is the manifest's own
+            let manifest = match (entry.kind, &entry.chunks) {
+                (EntryKind::File, Some(_)) => match Manifest::from_file(&entry.source) {
+                    Ok(manifest) => Some(manifest),
+                    Err(e) => {
+                        dprintln!("[ERROR] Cannot read the manifest of {} in commit {}: {}", path, commit_id, e);
+                        return Err(());
+                    }
+                },
+                _ => None
+            };
+            let (size, mode) = match &manifest {
+                // manifests written by older versions have no mode
+                Some(manifest) => (manifest.size, manifest.mode.unwrap_or_else(|| entry_mode(&metadata, entry.kind))),
+                None => (metadata.len(), entry_mode(&metadata, entry.kind))
+            };
+            let mut name = OsString::from(&prefix);
+            name.push(path.to_path_buf().as_os_str());
+            if entry.kind == EntryKind::Directory {
+                name.push("/");
+            }
+            entries.push(ArchiveEntry {
+                name: PathBuf::from(name),
+                kind: entry.kind,
+                entry: Some(entry),
+                manifest,
+                size,
+                mode,
+                mtime,
+            });
+        }
+
+        let file = match File::create(output) {
+            Ok(file) => file,
+            Err(e) => {
+                dprintln!("[ERROR] Cannot create archive {}: {}", output.display(), e);
+                return Err(());
+            }
+        };
+        let result = match format {
+            ArchiveFormat::Tar => write_tar(file, &entries).map(|_| ()),
+            ArchiveFormat::TarGz => write_tar(GzEncoder::new(file, Compression::default()), &entries)
+                .and_then(|encoder| encoder.finish())
+                .map(|_| ()),
+            ArchiveFormat::Zip => write_zip(file, &entries).map_err(std::io::Error::from)
+        };
+        if let Err(e) = result {
+            dprintln!("[ERROR] Cannot write archive {}: {}", output.display(), e);
+            let _ = std::fs::remove_file(output);
+            return Err(());
+        }
+        Ok(entries.len())
+    }
+}
@@ -0,0 +1,324 @@
+use std::path::{Path, PathBuf};
+use std::io::{BufReader, BufRead, Write};
+use serde::{self, Deserialize, Serialize};
+use serde_yaml::{self};
+
+use crate::lostcontrol::{Commit, Repo, RepoPath, CURRENT_CONFIG_VERSION, dprintln};
+use crate::lostcontrol::tree::ChangeKind;
+
+pub(crate) const BISECT_DIR: &str = "bisect";
+const BISECT_CONFIG_FILE: &str = "bisect.conf";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BisectMark {
+    Good,
+    Bad,
+    Skip,
+}
+
+// Where the search stands after a commit was marked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BisectStep {
+    // a good and a bad commit are needed before the search can start
+    NeedMarks,
+    // the commit was restored into the working tree and waits to be marked, remaining counts the commits still in question
+    Testing { commit_id: usize, remaining: usize },
+    Found(usize),
+    // skipped commits are in the way, any of these may be the first bad one
+    Ambiguous(Vec<usize>),
+}
+
+// Progress of a running bisect, current is the commit the working tree holds
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BisectState {
+    #[serde(skip)]
+    closed: bool,
+    #[serde(skip)]
+    modified: bool,
+    #[serde(skip)]
+    config_path: PathBuf,
+    pub branch: String,
+    pub original_commit: usize,
+    pub current: usize,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub bad: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub good: Vec<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub skipped: Vec<usize>,
+}
+
+impl BisectState {
+    fn new(config_path: &Path, branch: String, original_commit: usize) -> BisectState {
+        BisectState {
+            closed: false,
+            modified: true,
+            config_path: config_path.to_path_buf(),
+            branch,
+            original_commit,
+            current: original_commit,
+            bad: None,
+            good: vec![],
+            skipped: vec![],
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<BisectState, ()> {
+        let mut bisect_config = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                dprintln!("[ERROR] Cannot load Bisect State {}: {}", path.display(), e);
+                return Err(());
+            }
+        };
+        let mut version = String::new();
+        let mut contents = String::new();
+
+        let buf_reader = BufReader::new(&mut bisect_config);
+        for (index, line) in buf_reader.lines().enumerate() {
+            match index {
+                0 => version = line.unwrap(),
+                _ => {
+                    let line_string: String = line.unwrap() + "\n";
+                    contents.push_str(line_string.as_str());
+                }
+            }
+        }
+
+        if version != CURRENT_CONFIG_VERSION {
+            dprintln!("[ERROR] Bisect State {}: Bisect State version {} is not supported", path.display(), version);
+            return Err(())
+        }
+
+        let mut state: BisectState = match serde_yaml::from_str(&contents) {
+            Ok(state) => state,
+            Err(_) => {
+                dprintln!("[ERROR] Bisect State {}: Cannot parse Bisect State", path.display());
+                return Err(());
+            }
+        };
+        state.config_path = path.to_path_buf();
+        Ok(state)
+    }
+
+    fn mark(&mut self, mark: BisectMark, commit_id: usize) {
+        self.good.retain(|good| *good != commit_id);
+        self.skipped.retain(|skipped| *skipped != commit_id);
+        match mark {
+            BisectMark::Good => self.good.push(commit_id),
+            BisectMark::Bad => self.bad = Some(commit_id),
+            BisectMark::Skip => self.skipped.push(commit_id),
+        }
+        self.modified = true;
+    }
+
+    fn set_current(&mut self, commit_id: usize) {
+        self.current = commit_id;
+        self.modified = true;
+    }
+
+    // Picks the commit to test next from the commits of the branch
+    fn next_step(&self, commits: &[Commit]) -> Result<BisectStep, ()> {
+        let bad = match self.bad {
+            Some(bad) if !self.good.is_empty() => bad,
+            _ => return Ok(BisectStep::NeedMarks)
+        };
+        let position = |commit_id: usize| commits.iter().position(|commit| commit.id == commit_id);
+        let bad_position = match position(bad) {
+            Some(bad_position) => bad_position,
+            None => {
+                dprintln!("[ERROR] Bad commit {} is no longer on the branch", bad);
+                return Err(());
+            }
+        };
+        for good in self.good.iter().filter(|good| position(**good).is_none()) {
+            dprintln!("[WARN] Good commit {} is no longer on the branch", good);
+        }
+        let good_position = match self.good.iter().filter_map(|good| position(*good)).max() {
+            Some(good_position) => good_position,
+            None => {
+                dprintln!("[ERROR] None of the good commits is on the branch anymore");
+                return Err(());
+            }
+        };
+        if good_position >= bad_position {
+            dprintln!("[ERROR] Good commit {} does not come before bad commit {}", commits[good_position].id, bad);
+            return Err(());
+        }
+
+        let between = &commits[good_position + 1..bad_position];
+        let candidates: Vec<usize> = (good_position + 1..bad_position)
+            .filter(|index| !self.skipped.contains(&commits[*index].id))
+            .collect();
+        if candidates.is_empty() {
+            if between.is_empty() {
+                return Ok(BisectStep::Found(bad));
+            }
+            let mut suspects: Vec<usize> = between.iter().map(|commit| commit.id).collect();
+            suspects.push(bad);
+            return Ok(BisectStep::Ambiguous(suspects));
+        }
+        // the candidate closest to the middle halves the range, skipped commits push it aside
+        let middle = (good_position + bad_position) / 2;
+        let next = *candidates.iter().min_by_key(|index| index.abs_diff(middle)).unwrap();
+        Ok(BisectStep::Testing {
+            commit_id: commits[next].id,
+            remaining: between.len() + 1,
+        })
+    }
+
+    pub fn close(&mut self) {
+        if self.closed || !self.modified {
+            return;
+        }
+        let config_str = serde_yaml::to_string(&self).unwrap();
+        let mut bisect_config = match std::fs::File::create(&self.config_path) {
+            Ok(file) => file,
+            Err(e) => panic!("Cannot create Bisect State {}: {}", self.config_path.display(), e)
+        };
+
+        writeln!(bisect_config, "{}", CURRENT_CONFIG_VERSION).unwrap();
+        write!(bisect_config, "{}", config_str).unwrap();
+
+        self.closed = true;
+    }
+}
+
+impl Drop for BisectState {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Repo {
+    fn bisect_dir(&self) -> PathBuf {
+        self.repos_dir().join(BISECT_DIR)
+    }
+
+    fn bisect_state_path(&self) -> PathBuf {
+        self.bisect_dir().join(BISECT_CONFIG_FILE)
+    }
+
+    pub fn bisect_in_progress(&self) -> bool {
+        self.bisect_state_path().exists()
+    }
+
+    pub fn get_bisect_state(&self) -> Result<BisectState, ()> {
+        BisectState::from_file(&self.bisect_state_path())
+    }
+
+    // Tracked entries with uncommitted changes, restoring other commits would throw them away
+    pub fn bisect_blockers(&self) -> Result<Vec<RepoPath>, ()> {
+        Ok(self.working_changes()?.into_iter()
+            .filter(|change| change.kind != ChangeKind::Added)
+            .map(|change| change.path)
+            .collect())
+    }
+
+    // Starts searching the history of the current branch, callers are expected to check bisect_blockers first
+    pub fn bisect_start(&mut self, bad: Option<usize>, good: Vec<usize>) -> Result<BisectStep, ()> {
+        if let Some(operation) = self.pending_operation() {
+            dprintln!("[ERROR] A {} is already in progress", operation);
+            return Err(());
+        }
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let commits = branch_config.get_commits();
+        if let Some(missing) = bad.iter().chain(good.iter()).find(|id| branch_config.get_commit(**id).is_none()) {
+            dprintln!("[ERROR] Commit {} does not exist on branch {}", missing, branch_config.name);
+            return Err(());
+        }
+        if let Err(e) = std::fs::create_dir_all(self.bisect_dir()) {
+            dprintln!("[ERROR] Cannot create bisect directory {}: {}", self.bisect_dir().display(), e);
+            return Err(());
+        }
+
+        let mut state = BisectState::new(&self.bisect_state_path(), self.current_branch.clone(), branch_config.current_commit);
+        if let Some(bad) = bad {
+            state.mark(BisectMark::Bad, bad);
+        }
+        for good in good.into_iter() {
+            state.mark(BisectMark::Good, good);
+        }
+        match state.next_step(&commits) {
+            Ok(step) => self.bisect_checkout(&mut state, step),
+            Err(()) => {
+                state.closed = true;
+                let _ = std::fs::remove_dir_all(self.bisect_dir());
+                Err(())
+            }
+        }
+    }
+
+    // Marks the passed commit, or the one being tested, and restores the next one to test
+    pub fn bisect_mark(&mut self, mark: BisectMark, commit_id: Option<usize>) -> Result<BisectStep, ()> {
+        let mut state = match self.get_bisect_state() {
+            Ok(state) => state,
+            Err(()) => {
+                dprintln!("[ERROR] No bisect in progress");
+                return Err(());
+            }
+        };
+        let commit_id = commit_id.unwrap_or(state.current);
+        let branch_config = self.get_branch(&state.branch)?;
+        if branch_config.get_commit(commit_id).is_none() {
+            dprintln!("[ERROR] Commit {} does not exist on branch {}", commit_id, branch_config.name);
+            return Err(());
+        }
+        state.mark(mark, commit_id);
+        let step = state.next_step(&branch_config.get_commits())?;
+        self.bisect_checkout(&mut state, step)
+    }
+
+    // Where the search stands without marking anything, e.g. to continue a bisect run
+    pub fn bisect_step(&self) -> Result<BisectStep, ()> {
+        let state = self.get_bisect_state()?;
+        let branch_config = self.get_branch(&state.branch)?;
+        state.next_step(&branch_config.get_commits())
+    }
+
+    // Restores the commit to test, or the first bad one once it is found, into the working tree
+    fn bisect_checkout(&self, state: &mut BisectState, step: BisectStep) -> Result<BisectStep, ()> {
+        let target = match &step {
+            BisectStep::Testing { commit_id, .. } => *commit_id,
+            BisectStep::Found(commit_id) => *commit_id,
+            _ => return Ok(step)
+        };
+        if target != state.current {
+            let branch_config = self.get_branch(&state.branch)?;
+            let current_tree = self.get_tree(&branch_config, state.current)?;
+            let target_tree = self.get_tree(&branch_config, target)?;
+            let blockers = self.checkout_blockers(&current_tree, &target_tree);
+            if !blockers.is_empty() {
+                for path in blockers.iter() {
+                    dprintln!("[ERROR] {} has uncommitted changes or would be overwritten", path);
+                }
+                return Err(());
+            }
+            self.checkout_tree(&current_tree, &target_tree)?;
+            state.set_current(target);
+        }
+        Ok(step)
+    }
+
+    // Ends the search and puts the working tree back to the commit it started from
+    pub fn bisect_reset(&mut self) -> Result<usize, ()> {
+        let mut state = match self.get_bisect_state() {
+            Ok(state) => state,
+            Err(()) => {
+                dprintln!("[ERROR] No bisect in progress");
+                return Err(());
+            }
+        };
+        let branch_config = self.get_branch(&state.branch)?;
+        let current_tree = self.get_tree(&branch_config, state.current)?;
+        let original_tree = self.get_tree(&branch_config, state.original_commit)?;
+        self.checkout_tree(&current_tree, &original_tree)?;
+
+        state.closed = true;
+        if let Err(e) = std::fs::remove_dir_all(self.bisect_dir()) {
+            dprintln!("[ERROR] Cannot remove bisect directory {}: {}", self.bisect_dir().display(), e);
+            return Err(());
+        }
+        Ok(state.original_commit)
+    }
+}
@@ -0,0 +1,99 @@
+use std::path::Path;
+use std::rc::Rc;
+use similar::{capture_diff_slices, Algorithm, DiffTag};
+
+use crate::lostcontrol::{Commit, Repo, RepoPath, dprintln};
+use crate::lostcontrol::diff::is_binary;
+use crate::lostcontrol::tree::{EntryKind, Tree};
+
+// A line of a file together with the commit that last changed it
+pub struct BlameLine {
+    pub commit: Rc<Commit>,
+    pub content: String,
+}
+
+fn split_lines(text: &str) -> Vec<&str> {
+    text.lines().collect()
+}
+
+impl Repo {
+    // The contents path had right after commit, which has to store it as a text file
+    fn committed_text(&self, commit_dir: &Path, commit: &Commit, path: &RepoPath) -> Result<String, ()> {
+        let mut tree = Tree::new();
+        self.overlay_stored(&mut tree, commit_dir, &commit.chunked_files);
+        let contents = match tree.get(path) {
+            Some(entry) if entry.kind == EntryKind::File => entry.read(),
+            _ => {
+                dprintln!("[ERROR] {} is not a file in commit {}", path, commit.id);
+                return Err(());
+            }
+        };
+        match contents {
+            Ok(contents) if !is_binary(&contents) => Ok(String::from_utf8_lossy(&contents).into_owned()),
+            Ok(_) => {
+                dprintln!("[ERROR] {} is a binary file in commit {}", path, commit.id);
+                Err(())
+            },
+            Err(e) => {
+                dprintln!("[ERROR] Cannot read {} of commit {}: {}", path, commit.id, e);
+                Err(())
+            }
+        }
+    }
+
+    // Walks the commits of the current branch up to commit_id and attributes every line of the file
+    // to the last commit that changed it
+    pub fn blame(&self, path: &Path, commit_id: usize) -> Result<Vec<BlameLine>, ()> {
+        let (_, key) = self.resolve_path(path)?;
+        let branch_config = self.get_branch(&self.current_branch)?;
+        if branch_config.get_commit(commit_id).is_none() {
+            dprintln!("[ERROR] Commit {} does not exist on branch {}", commit_id, branch_config.name);
+            return Err(());
+        }
+
+        let mut lines: Vec<BlameLine> = vec![];
+        let mut found = false;
+        for commit in branch_config.get_commits().iter() {
+            if commit.removed_files.iter().any(|removed| *removed == key || key.is_inside(removed)) {
+                lines.clear();
+                found = false;
+            }
+            if commit.modified_files.contains(&key) {
+                let text = self.committed_text(&self.commit_dir(&branch_config, commit.id), commit, &key)?;
+                let new_lines = split_lines(&text);
+                let old_lines: Vec<&str> = lines.iter().map(|line| line.content.as_str()).collect();
+                let changed_by = Rc::new(commit.clone());
+                let mut blamed: Vec<BlameLine> = Vec::with_capacity(new_lines.len());
+                for op in capture_diff_slices(Algorithm::Myers, &old_lines, &new_lines) {
+                    let (tag, old_range, new_range) = op.as_tag_tuple();
+                    if tag == DiffTag::Equal {
+                        for index in old_range {
+                            blamed.push(BlameLine {
+                                commit: Rc::clone(&lines[index].commit),
+                                content: lines[index].content.clone(),
+                            });
+                        }
+                        continue;
+                    }
+                    for line in &new_lines[new_range] {
+                        blamed.push(BlameLine {
+                            commit: Rc::clone(&changed_by),
+                            content: line.to_string(),
+                        });
+                    }
+                }
+                lines = blamed;
+                found = true;
+            }
+            if commit.id == commit_id {
+                break;
+            }
+        }
+
+        if !found {
+            dprintln!("[ERROR] {} does not exist in commit {}", key, commit_id);
+            return Err(());
+        }
+        Ok(lines)
+    }
+}
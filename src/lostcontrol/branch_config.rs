@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::{BufReader, BufRead, Write};
 use serde::{self, Deserialize, Serialize};
 use serde_yaml::{self};
@@ -15,26 +15,30 @@ pub struct BranchConfig {
     config_path: PathBuf,
     pub name: String,
     pub current_commit: usize,
-    commits: Vec<Commit>
+    commits: Vec<Commit>,
+    // removed commits stay stored, so the reflog can bring them back
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    detached_commits: Vec<Commit>
 }
 
 impl BranchConfig {
-    pub fn new(name: String, repo_root_path: &PathBuf) -> BranchConfig {
+    pub fn new(name: String, repo_root_path: &Path) -> BranchConfig {
         let mut config_path = repo_root_path.join(&name).join(&name);
         config_path.set_extension("conf");
 
         BranchConfig {
             closed: false,
             modified: true,
-            config_path: config_path,
-            name: name,
+            config_path,
+            name,
             current_commit: 0,
-            commits: vec![]
+            commits: vec![],
+            detached_commits: vec![]
         }
     }
 
-    pub fn from_file(path: &PathBuf) -> Result<BranchConfig, ()> {
-        let mut branch_config = match std::fs::File::open(&path) {
+    pub fn from_file(path: &Path) -> Result<BranchConfig, ()> {
+        let mut branch_config = match std::fs::File::open(path) {
             Ok(file) => file,
             Err(e) => {
                 dprintln!("[ERROR] Cannot load Branch Config {}: {}", path.display(), e);
@@ -49,8 +53,8 @@ impl BranchConfig {
             match index {
                 0 => version = line.unwrap(),
                 _ => {
-                    let line_string: String = String::from(line.unwrap()) + "\n";
-                    contents.push_str(&line_string.as_str());
+                    let line_string: String = line.unwrap() + "\n";
+                    contents.push_str(line_string.as_str());
                 }
             }
         }
@@ -81,44 +85,80 @@ impl BranchConfig {
     }
 
     pub fn remove_commit(&mut self, commit_id: usize) -> Result<(), ()> {
-        if self.commits.len() == 0 {
+        if self.commits.is_empty() {
             dprintln!("[ERROR] Cannot remove commit {} from branch {}: Branch is empty", commit_id, self.name);
             return Err(());
         }
-        let mut index = 0;
-        for commit in self.commits.iter() {
-            if commit.id == commit_id {
-                self.commits.remove(index);
-                self.current_commit = if self.commits.len() > 0 {
-                    self.commits.last().unwrap().id
-                }
-                else {
-                    0
-                };
-                self.modified = true;
-                return Ok(());
-            }
-            index += 1;
+        if let Some(index) = self.commits.iter().position(|commit| commit.id == commit_id) {
+            let commit = self.commits.remove(index);
+            self.detached_commits.push(commit);
+            self.current_commit = match self.commits.last() {
+                Some(commit) => commit.id,
+                None => 0
+            };
+            self.modified = true;
+            return Ok(());
         }
         dprintln!("[ERROR] Cannot remove commit {} from branch {}: Commit not found", commit_id, self.name);
         Err(())
     }
 
-    pub fn get_commit(&self, commit_id: usize) -> Option<&Commit> {
-        for commit in self.commits.iter() {
-            if commit.id == commit_id {
-                return Some(commit);
-            }
+    // Ids are never reused, even after commits in between were removed
+    pub fn next_commit_id(&self) -> usize {
+        self.commits.iter().chain(self.detached_commits.iter()).map(|commit| commit.id).max().unwrap_or(0) + 1
+    }
+
+    // Keeps a commit stored without making it part of the history
+    pub fn push_detached(&mut self, commit: Commit) {
+        if self.closed {
+            return;
         }
-        None
+        self.detached_commits.push(commit);
+        self.modified = true;
     }
 
-    pub fn get_commits<'vlt>(&'vlt self) -> Vec<Commit> {
+    // Makes the passed stored commits the history of the branch, every other stored commit is detached
+    pub fn set_history(&mut self, commits: Vec<Commit>) {
+        if self.closed {
+            return;
+        }
+        let mut stored: Vec<Commit> = self.commits.drain(..).chain(self.detached_commits.drain(..)).collect();
+        stored.retain(|commit| !commits.iter().any(|kept| kept.id == commit.id));
+        self.current_commit = commits.last().map(|commit| commit.id).unwrap_or(0);
+        self.commits = commits;
+        self.detached_commits = stored;
+        self.modified = true;
+    }
+
+    // The commit that comes before commit_id on this branch, 0 for the first one
+    pub fn get_parent_id(&self, commit_id: usize) -> Option<usize> {
+        let index = self.commits.iter().position(|commit| commit.id == commit_id)?;
+        match index {
+            0 => Some(0),
+            _ => Some(self.commits[index - 1].id)
+        }
+    }
+
+    pub fn get_commit(&self, commit_id: usize) -> Option<&Commit> {
+        self.commits.iter().find(|commit| commit.id == commit_id)
+    }
+
+    pub fn get_commits(&self) -> Vec<Commit> {
         self.commits.clone()
     }
 
-    pub fn commit_count(&self) -> usize {
-        self.commits.len() as usize
+    pub fn get_detached_commits(&self) -> Vec<Commit> {
+        self.detached_commits.clone()
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+        self.modified = true;
+    }
+
+    // Drops the branch config without writing it
+    pub fn discard(&mut self) {
+        self.closed = true;
     }
 
     pub fn close(&mut self) {
@@ -132,7 +172,7 @@ impl BranchConfig {
             Err(e) => panic!("Cannot create Branch Config {}: {}", self.name, e)
         };
 
-        write!(branch_config, "{}\n", CURRENT_CONFIG_VERSION).unwrap();
+        writeln!(branch_config, "{}", CURRENT_CONFIG_VERSION).unwrap();
         write!(branch_config, "{}", config_str).unwrap();
         //dprintln!("[INFO] Branch Config {} updated!", self.name);
 
@@ -0,0 +1,186 @@
+use serde::{self, Deserialize, Serialize};
+
+use crate::lostcontrol::{Commit, CommitSource, Repo, RepoPath, dprintln};
+use crate::lostcontrol::tree::{compare, same_entry, Tree};
+
+// A sequence of cherry-picks that stopped because of conflicts, kept in the metafile until it is continued or aborted
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingCherryPick {
+    // the current commit before the first pick, abort goes back there
+    pub original_commit: usize,
+    pub current: CommitSource,
+    pub remaining: Vec<CommitSource>,
+    pub conflicts: Vec<RepoPath>,
+}
+
+impl Repo {
+    // The picked commit together with the trees before and after it
+    pub(crate) fn pick_trees(&self, source: &CommitSource) -> Result<(Commit, Tree, Tree), ()> {
+        let branch_config = self.get_branch(&source.branch)?;
+        let (commit, parent_id) = match (branch_config.get_commit(source.commit_id), branch_config.get_parent_id(source.commit_id)) {
+            (Some(commit), Some(parent_id)) => (commit.clone(), parent_id),
+            _ => {
+                dprintln!("[ERROR] Commit {} does not exist", source);
+                return Err(());
+            }
+        };
+        let parent_tree = self.get_tree(&branch_config, parent_id)?;
+        let commit_tree = self.get_tree(&branch_config, source.commit_id)?;
+        Ok((commit, parent_tree, commit_tree))
+    }
+
+    // Entries touched by any of the commits that have uncommitted changes, picking would mix them into the new commits
+    pub fn cherry_pick_blockers(&self, sources: &[CommitSource]) -> Result<Vec<RepoPath>, ()> {
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let head_tree = self.get_tree(&branch_config, branch_config.current_commit)?;
+
+        let mut blockers: Vec<RepoPath> = vec![];
+        for source in sources.iter() {
+            if !self.branches.contains(&source.branch) {
+                dprintln!("[ERROR] Branch {} does not exist", source.branch);
+                return Err(());
+            }
+            let (_, parent_tree, commit_tree) = self.pick_trees(source)?;
+            for change in compare(&parent_tree, &commit_tree) {
+                if !blockers.contains(&change.path)
+                    && !same_entry(self.working_entry(&change.path).as_ref(), head_tree.get(&change.path)) {
+                    blockers.push(change.path);
+                }
+            }
+        }
+        Ok(blockers)
+    }
+
+    // Applies the changes of every commit to the current branch and commits each one with its original message and author.
+    // Returns the conflicting entries if a pick stopped, the remaining picks wait for cherry_pick_continue then.
+    // Callers are expected to check cherry_pick_blockers first
+    pub fn cherry_pick(&mut self, sources: Vec<CommitSource>) -> Result<Vec<RepoPath>, ()> {
+        if let Some(operation) = self.pending_operation() {
+            dprintln!("[ERROR] A {} is already in progress", operation);
+            return Err(());
+        }
+        if !self.staged_files.is_empty() || !self.staged_removals.is_empty() {
+            dprintln!("[ERROR] Commit or unstage the staged files before cherry-picking");
+            return Err(());
+        }
+        let mut remaining = sources;
+        if remaining.is_empty() {
+            return Err(());
+        }
+        let current = remaining.remove(0);
+
+        let branch_config = self.get_branch(&self.current_branch)?;
+        self.set_pending_cherry_pick(Some(PendingCherryPick {
+            original_commit: branch_config.current_commit,
+            current,
+            remaining,
+            conflicts: vec![],
+        }));
+        drop(branch_config);
+        self.pick_pending()
+    }
+
+    // Picks the current commit of the pending sequence and everything after it, until a pick runs into conflicts
+    fn pick_pending(&mut self) -> Result<Vec<RepoPath>, ()> {
+        while let Some(pending) = self.pending_cherry_pick.clone() {
+            let (commit, parent_tree, commit_tree) = self.pick_trees(&pending.current)?;
+            let theirs_label = format!("commit {}", pending.current);
+            let outcome = self.apply_changes(&parent_tree, &commit_tree, "current", &theirs_label)?;
+
+            self.set_staged_files(outcome.changed_files);
+            self.staged_removals = outcome.removed_files;
+            if !outcome.conflicts.is_empty() {
+                self.pending_cherry_pick.as_mut().unwrap().conflicts = outcome.conflicts.clone();
+                return Ok(outcome.conflicts);
+            }
+            self.commit_pick(&commit, &pending.current)?;
+        }
+        Ok(vec![])
+    }
+
+    // Commits the staged result of the current pick and moves on to the next commit of the sequence
+    fn commit_pick(&mut self, commit: &Commit, source: &CommitSource) -> Result<(), ()> {
+        if self.staged_files.is_empty() && self.staged_removals.is_empty() {
+            // everything the commit changes is already part of the current branch
+            dprintln!("[INFO] Commit {} is already applied, skipping it", source);
+        }
+        else {
+            let mut picked = Commit::new(0, commit.message.clone(), commit.author.clone(), vec![]);
+            picked.picked_from = Some(source.clone());
+            self.record_commit(picked, &format!("cherry-pick {}", source))?;
+        }
+
+        let mut pending = self.pending_cherry_pick.clone().unwrap();
+        pending.conflicts.clear();
+        if pending.remaining.is_empty() {
+            self.set_pending_cherry_pick(None);
+        }
+        else {
+            pending.current = pending.remaining.remove(0);
+            self.set_pending_cherry_pick(Some(pending));
+        }
+        Ok(())
+    }
+
+    // Conflicting entries of the current pick that were not staged yet or still contain conflict markers
+    pub fn cherry_pick_unresolved(&self) -> Vec<RepoPath> {
+        match &self.pending_cherry_pick {
+            Some(pending) => self.unresolved_conflicts(&pending.conflicts),
+            None => vec![]
+        }
+    }
+
+    // Commits the resolved pick and carries on with the remaining ones, callers are expected to check cherry_pick_unresolved first
+    pub fn cherry_pick_continue(&mut self) -> Result<Vec<RepoPath>, ()> {
+        let pending = match &self.pending_cherry_pick {
+            Some(pending) => pending.clone(),
+            None => {
+                dprintln!("[ERROR] No cherry-pick in progress");
+                return Err(());
+            }
+        };
+        self.stage_resolved_removals(&pending.conflicts);
+        let (commit, _, _) = self.pick_trees(&pending.current)?;
+        self.commit_pick(&commit, &pending.current)?;
+        self.pick_pending()
+    }
+
+    // Removes the commits the sequence created and puts the working tree back to the state before the first pick
+    pub fn cherry_pick_abort(&mut self) -> Result<(), ()> {
+        let pending = match &self.pending_cherry_pick {
+            Some(pending) => pending.clone(),
+            None => {
+                dprintln!("[ERROR] No cherry-pick in progress");
+                return Err(());
+            }
+        };
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let head_tree = self.get_tree(&branch_config, branch_config.current_commit)?;
+        let original_tree = self.get_tree(&branch_config, pending.original_commit)?;
+
+        // first the entries of the pick that stopped, then whatever the finished picks committed
+        let mut touched: Vec<RepoPath> = match self.pick_trees(&pending.current) {
+            Ok((_, parent_tree, commit_tree)) => compare(&parent_tree, &commit_tree).into_iter().map(|change| change.path).collect(),
+            Err(()) => vec![]
+        };
+        touched.extend(self.staged_files.iter().cloned());
+        touched.extend(self.staged_removals.iter().cloned());
+        self.reset_working_entries(&touched, &head_tree)?;
+        let committed: Vec<RepoPath> = compare(&head_tree, &original_tree).into_iter().map(|change| change.path).collect();
+        self.reset_working_entries(&committed, &original_tree)?;
+
+        let picked_commits: Vec<usize> = branch_config.get_commits().iter()
+            .skip_while(|commit| pending.original_commit != 0 && commit.id != pending.original_commit)
+            .skip(if pending.original_commit == 0 { 0 } else { 1 })
+            .map(|commit| commit.id)
+            .collect();
+        drop(branch_config);
+        for commit_id in picked_commits {
+            self.remove_commit(commit_id)?;
+        }
+
+        self.unstage_all();
+        self.set_pending_cherry_pick(None);
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashSet;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use serde::{self, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::lostcontrol::dprintln;
+
+// Boundaries are cut where the rolling hash has its low bits cleared,
+// which gives chunks of about 1 MiB that only move around where the file was edited
+const MIN_CHUNK_SIZE: usize = 256 * 1024;
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+const BOUNDARY_MASK: u64 = (1 << 20) - 1;
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+const GEAR_SEED: u64 = 0x6c6f7374636f6e74;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChunkRef {
+    pub hash: String,
+    pub size: usize,
+}
+
+// Stored in the commit directory in place of a large file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub size: u64,
+    pub chunks: Vec<ChunkRef>,
+    // permission bits of the stored file, manifests written by older versions have none
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub mode: Option<u32>,
+}
+
+impl Manifest {
+    pub fn from_file(path: &Path) -> std::io::Result<Manifest> {
+        let contents = std::fs::read_to_string(path)?;
+        let manifest: Manifest = serde_yaml::from_str(&contents).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        // chunk hashes become file names in the store, a damaged manifest must not point anywhere else
+        if let Some(chunk_ref) = manifest.chunks.iter().find(|chunk_ref| !is_chunk_hash(&chunk_ref.hash)) {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid chunk hash {:?} in {}", chunk_ref.hash, path.display())));
+        }
+        Ok(manifest)
+    }
+
+    // The mode is left out, a file whose permissions changed still has the same contents
+    pub fn same_contents(&self, other: &Manifest) -> bool {
+        self.size == other.size && self.chunks == other.chunks
+    }
+}
+
+#[cfg(unix)]
+fn file_mode(path: &Path) -> std::io::Result<Option<u32>> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(Some(std::fs::metadata(path)?.permissions().mode() & 0o7777))
+}
+
+// Only unix permission bits are recorded, std::fs::copy keeps the read-only flag on other platforms
+#[cfg(not(unix))]
+fn file_mode(_path: &Path) -> std::io::Result<Option<u32>> {
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn apply_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn apply_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Every gear value is derived from a fixed seed, chunk boundaries must never change between versions
+fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = GEAR_SEED;
+    for value in table.iter_mut() {
+        // splitmix64
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut mixed = state;
+        mixed = (mixed ^ (mixed >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        mixed = (mixed ^ (mixed >> 27)).wrapping_mul(0x94d049bb133111eb);
+        *value = mixed ^ (mixed >> 31);
+    }
+    table
+}
+
+// A hex encoded SHA-256 digest as written by hash_chunk
+fn is_chunk_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+}
+
+fn hash_chunk(data: &[u8]) -> String {
+    let digest = Sha256::digest(data);
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Splits the file into content-defined chunks and hands each one to on_chunk, without loading the whole file
+pub fn split_file<F>(path: &Path, mut on_chunk: F) -> std::io::Result<Manifest>
+where F: FnMut(&ChunkRef, &[u8]) -> std::io::Result<()> {
+    let gear = gear_table();
+    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    let mut chunk: Vec<u8> = Vec::with_capacity(MAX_CHUNK_SIZE);
+    let mut manifest = Manifest {
+        size: 0,
+        chunks: vec![],
+        mode: None,
+    };
+    let mut rolling_hash: u64 = 0;
+
+    let mut emit = |chunk: &mut Vec<u8>, manifest: &mut Manifest| -> std::io::Result<()> {
+        let chunk_ref = ChunkRef {
+            hash: hash_chunk(chunk),
+            size: chunk.len(),
+        };
+        on_chunk(&chunk_ref, chunk)?;
+        manifest.size += chunk.len() as u64;
+        manifest.chunks.push(chunk_ref);
+        chunk.clear();
+        Ok(())
+    };
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for byte in &buffer[..read] {
+            chunk.push(*byte);
+            rolling_hash = (rolling_hash << 1).wrapping_add(gear[*byte as usize]);
+            if chunk.len() >= MAX_CHUNK_SIZE || (chunk.len() >= MIN_CHUNK_SIZE && rolling_hash & BOUNDARY_MASK == 0) {
+                emit(&mut chunk, &mut manifest)?;
+                rolling_hash = 0;
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        emit(&mut chunk, &mut manifest)?;
+    }
+    Ok(manifest)
+}
+
+// A stored file as a stream, only the chunk that is being read is kept in memory
+pub struct ChunkReader<'a> {
+    store: &'a ChunkStore,
+    chunks: std::slice::Iter<'a, ChunkRef>,
+    current: std::io::Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.chunks.next() {
+                Some(chunk_ref) => self.current = std::io::Cursor::new(self.store.read_chunk(chunk_ref)?),
+                None => return Ok(0)
+            }
+        }
+    }
+}
+
+// Deduplicated storage for the chunks of large files, every chunk is named after its hash
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    dir: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new(dir: PathBuf) -> ChunkStore {
+        ChunkStore {
+            dir
+        }
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(&hash[..2]).join(hash)
+    }
+
+    // Splits source into chunks, stores the ones that are new and writes the manifest to manifest_path
+    pub fn store_file(&self, source: &Path, manifest_path: &Path) -> std::io::Result<Manifest> {
+        let mut new_chunks = 0;
+        let mut manifest = split_file(source, |chunk_ref, data| {
+            let chunk_path = self.chunk_path(&chunk_ref.hash);
+            if chunk_path.exists() {
+                return Ok(());
+            }
+            std::fs::create_dir_all(chunk_path.parent().unwrap())?;
+            // written under a temporary name first, so an interrupted commit never leaves a broken chunk
+            let temp_path = chunk_path.with_extension("tmp");
+            std::fs::write(&temp_path, data)?;
+            std::fs::rename(&temp_path, &chunk_path)?;
+            new_chunks += 1;
+            Ok(())
+        })?;
+        dprintln!("[INFO] Stored {} in {} chunks, {} of them new", source.display(), manifest.chunks.len(), new_chunks);
+        manifest.mode = file_mode(source)?;
+
+        let manifest_str = serde_yaml::to_string(&manifest).unwrap();
+        std::fs::write(manifest_path, manifest_str)?;
+        Ok(manifest)
+    }
+
+    pub fn read_file(&self, manifest_path: &Path) -> std::io::Result<Vec<u8>> {
+        let manifest = Manifest::from_file(manifest_path)?;
+        let mut contents: Vec<u8> = Vec::with_capacity(manifest.size as usize);
+        for chunk_ref in manifest.chunks.iter() {
+            contents.append(&mut std::fs::read(self.chunk_path(&chunk_ref.hash))?);
+        }
+        Ok(contents)
+    }
+
+    // Reads a single chunk of a manifest and checks that it is complete
+    pub fn read_chunk(&self, chunk_ref: &ChunkRef) -> std::io::Result<Vec<u8>> {
+        let data = std::fs::read(self.chunk_path(&chunk_ref.hash))?;
+        if data.len() != chunk_ref.size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("chunk {} is damaged", chunk_ref.hash)));
+        }
+        Ok(data)
+    }
+
+    // Reads the file of a manifest one chunk at a time
+    pub fn open_file<'a>(&'a self, manifest: &'a Manifest) -> ChunkReader<'a> {
+        ChunkReader {
+            store: self,
+            chunks: manifest.chunks.iter(),
+            current: std::io::Cursor::new(vec![]),
+        }
+    }
+
+    // Reassembles the file chunk by chunk, so it never has to fit into memory, and gives it the recorded mode
+    pub fn restore_file(&self, manifest_path: &Path, destination: &Path) -> std::io::Result<()> {
+        let manifest = Manifest::from_file(manifest_path)?;
+        let mut file = std::fs::File::create(destination)?;
+        for chunk_ref in manifest.chunks.iter() {
+            file.write_all(&self.read_chunk(chunk_ref)?)?;
+        }
+        if let Some(mode) = manifest.mode {
+            apply_mode(destination, mode)?;
+        }
+        Ok(())
+    }
+
+    // Compares a stored file with a file on disk without reading either one completely
+    pub fn matches(&self, manifest_path: &Path, other: &Path) -> bool {
+        let manifest = match Manifest::from_file(manifest_path) {
+            Ok(manifest) => manifest,
+            Err(_) => return false
+        };
+        match std::fs::metadata(other) {
+            Ok(metadata) if metadata.len() == manifest.size => {},
+            _ => return false
+        }
+        match split_file(other, |_, _| Ok(())) {
+            Ok(other_manifest) => other_manifest.same_contents(&manifest),
+            Err(_) => false
+        }
+    }
+
+    // Deletes every chunk that none of the passed manifests refer to
+    pub fn prune(&self, manifest_paths: &[PathBuf]) -> std::io::Result<usize> {
+        let mut referenced: HashSet<String> = HashSet::new();
+        for manifest_path in manifest_paths.iter() {
+            for chunk_ref in Manifest::from_file(manifest_path)?.chunks {
+                referenced.insert(chunk_ref.hash);
+            }
+        }
+        if !self.dir.exists() {
+            return Ok(0);
+        }
+        let mut removed = 0;
+        for prefix_dir in std::fs::read_dir(&self.dir)? {
+            for chunk_file in std::fs::read_dir(prefix_dir?.path())? {
+                let chunk_file = chunk_file?;
+                let hash = chunk_file.file_name().to_string_lossy().into_owned();
+                if !referenced.contains(&hash) {
+                    std::fs::remove_file(chunk_file.path())?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::path::Path;
+
+    use super::{split_file, ChunkRef, ChunkStore, Manifest};
+    use crate::lostcontrol::test_support::TestDir;
+
+    // Deterministic bytes that look random to the rolling hash
+    fn noise(length: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..length).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        }).collect()
+    }
+
+    fn chunks_of(path: &Path) -> Vec<ChunkRef> {
+        split_file(path, |_, _| Ok(())).unwrap().chunks
+    }
+
+    #[test]
+    fn inserting_bytes_only_changes_the_first_chunk() {
+        let test_dir = TestDir::new("chunks-insert");
+        let original = noise(8 * 1024 * 1024, 0x1234_5678);
+        let mut edited = original.clone();
+        edited.splice(1000..1000, b"inserted near the start".iter().cloned());
+        std::fs::write(test_dir.path.join("original"), &original).unwrap();
+        std::fs::write(test_dir.path.join("edited"), &edited).unwrap();
+
+        let original_chunks = chunks_of(&test_dir.path.join("original"));
+        let edited_chunks = chunks_of(&test_dir.path.join("edited"));
+        assert!(original_chunks.len() > 2);
+        assert_eq!(original_chunks.len(), edited_chunks.len());
+        assert_ne!(original_chunks[0], edited_chunks[0]);
+        assert_eq!(original_chunks[1..], edited_chunks[1..]);
+    }
+
+    #[test]
+    fn stored_files_read_back_unchanged() {
+        let test_dir = TestDir::new("chunks-round-trip");
+        let store = ChunkStore::new(test_dir.path.join("chunks"));
+        let contents = noise(3 * 1024 * 1024, 42);
+        let source = test_dir.path.join("source");
+        std::fs::write(&source, &contents).unwrap();
+
+        let manifest = store.store_file(&source, &test_dir.path.join("manifest")).unwrap();
+        assert_eq!(manifest.size, contents.len() as u64);
+        assert_eq!(Manifest::from_file(&test_dir.path.join("manifest")).unwrap(), manifest);
+        assert_eq!(store.read_file(&test_dir.path.join("manifest")).unwrap(), contents);
+        let mut streamed: Vec<u8> = vec![];
+        store.open_file(&manifest).read_to_end(&mut streamed).unwrap();
+        assert_eq!(streamed, contents);
+        store.restore_file(&test_dir.path.join("manifest"), &test_dir.path.join("restored")).unwrap();
+        assert_eq!(std::fs::read(test_dir.path.join("restored")).unwrap(), contents);
+        assert!(store.matches(&test_dir.path.join("manifest"), &source));
+
+        // storing the same contents again refers to the same chunks
+        let copy = store.store_file(&test_dir.path.join("restored"), &test_dir.path.join("copy")).unwrap();
+        assert!(copy.same_contents(&manifest));
+        assert_eq!(store.prune(&[test_dir.path.join("copy")]).unwrap(), 0);
+        assert_eq!(store.prune(&[]).unwrap(), manifest.chunks.len());
+    }
+
+    #[test]
+    fn manifests_with_invalid_hashes_are_rejected() {
+        let test_dir = TestDir::new("chunks-invalid-hash");
+        let store = ChunkStore::new(test_dir.path.join("chunks"));
+        for hash in [String::new(), "a".repeat(63), "A".repeat(64), "../../../../etc/passwd".to_string()] {
+            let manifest_path = test_dir.path.join("manifest");
+            std::fs::write(&manifest_path, format!("size: 1\nchunks:\n  - hash: \"{}\"\n    size: 1\n", hash)).unwrap();
+            let error = Manifest::from_file(&manifest_path).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData, "{:?}", hash);
+            assert!(store.read_file(&manifest_path).is_err());
+        }
+    }
+}
@@ -1,54 +1,147 @@
 use serde::{self, Deserialize, Serialize};
 
+use crate::lostcontrol::RepoPath;
+
 const TIME_FORMAT_STRING: &str = "%Y-%m-%d %H:%M:%S";
 
+// Names a commit on a specific branch, written as <branch>:<id>
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CommitSource {
+    pub branch: String,
+    pub commit_id: usize,
+}
+
+impl std::fmt::Display for CommitSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.branch, self.commit_id)
+    }
+}
+
+// An entry that was moved, the commit also lists from as removed and to as modified
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RenamedFile {
+    pub from: RepoPath,
+    pub to: RepoPath,
+}
+
+impl std::fmt::Display for RenamedFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.from, self.to)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Commit {
     pub id: usize,
     pub message: String,
+    // commits written by older versions have no author
+    #[serde(default)]
+    pub author: String,
     pub creation_datetime: String,
-    pub modified_files: Vec<String>,
+    // when the changes were written, if that was before the commit was created, e.g. for an applied patch.
+    // The creation time stays the time the commit itself was made, it tells commits apart
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub authored_datetime: Option<String>,
+    pub modified_files: Vec<RepoPath>,
+    // large files that were stored as a manifest of chunks
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub chunked_files: Vec<RepoPath>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub removed_files: Vec<RepoPath>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub renamed_files: Vec<RenamedFile>,
+    // set when this commit undoes the changes of an earlier one
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub reverted_commit: Option<usize>,
+    // set when this commit was cherry-picked from another one
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub picked_from: Option<CommitSource>,
 }
 
 impl Commit {
-    pub fn new(id: usize, message: String, modified_files: Vec<String>) -> Commit {
-        return Commit {
-            id: id, 
-            message: message,
+    pub fn new(id: usize, message: String, author: String, modified_files: Vec<RepoPath>) -> Commit {
+        Commit {
+            id, 
+            message,
+            author,
             creation_datetime: chrono::Utc::now().to_rfc3339(),
-            modified_files: modified_files,
+            authored_datetime: None,
+            modified_files,
+            chunked_files: vec![],
+            removed_files: vec![],
+            renamed_files: vec![],
+            reverted_commit: None,
+            picked_from: None,
         }
     }
 
     pub fn get_time_formatted(&self) -> String {
-        let creation_datetime: chrono::DateTime<chrono::Local> = 
-            chrono::DateTime::from(chrono::DateTime::parse_from_rfc3339(&self.creation_datetime).unwrap());
-        return creation_datetime.format(TIME_FORMAT_STRING).to_string();
+        format_time(&self.creation_datetime)
+    }
+
+    // The time the changes were written, which is the creation time unless the commit has an author date of its own
+    pub fn authored_datetime(&self) -> &str {
+        self.authored_datetime.as_deref().unwrap_or(&self.creation_datetime)
     }
 }
 
+fn format_time(datetime: &str) -> String {
+    let datetime: chrono::DateTime<chrono::Local> =
+        chrono::DateTime::from(chrono::DateTime::parse_from_rfc3339(datetime).unwrap());
+    datetime.format(TIME_FORMAT_STRING).to_string()
+}
+
 impl std::fmt::Display for Commit {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "ID: {}\nMessage: {}\nCreated at: {}\nModified Files:\n", 
-            self.id,
-            self.message,
-            self.get_time_formatted(),
-        )?;
+        writeln!(f, "ID: {}\nMessage: {}", self.id, self.message)?;
+        if !self.author.is_empty() {
+            writeln!(f, "Author: {}", self.author)?;
+        }
+        writeln!(f, "Created at: {}", self.get_time_formatted())?;
+        if let Some(authored_datetime) = &self.authored_datetime {
+            writeln!(f, "Authored at: {}", format_time(authored_datetime))?;
+        }
+        writeln!(f, "Modified Files:")?;
         for file in &self.modified_files {
-            write!(f, "  {}\n", file)?;
+            writeln!(f, "  {}", file)?;
+        }
+        if !self.removed_files.is_empty() {
+            writeln!(f, "Removed Files:")?;
+            for file in &self.removed_files {
+                writeln!(f, "  {}", file)?;
+            }
+        }
+        if !self.renamed_files.is_empty() {
+            writeln!(f, "Renamed Files:")?;
+            for renamed_file in &self.renamed_files {
+                writeln!(f, "  {}", renamed_file)?;
+            }
         }
-        return Ok(());
+        if let Some(reverted_commit) = self.reverted_commit {
+            writeln!(f, "Reverts: {}", reverted_commit)?;
+        }
+        if let Some(picked_from) = &self.picked_from {
+            writeln!(f, "Picked from: {}", picked_from)?;
+        }
+        Ok(())
     }
 }
 
 // simple clone trait
 impl Clone for Commit {
     fn clone(&self) -> Commit {
-        return Commit {
+        Commit {
             id: self.id,
             message: self.message.clone(),
+            author: self.author.clone(),
             creation_datetime: self.creation_datetime.clone(),
+            authored_datetime: self.authored_datetime.clone(),
             modified_files: self.modified_files.clone(),
+            chunked_files: self.chunked_files.clone(),
+            removed_files: self.removed_files.clone(),
+            renamed_files: self.renamed_files.clone(),
+            reverted_commit: self.reverted_commit,
+            picked_from: self.picked_from.clone(),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,621 @@
+use std::ops::Range;
+use similar::{capture_diff_slices, Algorithm, ChangeTag, DiffTag, TextDiff};
+use similar::udiff::UnifiedHunkHeader;
+
+use crate::lostcontrol::rename::DetectedRename;
+use crate::lostcontrol::tree::{ChangeKind, EntryKind, TreeChange, TreeEntry};
+
+const CONTEXT_LINES: usize = 3;
+// same heuristic git uses: a NUL byte in the first few kilobytes means binary
+const BINARY_CHECK_SIZE: usize = 8000;
+// used when the width of the terminal is unknown
+pub const DEFAULT_WIDTH: usize = 80;
+// side-by-side columns never get narrower than this, even on tiny terminals
+const MIN_COLUMN_WIDTH: usize = 20;
+const TAB_WIDTH: usize = 4;
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+// How the contents of a changed file are shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DiffStyle {
+    #[default]
+    Unified,
+    // changed words inline, as [-removed-]{+added+} without colours
+    Words,
+    // the old and the new version in two columns
+    SideBySide,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DiffOptions {
+    pub style: DiffStyle,
+    pub color: bool,
+    // columns of the terminal, side-by-side output is fitted into them
+    pub width: usize,
+}
+
+impl Default for DiffOptions {
+    fn default() -> DiffOptions {
+        DiffOptions {
+            style: DiffStyle::Unified,
+            color: false,
+            width: DEFAULT_WIDTH,
+        }
+    }
+}
+
+fn paint(text: &str, color_code: &str, color: bool) -> String {
+    match color {
+        true => format!("{}{}{}", color_code, text, RESET),
+        false => text.to_string()
+    }
+}
+
+pub fn is_binary(data: &[u8]) -> bool {
+    data.iter().take(BINARY_CHECK_SIZE).any(|byte| *byte == 0)
+}
+
+// Produces a unified diff between old and new, names are used for the --- and +++ header lines
+pub fn unified_diff(old_name: &str, new_name: &str, old: &[u8], new: &[u8]) -> String {
+    if is_binary(old) || is_binary(new) {
+        return format!("Binary files {} and {} differ\n", old_name, new_name);
+    }
+    let old_text = String::from_utf8_lossy(old);
+    let new_text = String::from_utf8_lossy(new);
+    let text_diff = TextDiff::from_lines(old_text.as_ref(), new_text.as_ref());
+    text_diff.unified_diff()
+        .context_radius(CONTEXT_LINES)
+        .header(old_name, new_name)
+        .to_string()
+}
+
+// Renders a single tree change the way the diff command prints it
+pub fn format_change(change: &TreeChange, options: &DiffOptions) -> String {
+    let old_kind = change.old.as_ref().map(|entry| entry.kind);
+    let new_kind = change.new.as_ref().map(|entry| entry.kind);
+    match (old_kind, new_kind) {
+        (None, Some(EntryKind::Directory)) => format!("new directory: {}\n", change.format_path()),
+        (Some(EntryKind::Directory), None) => format!("deleted directory: {}\n", change.format_path()),
+        (Some(old_kind), Some(new_kind)) if old_kind != new_kind => {
+            format!("type changed: {} ({} -> {})\n", change.path, kind_name(old_kind), kind_name(new_kind))
+        },
+        _ => {
+            let old_name = match change.old {
+                Some(_) => format!("a/{}", change.path),
+                None => String::from("/dev/null")
+            };
+            let new_name = match change.new {
+                Some(_) => format!("b/{}", change.path),
+                None => String::from("/dev/null")
+            };
+            let old_contents = read_entry(&change.old);
+            let new_contents = read_entry(&change.new);
+            let diff = render_diff(&old_name, &new_name, &old_contents, &new_contents, options);
+            // empty files that are added or removed have no hunks, but still deserve a header
+            if diff.is_empty() && change.kind != ChangeKind::Modified {
+                return format_header(&old_name, &new_name, options.color);
+            }
+            diff
+        }
+    }
+}
+
+// Renders a moved or copied file, with the diff between both versions unless they are identical
+pub fn format_rename(rename: &DetectedRename, options: &DiffOptions) -> String {
+    let mut formatted = format!("{}\n", paint(&rename.to_string(), BOLD, options.color));
+    if rename.similarity < 100 {
+        let old_contents = rename.old.read().unwrap_or_default();
+        let new_contents = rename.new.read().unwrap_or_default();
+        formatted.push_str(&render_diff(&format!("a/{}", rename.from), &format!("b/{}", rename.to), &old_contents, &new_contents, options));
+    }
+    formatted
+}
+
+// The diff between two versions in the requested style, empty if they are identical
+pub fn render_diff(old_name: &str, new_name: &str, old: &[u8], new: &[u8], options: &DiffOptions) -> String {
+    if is_binary(old) || is_binary(new) {
+        return format!("Binary files {} and {} differ\n", old_name, new_name);
+    }
+    let old_text = String::from_utf8_lossy(old);
+    let new_text = String::from_utf8_lossy(new);
+    match options.style {
+        DiffStyle::Unified if options.color => color_unified(&unified_diff(old_name, new_name, old, new)),
+        DiffStyle::Unified => unified_diff(old_name, new_name, old, new),
+        DiffStyle::Words => word_diff(old_name, new_name, &old_text, &new_text, options.color),
+        DiffStyle::SideBySide => side_by_side(old_name, new_name, &old_text, &new_text, options)
+    }
+}
+
+fn format_header(old_name: &str, new_name: &str, color: bool) -> String {
+    format!("{}\n{}\n", paint(&format!("--- {}", old_name), BOLD, color), paint(&format!("+++ {}", new_name), BOLD, color))
+}
+
+fn color_unified(diff: &str) -> String {
+    let mut output = String::new();
+    for line in diff.split_inclusive('\n') {
+        let (text, line_break) = match line.strip_suffix('\n') {
+            Some(text) => (text, "\n"),
+            None => (line, "")
+        };
+        let color_code = if text.starts_with("---") || text.starts_with("+++") {
+            BOLD
+        }
+        else if text.starts_with("@@") {
+            CYAN
+        }
+        else if text.starts_with('-') {
+            RED
+        }
+        else if text.starts_with('+') {
+            GREEN
+        }
+        else {
+            ""
+        };
+        match color_code {
+            "" => output.push_str(text),
+            _ => output.push_str(&paint(text, color_code, true))
+        }
+        output.push_str(line_break);
+    }
+    output
+}
+
+// Adds removed and added words to the output, whitespace around them stays outside of the markers
+fn push_word_changes(output: &mut String, removed: &mut String, added: &mut String, color: bool) {
+    if !removed.trim().is_empty() {
+        let text = removed.trim();
+        // with nothing added in its place, the whitespace of the removed words is all that keeps their neighbours apart
+        let (leading, trailing) = match added.is_empty() {
+            true => (&removed[..removed.len() - removed.trim_start().len()], &removed[removed.trim_end().len()..]),
+            false => ("", "")
+        };
+        output.push_str(leading);
+        match color {
+            true => output.push_str(&paint(text, RED, true)),
+            false => output.push_str(&format!("[-{}-]", text))
+        }
+        output.push_str(trailing);
+    }
+    if added.trim().is_empty() {
+        output.push_str(added);
+    }
+    else {
+        let text = added.trim();
+        let leading = &added[..added.len() - added.trim_start().len()];
+        let trailing = &added[added.trim_end().len()..];
+        output.push_str(leading);
+        match color {
+            true => output.push_str(&paint(text, GREEN, true)),
+            false => output.push_str(&format!("{{+{}+}}", text))
+        }
+        output.push_str(trailing);
+    }
+    removed.clear();
+    added.clear();
+}
+
+// Like a unified diff, but changed lines are shown once with the changed words marked inside them
+fn word_diff(old_name: &str, new_name: &str, old: &str, new: &str, color: bool) -> String {
+    let text_diff = TextDiff::from_lines(old, new);
+    let mut output = String::new();
+    for group in text_diff.grouped_ops(CONTEXT_LINES) {
+        if output.is_empty() {
+            output.push_str(&format_header(old_name, new_name, color));
+        }
+        output.push_str(&paint(&UnifiedHunkHeader::new(&group).to_string(), CYAN, color));
+        output.push('\n');
+        for op in group.iter() {
+            let (tag, old_range, new_range) = op.as_tag_tuple();
+            let old_part = text_diff.old_slices()[old_range].concat();
+            let new_part = text_diff.new_slices()[new_range].concat();
+            if tag == DiffTag::Equal {
+                output.push_str(&old_part);
+                continue;
+            }
+            let mut removed = String::new();
+            let mut added = String::new();
+            for change in TextDiff::from_words(old_part.as_str(), new_part.as_str()).iter_all_changes() {
+                match change.tag() {
+                    ChangeTag::Delete => removed.push_str(change.value()),
+                    ChangeTag::Insert => added.push_str(change.value()),
+                    ChangeTag::Equal => {
+                        push_word_changes(&mut output, &mut removed, &mut added, color);
+                        output.push_str(change.value());
+                    }
+                }
+            }
+            push_word_changes(&mut output, &mut removed, &mut added, color);
+            // lines that were only removed leave nothing behind to end them
+            if !output.ends_with('\n') {
+                output.push('\n');
+            }
+        }
+        if !output.ends_with('\n') {
+            output.push('\n');
+        }
+    }
+    output
+}
+
+// Cuts or pads a line to exactly width characters, tabs are expanded first
+fn fit_column(line: &str, width: usize) -> String {
+    let line = line.trim_end_matches(['\n', '\r']).replace('\t', &" ".repeat(TAB_WIDTH));
+    let mut fitted: String = line.chars().take(width).collect();
+    let length = fitted.chars().count();
+    fitted.push_str(&" ".repeat(width - length));
+    fitted
+}
+
+// One row of side-by-side output, the marker tells how the two sides differ
+fn side_by_side_row(old: Option<&str>, new: Option<&str>, column_width: usize, color: bool) -> String {
+    let marker = match (old, new) {
+        (Some(_), Some(_)) => '|',
+        (Some(_), None) => '<',
+        (None, Some(_)) => '>',
+        (None, None) => ' '
+    };
+    let left = fit_column(old.unwrap_or(""), column_width);
+    let right = fit_column(new.unwrap_or(""), column_width);
+    let left = paint(&left, RED, color && old.is_some());
+    let right = paint(right.trim_end(), GREEN, color && new.is_some());
+    let row = format!("{} {} {}", left, marker, right);
+    format!("{}\n", row.trim_end())
+}
+
+// Both versions next to each other, each in half of the width
+fn side_by_side(old_name: &str, new_name: &str, old: &str, new: &str, options: &DiffOptions) -> String {
+    let column_width = (options.width.saturating_sub(3) / 2).max(MIN_COLUMN_WIDTH);
+    let text_diff = TextDiff::from_lines(old, new);
+    let old_lines = text_diff.old_slices();
+    let new_lines = text_diff.new_slices();
+    let mut output = String::new();
+    for group in text_diff.grouped_ops(CONTEXT_LINES) {
+        if output.is_empty() {
+            let header = format!("{}   +++ {}", fit_column(&format!("--- {}", old_name), column_width), new_name);
+            output.push_str(&paint(header.trim_end(), BOLD, options.color));
+            output.push('\n');
+        }
+        output.push_str(&paint(&UnifiedHunkHeader::new(&group).to_string(), CYAN, options.color));
+        output.push('\n');
+        for op in group.iter() {
+            let (tag, old_range, new_range) = op.as_tag_tuple();
+            if tag == DiffTag::Equal {
+                for (old_line, new_line) in old_lines[old_range].iter().zip(new_lines[new_range].iter()) {
+                    let row = format!("{}   {}", fit_column(old_line, column_width), fit_column(new_line, column_width));
+                    output.push_str(row.trim_end());
+                    output.push('\n');
+                }
+                continue;
+            }
+            // changed lines are paired up, whatever is left over on one side stands alone
+            let old_part = &old_lines[old_range];
+            let new_part = &new_lines[new_range];
+            for index in 0..old_part.len().max(new_part.len()) {
+                output.push_str(&side_by_side_row(old_part.get(index).copied(), new_part.get(index).copied(), column_width, options.color));
+            }
+        }
+    }
+    output
+}
+
+// Lines added and removed in a file, used by --stat and --numstat
+pub struct FileStat {
+    // the path, or old -> new for moved and copied files
+    pub name: String,
+    pub insertions: usize,
+    pub deletions: usize,
+    // binary files have no lines to count
+    pub binary: bool,
+}
+
+impl FileStat {
+    pub fn new(name: String, old: &[u8], new: &[u8]) -> FileStat {
+        if is_binary(old) || is_binary(new) {
+            return FileStat { name, insertions: 0, deletions: 0, binary: true };
+        }
+        let old_text = String::from_utf8_lossy(old);
+        let new_text = String::from_utf8_lossy(new);
+        let text_diff = TextDiff::from_lines(old_text.as_ref(), new_text.as_ref());
+        let mut insertions = 0;
+        let mut deletions = 0;
+        for op in text_diff.ops() {
+            let (tag, old_range, new_range) = op.as_tag_tuple();
+            match tag {
+                DiffTag::Equal => {},
+                DiffTag::Delete => deletions += old_range.len(),
+                DiffTag::Insert => insertions += new_range.len(),
+                DiffTag::Replace => {
+                    deletions += old_range.len();
+                    insertions += new_range.len();
+                }
+            }
+        }
+        FileStat { name, insertions, deletions, binary: false }
+    }
+
+    // Directories have no lines and are left out
+    pub fn of_change(change: &TreeChange) -> Option<FileStat> {
+        let is_directory = |entry: &Option<TreeEntry>| entry.as_ref().is_some_and(|entry| entry.kind == EntryKind::Directory);
+        if is_directory(&change.old) && is_directory(&change.new) || change.old.is_none() && is_directory(&change.new)
+            || change.new.is_none() && is_directory(&change.old) {
+            return None;
+        }
+        Some(FileStat::new(change.path.to_string(), &read_entry(&change.old), &read_entry(&change.new)))
+    }
+
+    pub fn of_rename(rename: &DetectedRename) -> FileStat {
+        let name = format!("{} -> {}", rename.from, rename.to);
+        FileStat::new(name, &rename.old.read().unwrap_or_default(), &rename.new.read().unwrap_or_default())
+    }
+}
+
+// A line per file with a bar of + and - scaled to the width, and a summary at the end
+pub fn format_stat(stats: &[FileStat], options: &DiffOptions) -> String {
+    let name_width = stats.iter().map(|stat| stat.name.chars().count()).max().unwrap_or(0);
+    let most_changed = stats.iter().map(|stat| stat.insertions + stat.deletions).max().unwrap_or(0);
+    let count_width = most_changed.to_string().len().max(3);
+    let bar_width = options.width.saturating_sub(name_width + count_width + 5).max(10);
+
+    let mut output = String::new();
+    for stat in stats.iter() {
+        let padding = " ".repeat(name_width - stat.name.chars().count());
+        if stat.binary {
+            output.push_str(&format!(" {}{} | {:>width$}\n", stat.name, padding, "Bin", width = count_width));
+            continue;
+        }
+        let changed = stat.insertions + stat.deletions;
+        let (plus, minus) = match most_changed > bar_width {
+            // every changed file keeps at least one sign
+            true => {
+                let scaled = |count: usize| match count {
+                    0 => 0,
+                    count => (count * bar_width / most_changed).max(1)
+                };
+                (scaled(stat.insertions), scaled(stat.deletions))
+            },
+            false => (stat.insertions, stat.deletions)
+        };
+        output.push_str(&format!(" {}{} | {:>width$} {}{}\n", stat.name, padding, changed,
+            paint(&"+".repeat(plus), GREEN, options.color && plus > 0),
+            paint(&"-".repeat(minus), RED, options.color && minus > 0),
+            width = count_width));
+    }
+    let insertions: usize = stats.iter().map(|stat| stat.insertions).sum();
+    let deletions: usize = stats.iter().map(|stat| stat.deletions).sum();
+    let plural = |count: usize| if count == 1 { "" } else { "s" };
+    output.push_str(&format!(" {} file{} changed", stats.len(), plural(stats.len())));
+    if insertions > 0 || deletions == 0 {
+        output.push_str(&format!(", {} insertion{}(+)", insertions, plural(insertions)));
+    }
+    if deletions > 0 || insertions == 0 {
+        output.push_str(&format!(", {} deletion{}(-)", deletions, plural(deletions)));
+    }
+    output.push('\n');
+    output
+}
+
+// Tab separated added and removed line counts per file, - for binary files, meant for scripts
+pub fn format_numstat(stats: &[FileStat]) -> String {
+    let mut output = String::new();
+    for stat in stats.iter() {
+        match stat.binary {
+            true => output.push_str(&format!("-\t-\t{}\n", stat.name)),
+            false => output.push_str(&format!("{}\t{}\t{}\n", stat.insertions, stat.deletions, stat.name))
+        }
+    }
+    output
+}
+
+fn read_entry(entry: &Option<TreeEntry>) -> Vec<u8> {
+    match entry {
+        Some(entry) => entry.read().unwrap_or_default(),
+        None => vec![]
+    }
+}
+
+fn kind_name(kind: EntryKind) -> &'static str {
+    match kind {
+        EntryKind::File => "file",
+        EntryKind::Directory => "directory",
+        EntryKind::Symlink => "symlink",
+    }
+}
+
+// A changed region, old and new are line ranges of the two versions
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LineChange {
+    old: Range<usize>,
+    new: Range<usize>,
+}
+
+// One or more changed regions that are close enough to be shown with shared context
+#[derive(Debug, Clone)]
+pub struct Hunk {
+    changes: Vec<LineChange>,
+}
+
+impl Hunk {
+    // Breaks the hunk into its changed regions, None if it only has a single one
+    pub fn split(&self) -> Option<Vec<Hunk>> {
+        if self.changes.len() < 2 {
+            return None;
+        }
+        Some(self.changes.iter().map(|change| Hunk { changes: vec![change.clone()] }).collect())
+    }
+}
+
+// The line-wise changes between two versions of a text file, used to take over only some of them
+pub struct FilePatch<'a> {
+    old: Vec<&'a str>,
+    new: Vec<&'a str>,
+    pub hunks: Vec<Hunk>,
+}
+
+// Lines keep their line break, so putting them back together gives the exact contents
+fn split_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+fn format_line(prefix: char, line: &str) -> String {
+    match line.strip_suffix('\n') {
+        Some(line) => format!("{}{}\n", prefix, line),
+        None => format!("{}{}\n\\ No newline at end of file\n", prefix, line)
+    }
+}
+
+impl<'a> FilePatch<'a> {
+    pub fn new(old: &'a str, new: &'a str) -> FilePatch<'a> {
+        let old_lines = split_lines(old);
+        let new_lines = split_lines(new);
+        let mut hunks: Vec<Hunk> = vec![];
+        for op in capture_diff_slices(Algorithm::Myers, &old_lines, &new_lines) {
+            let (tag, old_range, new_range) = op.as_tag_tuple();
+            if tag == DiffTag::Equal {
+                continue;
+            }
+            let change = LineChange { old: old_range, new: new_range };
+            // regions whose context would overlap end up in the same hunk, like in a unified diff
+            match hunks.last_mut() {
+                Some(hunk) if change.old.start - hunk.changes.last().unwrap().old.end <= 2 * CONTEXT_LINES => {
+                    hunk.changes.push(change);
+                },
+                _ => hunks.push(Hunk { changes: vec![change] })
+            }
+        }
+        FilePatch {
+            old: old_lines,
+            new: new_lines,
+            hunks,
+        }
+    }
+
+    // Renders the hunk like a unified diff does, with a few lines of context around it
+    pub fn format_hunk(&self, hunk: &Hunk) -> String {
+        let first = hunk.changes.first().unwrap();
+        let last = hunk.changes.last().unwrap();
+        let context_start = first.old.start.saturating_sub(CONTEXT_LINES);
+        let context_end = (last.old.end + CONTEXT_LINES).min(self.old.len());
+        let old_count = context_end - context_start;
+        let new_count = hunk.changes.iter()
+            .fold(old_count, |count, change| count + change.new.len() - change.old.len());
+        let new_start = first.new.start - (first.old.start - context_start);
+
+        let mut output = format!("@@ -{},{} +{},{} @@\n", context_start + 1, old_count, new_start + 1, new_count);
+        let mut position = context_start;
+        for change in hunk.changes.iter() {
+            for line in &self.old[position..change.old.start] {
+                output.push_str(&format_line(' ', line));
+            }
+            for line in &self.old[change.old.clone()] {
+                output.push_str(&format_line('-', line));
+            }
+            for line in &self.new[change.new.clone()] {
+                output.push_str(&format_line('+', line));
+            }
+            position = change.old.end;
+        }
+        for line in &self.old[position..context_end] {
+            output.push_str(&format_line(' ', line));
+        }
+        output
+    }
+
+    // The old contents with only the changes of the accepted hunks applied
+    pub fn apply(&self, accepted: &[Hunk]) -> String {
+        let mut output = String::new();
+        let mut position = 0;
+        for hunk in self.hunks.iter() {
+            for change in hunk.changes.iter() {
+                output.extend(self.old[position..change.old.start].iter().copied());
+                match accepted.iter().any(|hunk| hunk.changes.contains(change)) {
+                    true => output.extend(self.new[change.new.clone()].iter().copied()),
+                    false => output.extend(self.old[change.old.clone()].iter().copied())
+                }
+                position = change.old.end;
+            }
+        }
+        output.extend(self.old[position..].iter().copied());
+        output
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{format_numstat, format_stat, side_by_side, word_diff, DiffOptions, DiffStyle, FileStat};
+
+    #[test]
+    fn word_diff_marks_replaced_and_inserted_words() {
+        let diff = word_diff("a", "b", "the quick brown fox\n", "the slow brown fox jumps\n", false);
+        assert_eq!(diff, "--- a\n+++ b\n@@ -1 +1 @@\nthe [-quick-]{+slow+} brown fox {+jumps+}\n");
+    }
+
+    #[test]
+    fn word_diff_keeps_removed_words_apart_from_their_neighbours() {
+        let diff = word_diff("a", "b", "one two three\nkept\n", "one three\nkept\n", false);
+        assert_eq!(diff, "--- a\n+++ b\n@@ -1,2 +1,2 @@\none [-two-] three\nkept\n");
+        let colored = word_diff("a", "b", "one two three\n", "one three\n", true);
+        assert!(colored.ends_with("one \x1b[31mtwo\x1b[0m three\n"));
+    }
+
+    #[test]
+    fn side_by_side_fits_lines_into_their_columns() {
+        let options = DiffOptions {
+            style: DiffStyle::SideBySide,
+            color: false,
+            width: 47,
+        };
+        let old = "same\nthis line is much longer than its column\näöü€ multibyte characters are counted once\ngone\n";
+        let new = "same\nshort\näöü€ multibyte characters are counted ONCE\n";
+        let diff = side_by_side("a", "b", old, new, &options);
+        assert_eq!(diff, concat!(
+            "--- a                    +++ b\n",
+            "@@ -1,4 +1,3 @@\n",
+            "same                     same\n",
+            "this line is much long | short\n",
+            "äöü€ multibyte charact | äöü€ multibyte charact\n",
+            "gone                   <\n",
+        ));
+    }
+
+    #[test]
+    fn side_by_side_columns_have_a_minimum_width() {
+        let options = DiffOptions {
+            style: DiffStyle::SideBySide,
+            color: false,
+            width: 10,
+        };
+        let diff = side_by_side("a", "b", "old\tline that is too long\n", "new\n", &options);
+        assert_eq!(diff.lines().nth(2), Some("old    line that is  | new"));
+    }
+
+    #[test]
+    fn stat_bars_are_scaled_to_the_width() {
+        let stats = vec![
+            FileStat::new("small".to_string(), b"a\n", b"b\n"),
+            FileStat::new("large-file".to_string(), b"", "x\n".repeat(200).as_bytes()),
+            FileStat::new("bin".to_string(), b"\0", b"\0\0"),
+        ];
+        let stat = format_stat(&stats, &DiffOptions::default());
+        assert_eq!(stat, format!(concat!(
+            " small      |   2 +-\n",
+            " large-file | 200 {}\n",
+            " bin        | Bin\n",
+            " 3 files changed, 201 insertions(+), 1 deletion(-)\n",
+        ), "+".repeat(62)));
+        assert!(stat.lines().all(|line| line.chars().count() <= 80));
+        assert_eq!(format_numstat(&stats), "1\t1\tsmall\n200\t0\tlarge-file\n-\t-\tbin\n");
+    }
+
+    #[test]
+    fn stat_bars_below_the_width_are_not_scaled() {
+        let stats = vec![FileStat::new("f".to_string(), b"a\nb\nc\n", b"a\n")];
+        assert_eq!(format_stat(&stats, &DiffOptions::default()), " f |   2 --\n 1 file changed, 2 deletions(-)\n");
+    }
+}
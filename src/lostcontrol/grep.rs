@@ -0,0 +1,84 @@
+use std::path::PathBuf;
+use regex::Regex;
+
+use crate::lostcontrol::{Repo, RepoPath, dprintln};
+use crate::lostcontrol::diff::is_binary;
+use crate::lostcontrol::tree::{EntryKind, Tree};
+
+// Which stored contents a search looks at
+pub enum GrepScope {
+    // the full tree of a commit
+    Commit(usize),
+    // every version of a file any commit of the branch stored
+    AllHistory,
+}
+
+pub struct GrepMatch {
+    pub commit_id: usize,
+    pub path: RepoPath,
+    // counting from 1
+    pub line_number: usize,
+    pub line: String,
+}
+
+fn matches_filter(path: &RepoPath, filters: &[RepoPath]) -> bool {
+    filters.is_empty() || filters.iter().any(|filter| filter.is_empty() || path == filter || path.is_inside(filter))
+}
+
+// Binary files and directories are skipped
+fn grep_tree(tree: &Tree, commit_id: usize, pattern: &Regex, filters: &[RepoPath], matches: &mut Vec<GrepMatch>) {
+    for (path, entry) in tree.iter() {
+        if entry.kind != EntryKind::File || !matches_filter(path, filters) {
+            continue;
+        }
+        let contents = match entry.read() {
+            Ok(contents) => contents,
+            Err(e) => {
+                dprintln!("[ERROR] Cannot read {} of commit {}: {}", path, commit_id, e);
+                continue;
+            }
+        };
+        if is_binary(&contents) {
+            continue;
+        }
+        for (index, line) in String::from_utf8_lossy(&contents).lines().enumerate() {
+            if pattern.is_match(line) {
+                matches.push(GrepMatch {
+                    commit_id,
+                    path: path.clone(),
+                    line_number: index + 1,
+                    line: line.to_string(),
+                });
+            }
+        }
+    }
+}
+
+impl Repo {
+    // Searches the stored files of the current branch for lines matching pattern, optionally only below paths
+    pub fn grep(&self, pattern: &Regex, scope: GrepScope, paths: &[PathBuf]) -> Result<Vec<GrepMatch>, ()> {
+        let mut filters: Vec<RepoPath> = vec![];
+        for path in paths.iter() {
+            let (_, key) = self.resolve_path(path)?;
+            filters.push(key);
+        }
+        let branch_config = self.get_branch(&self.current_branch)?;
+
+        let mut matches: Vec<GrepMatch> = vec![];
+        match scope {
+            GrepScope::Commit(commit_id) => {
+                let tree = self.get_tree(&branch_config, commit_id)?;
+                grep_tree(&tree, commit_id, pattern, &filters, &mut matches);
+            },
+            GrepScope::AllHistory => {
+                // each commit only stores the files it changed, so every version is searched exactly once
+                for commit in branch_config.get_commits().iter() {
+                    let mut tree = Tree::new();
+                    self.overlay_stored(&mut tree, &self.commit_dir(&branch_config, commit.id), &commit.chunked_files);
+                    grep_tree(&tree, commit.id, pattern, &filters, &mut matches);
+                }
+            }
+        }
+        Ok(matches)
+    }
+}
@@ -0,0 +1,259 @@
+use std::path::{Path, PathBuf};
+
+use crate::lostcontrol::{Repo, RepoPath, dprintln};
+use crate::lostcontrol::fs_operations::{copy_symlink, remove_entry};
+use crate::lostcontrol::repo::restore_entry;
+use crate::lostcontrol::tree::{compare, ChangeKind, EntryKind, Tree, TreeChange, TreeEntry};
+
+pub(crate) const INDEX_DIR: &str = "index";
+
+impl Repo {
+    fn index_dir(&self) -> PathBuf {
+        self.repos_dir().join(INDEX_DIR)
+    }
+
+    fn index_path(&self, file: &RepoPath) -> PathBuf {
+        self.index_dir().join(file.to_path_buf())
+    }
+
+    // Where the staged contents of an entry are read from when committing: its snapshot in the index,
+    // or the working tree for entries that were staged without one, e.g. by a revert.
+    // Also returns whether the snapshot is a manifest of chunks
+    pub(crate) fn staged_source(&self, file: &RepoPath) -> Result<(PathBuf, bool), ()> {
+        if !self.staged_snapshots.contains(file) {
+            return Ok((self.root_dir().join(file.to_path_buf()), false));
+        }
+        let index_path = self.index_path(file);
+        if EntryKind::of(&index_path).is_none() {
+            dprintln!("[ERROR] The staged snapshot of {} is missing", file);
+            return Err(());
+        }
+        Ok((index_path, self.chunked_snapshots.contains(file)))
+    }
+
+    // Staged snapshots that are gone from the index, e.g. because an undo brought back a staging
+    // whose snapshots were already committed and cleaned up. They have to be staged again
+    pub fn missing_snapshots(&self) -> Vec<RepoPath> {
+        self.staged_snapshots.iter()
+            .filter(|file| EntryKind::of(&self.index_path(file)).is_none())
+            .cloned()
+            .collect()
+    }
+
+    // The manifests of the chunked snapshots, their chunks must not be pruned
+    pub(crate) fn snapshot_manifests(&self) -> Vec<PathBuf> {
+        self.chunked_snapshots.iter()
+            .map(|file| self.index_path(file))
+            .filter(|index_path| index_path.exists())
+            .collect()
+    }
+
+    // What --patch starts from: the staged snapshot if the file is staged, otherwise the contents in the current commit,
+    // which are empty for new files. Returns the key of the file, the base and the working tree contents
+    pub fn patch_contents(&self, path: &Path) -> Result<(RepoPath, Vec<u8>, Vec<u8>), ()> {
+        let (absolute_path, key) = self.resolve_path(path)?;
+        if EntryKind::of(&absolute_path) != Some(EntryKind::File) {
+            dprintln!("[ERROR] {} is not a file", key);
+            return Err(());
+        }
+        let working = match std::fs::read(&absolute_path) {
+            Ok(working) => working,
+            Err(e) => {
+                dprintln!("[ERROR] Cannot read {}: {}", absolute_path.display(), e);
+                return Err(());
+            }
+        };
+        if self.staged_snapshots.contains(&key) {
+            let index_path = self.index_path(&key);
+            let staged = match self.chunked_snapshots.contains(&key) {
+                true => self.chunk_store().read_file(&index_path),
+                false => std::fs::read(&index_path)
+            };
+            if let Ok(staged) = staged {
+                return Ok((key, staged, working));
+            }
+        }
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let head_tree = self.get_tree(&branch_config, branch_config.current_commit)?;
+        let committed = match head_tree.get(&key) {
+            Some(entry) if entry.kind == EntryKind::File => entry.read().map_err(|e| {
+                dprintln!("[ERROR] Cannot read {} of commit {}: {}", key, branch_config.current_commit, e);
+            })?,
+            _ => vec![]
+        };
+        Ok((key, committed, working))
+    }
+
+    // Copies the working tree entries into the index, replacing older snapshots of them, large files go into the chunk store.
+    // Entries that can't be copied are committed from the working tree instead
+    pub(crate) fn snapshot_entries(&mut self, files: &[RepoPath]) {
+        let mut staged_snapshots = self.staged_snapshots.clone();
+        let mut chunked_snapshots = self.chunked_snapshots.clone();
+        for file in files.iter() {
+            chunked_snapshots.retain(|snapshot| snapshot != file);
+            let source_path = self.root_dir().join(file.to_path_buf());
+            let index_path = self.index_path(file);
+            let source_kind = EntryKind::of(&source_path);
+            let index_kind = EntryKind::of(&index_path);
+            if index_kind.is_some() && index_kind != source_kind {
+                if let Err(e) = remove_entry(&index_path) {
+                    dprintln!("[ERROR] Cannot replace snapshot {}: {}", index_path.display(), e);
+                    staged_snapshots.retain(|snapshot| snapshot != file);
+                    continue;
+                }
+            }
+            let parent_created = match index_path.parent() {
+                Some(parent) => std::fs::create_dir_all(parent),
+                None => Ok(())
+            };
+            let result = parent_created.and_then(|_| match source_kind {
+                Some(EntryKind::Directory) => std::fs::create_dir_all(&index_path),
+                Some(EntryKind::Symlink) => copy_symlink(&source_path, &index_path),
+                Some(EntryKind::File) => {
+                    let file_size = std::fs::metadata(&source_path)?.len();
+                    if file_size >= self.chunk_threshold {
+                        self.chunk_store().store_file(&source_path, &index_path)?;
+                        chunked_snapshots.push(file.clone());
+                        Ok(())
+                    }
+                    else {
+                        std::fs::copy(&source_path, &index_path).map(|_| ())
+                    }
+                },
+                None => Err(std::io::Error::from(std::io::ErrorKind::NotFound))
+            });
+            match result {
+                Ok(()) => {
+                    if !staged_snapshots.contains(file) {
+                        staged_snapshots.push(file.clone());
+                    }
+                },
+                Err(e) => {
+                    dprintln!("[ERROR] Cannot take a snapshot of {}: {}", file, e);
+                    staged_snapshots.retain(|snapshot| snapshot != file);
+                }
+            }
+        }
+        self.set_chunked_snapshots(chunked_snapshots);
+        self.set_staged_snapshots(staged_snapshots);
+        self.prune_index();
+    }
+
+    // Writes stored snapshots back into the index, e.g. the ones saved by a stash, and marks them as staged snapshots.
+    // Chunked snapshots stay chunked, only their manifest is copied. The files are expected to be staged already
+    pub(crate) fn restore_snapshots(&mut self, tree: &Tree, files: &[RepoPath]) -> Result<(), ()> {
+        let mut staged_snapshots = self.staged_snapshots.clone();
+        let mut chunked_snapshots = self.chunked_snapshots.clone();
+        for file in files.iter() {
+            let entry = match tree.get(file) {
+                Some(entry) => entry,
+                None => {
+                    dprintln!("[ERROR] The snapshot of {} is missing", file);
+                    return Err(());
+                }
+            };
+            chunked_snapshots.retain(|snapshot| snapshot != file);
+            if entry.chunks.is_some() {
+                let manifest = TreeEntry {
+                    chunks: None,
+                    ..entry.clone()
+                };
+                restore_entry(&manifest, &self.index_path(file))?;
+                chunked_snapshots.push(file.clone());
+            }
+            else {
+                restore_entry(entry, &self.index_path(file))?;
+            }
+            if !staged_snapshots.contains(file) {
+                staged_snapshots.push(file.clone());
+            }
+        }
+        self.set_chunked_snapshots(chunked_snapshots);
+        self.set_staged_snapshots(staged_snapshots);
+        Ok(())
+    }
+
+    // Staged entries whose working tree version differs from what was staged, old is the snapshot and new the working entry
+    pub fn changed_since_staged(&self) -> Vec<TreeChange> {
+        let mut staged_tree = Tree::new();
+        let mut working_tree = Tree::new();
+        for file in self.staged_snapshots.iter() {
+            let index_path = self.index_path(file);
+            let kind = match EntryKind::of(&index_path) {
+                Some(kind) => kind,
+                None => continue
+            };
+            staged_tree.insert(file.clone(), TreeEntry {
+                kind,
+                source: index_path,
+                chunks: match self.chunked_snapshots.contains(file) {
+                    true => Some(self.chunk_store()),
+                    false => None
+                },
+            });
+            if let Some(working_entry) = self.working_entry(file) {
+                working_tree.insert(file.clone(), working_entry);
+            }
+        }
+        compare(&staged_tree, &working_tree).into_iter()
+            .filter(|change| change.kind != ChangeKind::Added)
+            .collect()
+    }
+
+    // Stages contents for a file that differ from the working tree, they are kept in the index until the next commit
+    pub fn stage_contents(&mut self, key: &RepoPath, contents: &[u8]) -> Result<(), ()> {
+        if self.is_ignored(key) {
+            dprintln!("[INFO] File {} is on ignore list, skipping...", key);
+            return Err(());
+        }
+        let index_path = self.index_path(key);
+        if let Some(parent) = index_path.parent() {
+            if let Err(e) = std::fs::create_dir_all(parent) {
+                dprintln!("[ERROR] Cannot create index directory {}: {}", parent.display(), e);
+                return Err(());
+            }
+        }
+        if let Err(e) = std::fs::write(&index_path, contents) {
+            dprintln!("[ERROR] Cannot write {}: {}", index_path.display(), e);
+            return Err(());
+        }
+        // the snapshot is written as a whole, even if the one it replaces was chunked
+        if self.chunked_snapshots.contains(key) {
+            let mut chunked_snapshots = self.chunked_snapshots.clone();
+            chunked_snapshots.retain(|snapshot| snapshot != key);
+            self.set_chunked_snapshots(chunked_snapshots);
+        }
+        if !self.staged_files.contains(key) {
+            let mut staged_files = self.staged_files.clone();
+            staged_files.push(key.clone());
+            self.set_staged_files(staged_files);
+        }
+        if !self.staged_snapshots.contains(key) {
+            let mut staged_snapshots = self.staged_snapshots.clone();
+            staged_snapshots.push(key.clone());
+            self.set_staged_snapshots(staged_snapshots);
+        }
+        dprintln!("[INFO] File {} staged partially!", key);
+        Ok(())
+    }
+
+    // Drops the snapshots of entries that are no longer staged, the index goes away once nothing is left in it
+    pub(crate) fn prune_index(&mut self) {
+        let (kept, dropped): (Vec<RepoPath>, Vec<RepoPath>) = self.staged_snapshots.iter().cloned()
+            .partition(|file| self.staged_files.contains(file));
+        if !dropped.is_empty() {
+            self.set_staged_snapshots(kept);
+        }
+        if self.staged_snapshots.is_empty() {
+            if self.index_dir().exists() {
+                if let Err(e) = std::fs::remove_dir_all(self.index_dir()) {
+                    dprintln!("[ERROR] Cannot clear index directory {}: {}", self.index_dir().display(), e);
+                }
+            }
+            return;
+        }
+        for file in dropped.iter() {
+            let _ = std::fs::remove_file(self.index_path(file));
+        }
+    }
+}
@@ -0,0 +1,360 @@
+use std::ops::Range;
+use similar::{capture_diff_slices, Algorithm};
+
+use crate::lostcontrol::{Repo, RepoPath, dprintln};
+use crate::lostcontrol::diff::is_binary;
+use crate::lostcontrol::repo::restore_entry;
+use crate::lostcontrol::tree::{compare, same_entry, EntryKind, Tree, TreeEntry};
+
+const CONFLICT_START: &str = "<<<<<<<";
+const CONFLICT_SEPARATOR: &str = "=======";
+const CONFLICT_END: &str = ">>>>>>>";
+
+pub struct MergeResult {
+    pub contents: Vec<u8>,
+    pub conflicts: usize,
+}
+
+// What applying the changes between two trees to the working tree did
+pub struct ApplyOutcome {
+    pub changed_files: Vec<RepoPath>,
+    pub removed_files: Vec<RepoPath>,
+    pub conflicts: Vec<RepoPath>,
+}
+
+// A region of base that one side replaced with other lines
+struct Hunk {
+    base: Range<usize>,
+    side: Range<usize>,
+}
+
+fn split_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+// Neighbouring delete and insert operations are joined, so every changed region is a single hunk
+fn changed_hunks(base: &[&str], side: &[&str]) -> Vec<Hunk> {
+    let mut hunks: Vec<Hunk> = vec![];
+    for op in capture_diff_slices(Algorithm::Myers, base, side) {
+        let (tag, base_range, side_range) = op.as_tag_tuple();
+        if tag == similar::DiffTag::Equal {
+            continue;
+        }
+        match hunks.last_mut() {
+            Some(last) if last.base.end == base_range.start && last.side.end == side_range.start => {
+                last.base.end = base_range.end;
+                last.side.end = side_range.end;
+            },
+            _ => hunks.push(Hunk {
+                base: base_range,
+                side: side_range,
+            })
+        }
+    }
+    hunks
+}
+
+fn push_lines(output: &mut String, lines: &[&str]) {
+    for line in lines {
+        output.push_str(line);
+    }
+}
+
+// Conflict markers have to start on a line of their own
+fn push_conflict_lines(output: &mut String, lines: &[&str]) {
+    push_lines(output, lines);
+    if !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+}
+
+// Combines the changes that ours and theirs made to base line by line.
+// Regions that both sides changed differently are written with conflict markers, labelled with the passed names
+pub fn merge_text(base: &str, ours: &str, theirs: &str, ours_label: &str, theirs_label: &str) -> MergeResult {
+    let base_lines = split_lines(base);
+    let ours_lines = split_lines(ours);
+    let theirs_lines = split_lines(theirs);
+    let ours_hunks = changed_hunks(&base_lines, &ours_lines);
+    let theirs_hunks = changed_hunks(&base_lines, &theirs_lines);
+
+    let mut output = String::with_capacity(ours.len().max(theirs.len()));
+    let mut conflicts = 0;
+    let mut base_position = 0;
+    // how far each side has moved away from the line numbers of base so far
+    let mut ours_offset: isize = 0;
+    let mut theirs_offset: isize = 0;
+    let (mut ours_index, mut theirs_index) = (0, 0);
+
+    while ours_index < ours_hunks.len() || theirs_index < theirs_hunks.len() {
+        // start a group with whichever hunk comes first, then pull in every hunk that overlaps it
+        let first_start = match (ours_hunks.get(ours_index), theirs_hunks.get(theirs_index)) {
+            (Some(our_hunk), Some(their_hunk)) => our_hunk.base.start.min(their_hunk.base.start),
+            (Some(our_hunk), None) => our_hunk.base.start,
+            (None, Some(their_hunk)) => their_hunk.base.start,
+            (None, None) => break
+        };
+        let group_start = first_start;
+        let mut group_end = first_start;
+        let (ours_first, theirs_first) = (ours_index, theirs_index);
+        loop {
+            if let Some(hunk) = ours_hunks.get(ours_index).filter(|hunk| hunk.base.start <= group_end) {
+                group_end = group_end.max(hunk.base.end);
+                ours_index += 1;
+                continue;
+            }
+            if let Some(hunk) = theirs_hunks.get(theirs_index).filter(|hunk| hunk.base.start <= group_end) {
+                group_end = group_end.max(hunk.base.end);
+                theirs_index += 1;
+                continue;
+            }
+            break;
+        }
+
+        push_lines(&mut output, &base_lines[base_position..group_start]);
+
+        let ours_start = (group_start as isize + ours_offset) as usize;
+        let theirs_start = (group_start as isize + theirs_offset) as usize;
+        for hunk in &ours_hunks[ours_first..ours_index] {
+            ours_offset += hunk.side.len() as isize - hunk.base.len() as isize;
+        }
+        for hunk in &theirs_hunks[theirs_first..theirs_index] {
+            theirs_offset += hunk.side.len() as isize - hunk.base.len() as isize;
+        }
+        let ours_region = &ours_lines[ours_start..(group_end as isize + ours_offset) as usize];
+        let theirs_region = &theirs_lines[theirs_start..(group_end as isize + theirs_offset) as usize];
+
+        let ours_changed = ours_index > ours_first;
+        let theirs_changed = theirs_index > theirs_first;
+        if !theirs_changed || ours_region == theirs_region {
+            push_lines(&mut output, ours_region);
+        }
+        else if !ours_changed {
+            push_lines(&mut output, theirs_region);
+        }
+        else {
+            conflicts += 1;
+            output.push_str(&format!("{} {}\n", CONFLICT_START, ours_label));
+            push_conflict_lines(&mut output, ours_region);
+            output.push_str(&format!("{}\n", CONFLICT_SEPARATOR));
+            push_conflict_lines(&mut output, theirs_region);
+            output.push_str(&format!("{} {}\n", CONFLICT_END, theirs_label));
+        }
+        base_position = group_end;
+    }
+    push_lines(&mut output, &base_lines[base_position..]);
+
+    MergeResult {
+        contents: output.into_bytes(),
+        conflicts,
+    }
+}
+
+// True if a line of the file still starts with a conflict marker
+pub fn has_conflict_markers(data: &[u8]) -> bool {
+    data.split(|byte| *byte == b'\n')
+        .any(|line| line.starts_with(CONFLICT_START.as_bytes()) || line.starts_with(CONFLICT_END.as_bytes()))
+}
+
+fn read_text(entry: &TreeEntry) -> Option<String> {
+    if entry.kind != EntryKind::File || entry.chunks.is_some() {
+        return None;
+    }
+    let contents = entry.read().ok()?;
+    if is_binary(&contents) {
+        return None;
+    }
+    String::from_utf8(contents).ok()
+}
+
+// Only small text files can be merged line by line, an entry that is missing in base counts as empty
+fn merge_entries(base: Option<&TreeEntry>, ours: &TreeEntry, theirs: &TreeEntry, ours_label: &str, theirs_label: &str) -> Option<MergeResult> {
+    let base_text = match base {
+        Some(base) => read_text(base)?,
+        None => String::new()
+    };
+    let ours_text = read_text(ours)?;
+    let theirs_text = read_text(theirs)?;
+    Some(merge_text(&base_text, &ours_text, &theirs_text, ours_label, theirs_label))
+}
+
+impl Repo {
+    // Applies the changes that turn base into target to the working tree.
+    // Entries that were changed in the working tree as well are merged line by line, whatever can't be merged
+    // is left as it is (or written with conflict markers) and reported as a conflict
+    pub(crate) fn apply_changes(&self, base: &Tree, target: &Tree, ours_label: &str, theirs_label: &str) -> Result<ApplyOutcome, ()> {
+        let changes = compare(base, target);
+        let mut outcome = ApplyOutcome {
+            changed_files: vec![],
+            removed_files: vec![],
+            conflicts: vec![],
+        };
+
+        // removals first and contents before their directories, so directories can be removed once they are empty
+        for change in changes.iter().rev().filter(|change| change.new.is_none()) {
+            let working_path = self.root_dir().join(change.path.to_path_buf());
+            let ours = self.working_entry(&change.path);
+            match &ours {
+                None => {},
+                Some(ours) if same_entry(Some(ours), change.old.as_ref()) => {
+                    if ours.kind == EntryKind::Directory {
+                        // a directory that still has contents stays tracked
+                        if std::fs::remove_dir(&working_path).is_err() {
+                            continue;
+                        }
+                    }
+                    else if let Err(e) = std::fs::remove_file(&working_path) {
+                        dprintln!("[ERROR] Cannot remove {}: {}", working_path.display(), e);
+                        return Err(());
+                    }
+                    outcome.removed_files.push(change.path.clone());
+                },
+                Some(_) => outcome.conflicts.push(change.path.clone())
+            }
+        }
+
+        for change in changes.iter().filter(|change| change.new.is_some()) {
+            let working_path = self.root_dir().join(change.path.to_path_buf());
+            let theirs = change.new.as_ref().unwrap();
+            let ours = self.working_entry(&change.path);
+            if same_entry(ours.as_ref(), Some(theirs)) {
+                continue;
+            }
+            if same_entry(ours.as_ref(), change.old.as_ref()) {
+                restore_entry(theirs, &working_path)?;
+                outcome.changed_files.push(change.path.clone());
+                continue;
+            }
+            let merged = match &ours {
+                Some(ours) => merge_entries(change.old.as_ref(), ours, theirs, ours_label, theirs_label),
+                None => None
+            };
+            match merged {
+                Some(merged) => {
+                    if let Err(e) = std::fs::write(&working_path, &merged.contents) {
+                        dprintln!("[ERROR] Cannot write {}: {}", working_path.display(), e);
+                        return Err(());
+                    }
+                    if merged.conflicts > 0 {
+                        dprintln!("[INFO] {} conflicting regions in {}", merged.conflicts, change.path);
+                        outcome.conflicts.push(change.path.clone());
+                    }
+                    else {
+                        outcome.changed_files.push(change.path.clone());
+                    }
+                },
+                None => outcome.conflicts.push(change.path.clone())
+            }
+        }
+        Ok(outcome)
+    }
+
+    // Conflicting entries that were not staged yet or still contain conflict markers, deleting an entry resolves it as well
+    pub(crate) fn unresolved_conflicts(&self, conflicts: &[RepoPath]) -> Vec<RepoPath> {
+        conflicts.iter()
+            .filter(|path| {
+                if self.staged_removals.contains(path) || self.working_entry(path).is_none() {
+                    return false;
+                }
+                if !self.staged_files.contains(path) {
+                    return true;
+                }
+                match self.working_entry(path).map(|entry| entry.read()) {
+                    Some(Ok(contents)) => has_conflict_markers(&contents),
+                    _ => false
+                }
+            })
+            .cloned()
+            .collect()
+    }
+
+    // Conflicts that were resolved by deleting the entry are committed as removals
+    pub(crate) fn stage_resolved_removals(&mut self, conflicts: &[RepoPath]) {
+        for path in conflicts.iter() {
+            if self.working_entry(path).is_none() && !self.staged_removals.contains(path) {
+                self.staged_files.retain(|file| file != path);
+                self.staged_removals.push(path.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{has_conflict_markers, merge_text};
+    use crate::lostcontrol::{Repo, RepoPath};
+    use crate::lostcontrol::test_support::{tree_with, TestDir};
+
+    fn merged(base: &str, ours: &str, theirs: &str) -> (String, usize) {
+        let result = merge_text(base, ours, theirs, "ours", "theirs");
+        (String::from_utf8(result.contents).unwrap(), result.conflicts)
+    }
+
+    #[test]
+    fn changes_to_different_lines_merge_cleanly() {
+        let (text, conflicts) = merged("a\nb\nc\nd\n", "A\nb\nc\nd\n", "a\nb\nc\nD\n");
+        assert_eq!(conflicts, 0);
+        assert_eq!(text, "A\nb\nc\nD\n");
+        assert!(!has_conflict_markers(text.as_bytes()));
+    }
+
+    #[test]
+    fn identical_changes_merge_cleanly() {
+        let (text, conflicts) = merged("a\nb\n", "a\nB\nc\n", "a\nB\nc\n");
+        assert_eq!(conflicts, 0);
+        assert_eq!(text, "a\nB\nc\n");
+    }
+
+    #[test]
+    fn changes_to_the_same_line_conflict() {
+        let (text, conflicts) = merged("a\nb\nc\n", "a\nours\nc\n", "a\ntheirs\nc\n");
+        assert_eq!(conflicts, 1);
+        assert_eq!(text, "a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\n");
+        assert!(has_conflict_markers(text.as_bytes()));
+    }
+
+    #[test]
+    fn conflict_markers_start_on_their_own_line() {
+        let (text, conflicts) = merged("a", "ours", "theirs");
+        assert_eq!(conflicts, 1);
+        assert_eq!(text, "<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\n");
+    }
+
+    #[test]
+    fn markers_inside_a_line_are_not_conflicts() {
+        assert!(!has_conflict_markers(b"text <<<<<<< inside\n"));
+        assert!(has_conflict_markers(b"text\n>>>>>>> theirs"));
+    }
+
+    #[test]
+    fn deleting_a_modified_file_conflicts() {
+        let test_dir = TestDir::new("merge-delete-modify");
+        let root = test_dir.path.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        let repo = Repo::new("test", &root).unwrap();
+        let base = tree_with(&test_dir.path.join("base"), &[("f", "a\n"), ("g", "g\n")]);
+        let target = tree_with(&test_dir.path.join("target"), &[("g", "g\n")]);
+        std::fs::write(root.join("f"), "changed\n").unwrap();
+        std::fs::write(root.join("g"), "g\n").unwrap();
+
+        let outcome = repo.apply_changes(&base, &target, "ours", "theirs").unwrap();
+        assert_eq!(outcome.conflicts, vec![RepoPath::from_path(Path::new("f"))]);
+        assert!(outcome.removed_files.is_empty());
+        assert_eq!(std::fs::read_to_string(root.join("f")).unwrap(), "changed\n");
+    }
+
+    #[test]
+    fn modifying_a_deleted_file_conflicts() {
+        let test_dir = TestDir::new("merge-modify-delete");
+        let root = test_dir.path.join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        let repo = Repo::new("test", &root).unwrap();
+        let base = tree_with(&test_dir.path.join("base"), &[("f", "a\n")]);
+        let target = tree_with(&test_dir.path.join("target"), &[("f", "b\n")]);
+
+        let outcome = repo.apply_changes(&base, &target, "ours", "theirs").unwrap();
+        assert_eq!(outcome.conflicts, vec![RepoPath::from_path(Path::new("f"))]);
+        assert!(!root.join("f").exists());
+    }
+}
@@ -1,12 +1,36 @@
-const CURRENT_CONFIG_VERSION: &str = "0.0.5";
+// 0.0.6 writes paths as %XX escaped bytes relative to the repository root
+const CURRENT_CONFIG_VERSION: &str = "0.0.6";
 
 mod commit;
 mod repo;
 mod branch_config;
+mod repo_path;
+mod stash;
+mod revert;
+mod cherry_pick;
+mod blame;
+mod index;
+mod staging;
+pub mod chunks;
+pub mod tree;
+pub mod diff;
+pub mod merge;
+pub mod rebase;
+pub mod reflog;
+pub mod oplog;
+pub mod bisect;
+pub mod grep;
+pub mod restore;
+pub mod archive;
+pub mod rename;
+pub mod patch;
+#[cfg(test)]
+mod test_support;
 
-pub use self::commit::Commit;
+pub use self::commit::{Commit, CommitSource, RenamedFile};
 pub use self::repo::Repo;
 pub use self::branch_config::BranchConfig;
+pub use self::repo_path::RepoPath;
 
 macro_rules! dprintln {
     ($($arg:tt)*) => (
@@ -20,17 +44,81 @@ pub(crate) use dprintln;
 
 // TODO: These functions need to be rewritten to support a more generic path format
 pub mod fs_operations {
-    use std::path::PathBuf;
+    use std::collections::HashSet;
+    use std::path::{Component, Path, PathBuf};
 
-    pub fn grab_directories(path: &PathBuf) -> Result<Vec<PathBuf>,()> {
+    // Returns true if the path itself is a symbolic link, without following it
+    pub fn is_symlink(path: &Path) -> bool {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata.file_type().is_symlink(),
+            Err(_) => false
+        }
+    }
+
+    // Returns true if anything, including a dangling symlink, exists at the path
+    pub fn entry_exists(path: &Path) -> bool {
+        std::fs::symlink_metadata(path).is_ok()
+    }
+
+    #[cfg(unix)]
+    pub fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+        std::os::unix::fs::symlink(target, link)
+    }
+
+    #[cfg(windows)]
+    pub fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+        let resolved_target = match link.parent() {
+            Some(parent) => parent.join(target),
+            None => target.to_path_buf()
+        };
+        if resolved_target.is_dir() {
+            std::os::windows::fs::symlink_dir(target, link)
+        }
+        else {
+            std::os::windows::fs::symlink_file(target, link)
+        }
+    }
+
+    // Recreates the symlink at source as destination, replacing whatever is there
+    pub fn copy_symlink(source: &Path, destination: &Path) -> std::io::Result<()> {
+        let target = std::fs::read_link(source)?;
+        remove_entry(destination)?;
+        create_symlink(&target, destination)
+    }
+
+    // Removes a file, symlink or directory without following symlinks, missing entries are not an error
+    pub fn remove_entry(path: &Path) -> std::io::Result<()> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+            Ok(_) => std::fs::remove_file(path),
+            Err(_) => Ok(())
+        }
+    }
+
+    // Resolves . and .. without touching the file system, so symlinks are left alone
+    pub fn normalize_path(path: &Path) -> PathBuf {
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::CurDir => {},
+                Component::ParentDir => {
+                    normalized.pop();
+                },
+                component => normalized.push(component.as_os_str())
+            }
+        }
+        normalized
+    }
+
+    // Symlinked directories are never descended into, they are returned by grab_files instead
+    pub fn grab_directories(path: &Path) -> Result<Vec<PathBuf>,()> {
         if path.exists() && path.is_dir() {
             let entries = std::fs::read_dir(path).unwrap();
             let mut files: Vec<PathBuf> = Vec::new();
             for entry in entries {
                 let entry = entry.unwrap();
-                let path = entry.path();
-                if path.is_dir() {
-                    files.push(path);
+                if entry.file_type().unwrap().is_dir() {
+                    files.push(entry.path());
                 }
             }
             return Ok(files);
@@ -38,15 +126,16 @@ pub mod fs_operations {
         Err(())
     }
 
-    pub fn grab_files(path: &PathBuf) -> Result<Vec<PathBuf>, ()> {
+    // Returns regular files and symlinks, symlinks are not followed
+    pub fn grab_files(path: &Path) -> Result<Vec<PathBuf>, ()> {
         if path.exists() && path.is_dir() {
             let entries = std::fs::read_dir(path).unwrap();
             let mut files: Vec<PathBuf> = Vec::new();
             for entry in entries {
                 let entry = entry.unwrap();
-                let path = entry.path();
-                if path.is_file() {
-                    files.push(path);
+                let file_type = entry.file_type().unwrap();
+                if file_type.is_file() || file_type.is_symlink() {
+                    files.push(entry.path());
                 }
             }
             return Ok(files);
@@ -54,17 +143,26 @@ pub mod fs_operations {
         Err(())
     }
     
-    pub fn expand_directory(path: &PathBuf, ignored_dirs: &Vec<String>) -> Vec<PathBuf> {
+    // Returns every file, symlink and directory below path, the directory itself is not included
+    pub fn expand_directory(path: &Path, ignored_dirs: &[PathBuf]) -> Vec<PathBuf> {
         // get all files in directory recursively
         let mut files: Vec<PathBuf> = Vec::new();
         let mut directories: Vec<PathBuf> = vec![path.to_path_buf()];
-        while directories.len() > 0 {
-            let current_directory = directories.pop().unwrap();
-            let current_directory_string = current_directory.to_str().unwrap().to_string();
-            if ignored_dirs.contains(&current_directory_string) {
-                dprintln!("[INFO] Directory {} is on ignore list, skipping...", &current_directory_string);
+        // guards against cycles if the starting directory is reached through a symlink
+        let mut visited: HashSet<PathBuf> = HashSet::new();
+        while let Some(current_directory) = directories.pop() {
+            if ignored_dirs.contains(&current_directory) {
+                dprintln!("[INFO] Directory {} is on ignore list, skipping...", current_directory.display());
+                continue;
+            }
+            let canonical_directory = std::fs::canonicalize(&current_directory).unwrap_or_else(|_| current_directory.clone());
+            if !visited.insert(canonical_directory) {
+                dprintln!("[WARN] Directory {} was already visited, skipping...", current_directory.display());
                 continue;
             }
+            if current_directory != path {
+                files.push(current_directory.clone());
+            }
     
             let mut child_dirs = grab_directories(&current_directory).unwrap();
             directories.append(&mut child_dirs);
@@ -77,4 +175,24 @@ pub mod fs_operations {
         }
         files
     }
-}
\ No newline at end of file
+
+    // Copies a directory tree, symlinks are recreated instead of followed
+    pub fn copy_directory(source: &Path, destination: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(destination)?;
+        for entry in std::fs::read_dir(source)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let target = destination.join(entry.file_name());
+            if file_type.is_symlink() {
+                copy_symlink(&entry.path(), &target)?;
+            }
+            else if file_type.is_dir() {
+                copy_directory(&entry.path(), &target)?;
+            }
+            else {
+                std::fs::copy(entry.path(), &target)?;
+            }
+        }
+        Ok(())
+    }
+}
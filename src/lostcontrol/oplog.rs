@@ -0,0 +1,375 @@
+use std::path::{Path, PathBuf};
+use std::io::{BufReader, BufRead, Write};
+use serde::{self, Deserialize, Serialize};
+use serde_yaml::{self};
+
+use crate::lostcontrol::{Repo, RepoPath, CURRENT_CONFIG_VERSION, dprintln};
+
+pub(crate) const OPLOG_DIR: &str = "oplog";
+const OPLOG_CONFIG_FILE: &str = "oplog.conf";
+const TEMPORARY_EXTENSION: &str = "undo-tmp";
+// older operations are dropped once the log grows past this
+const MAX_OPERATIONS: usize = 50;
+const TIME_FORMAT_STRING: &str = "%Y-%m-%d %H:%M:%S";
+
+// A config file of the repository before and after an operation, None if it didn't exist
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConfigSnapshot {
+    // relative to the root of the repository
+    pub path: PathBuf,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Operation {
+    pub command: String,
+    pub timestamp: String,
+    pub user: String,
+    pub branch_before: String,
+    pub branch_after: String,
+    pub snapshots: Vec<ConfigSnapshot>,
+}
+
+impl Operation {
+    pub fn get_time_formatted(&self) -> String {
+        let timestamp: chrono::DateTime<chrono::Local> =
+            chrono::DateTime::from(chrono::DateTime::parse_from_rfc3339(&self.timestamp).unwrap());
+        timestamp.format(TIME_FORMAT_STRING).to_string()
+    }
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} ({}, {})", self.command, self.user, self.get_time_formatted())
+    }
+}
+
+// The configs of the repository as they are on disk right now
+pub struct ConfigCapture {
+    branch: String,
+    files: Vec<(PathBuf, Option<String>)>,
+}
+
+// The operations that can be undone, everything from position on was undone and can be redone
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OperationLog {
+    #[serde(skip)]
+    closed: bool,
+    #[serde(skip)]
+    modified: bool,
+    #[serde(skip)]
+    config_path: PathBuf,
+    position: usize,
+    operations: Vec<Operation>,
+}
+
+impl OperationLog {
+    pub fn new(config_path: &Path) -> OperationLog {
+        OperationLog {
+            closed: false,
+            modified: false,
+            config_path: config_path.to_path_buf(),
+            position: 0,
+            operations: vec![],
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<OperationLog, ()> {
+        let mut oplog_config = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                dprintln!("[ERROR] Cannot load Operation Log {}: {}", path.display(), e);
+                return Err(());
+            }
+        };
+        let mut version = String::new();
+        let mut contents = String::new();
+
+        let buf_reader = BufReader::new(&mut oplog_config);
+        for (index, line) in buf_reader.lines().enumerate() {
+            match index {
+                0 => version = line.unwrap(),
+                _ => {
+                    let line_string: String = line.unwrap() + "\n";
+                    contents.push_str(line_string.as_str());
+                }
+            }
+        }
+
+        if version != CURRENT_CONFIG_VERSION {
+            dprintln!("[ERROR] Operation Log {}: Operation Log version {} is not supported", path.display(), version);
+            return Err(())
+        }
+
+        let mut oplog: OperationLog = match serde_yaml::from_str(&contents) {
+            Ok(oplog) => oplog,
+            Err(_) => {
+                dprintln!("[ERROR] Operation Log {}: Cannot parse Operation Log", path.display());
+                return Err(());
+            }
+        };
+        oplog.config_path = path.to_path_buf();
+        Ok(oplog)
+    }
+
+    // The operation undo would take back
+    pub fn undoable(&self) -> Option<&Operation> {
+        match self.position {
+            0 => None,
+            _ => self.operations.get(self.position - 1)
+        }
+    }
+
+    // The operation redo would apply again
+    pub fn redoable(&self) -> Option<&Operation> {
+        self.operations.get(self.position)
+    }
+
+    // A new operation replaces everything that was undone
+    fn push_operation(&mut self, operation: Operation) {
+        self.operations.truncate(self.position);
+        self.operations.push(operation);
+        if self.operations.len() > MAX_OPERATIONS {
+            self.operations.remove(0);
+        }
+        self.position = self.operations.len();
+        self.modified = true;
+    }
+
+    fn move_position(&mut self, redo: bool) {
+        match redo {
+            true => self.position += 1,
+            false => self.position -= 1
+        }
+        self.modified = true;
+    }
+
+    pub fn close(&mut self) {
+        if self.closed || !self.modified {
+            return;
+        }
+        let config_str = serde_yaml::to_string(&self).unwrap();
+        let mut oplog_config = match std::fs::File::create(&self.config_path) {
+            Ok(file) => file,
+            Err(e) => panic!("Cannot create Operation Log {}: {}", self.config_path.display(), e)
+        };
+
+        writeln!(oplog_config, "{}", CURRENT_CONFIG_VERSION).unwrap();
+        write!(oplog_config, "{}", config_str).unwrap();
+
+        self.closed = true;
+    }
+}
+
+impl Drop for OperationLog {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn read_config(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path).ok()
+}
+
+impl Repo {
+    fn oplog_dir(&self) -> PathBuf {
+        self.repos_dir().join(OPLOG_DIR)
+    }
+
+    pub fn get_operation_log(&self) -> OperationLog {
+        let config_path = self.oplog_dir().join(OPLOG_CONFIG_FILE);
+        if config_path.exists() {
+            if let Ok(oplog) = OperationLog::from_file(&config_path) {
+                return oplog;
+            }
+        }
+        OperationLog::new(&config_path)
+    }
+
+    // The metafile and the config of every branch, relative to the root of the repository
+    fn config_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = vec![self.metafile_path().strip_prefix(self.root_dir()).unwrap().to_path_buf()];
+        for branch in self.branches.iter() {
+            let config_path = self.repos_dir().join(branch).join(format!("{}.conf", branch));
+            paths.push(config_path.strip_prefix(self.root_dir()).unwrap().to_path_buf());
+        }
+        paths
+    }
+
+    // Reads the metafile and the config of every branch
+    pub fn capture_configs(&self) -> ConfigCapture {
+        ConfigCapture {
+            branch: self.current_branch.clone(),
+            files: self.config_paths().into_iter()
+                .map(|path| {
+                    let contents = read_config(&self.root_dir().join(&path));
+                    (path, contents)
+                })
+                .collect(),
+        }
+    }
+
+    // Adds a command to the operation log, if it changed any of the configs captured before it ran.
+    // The repository has to be closed already, so its configs are on disk
+    pub fn record_operation(&self, command: &str, before: ConfigCapture) {
+        let mut files = before.files;
+        // configs of branches the command created didn't exist before it ran
+        for path in self.config_paths() {
+            if !files.iter().any(|(captured, _)| *captured == path) {
+                files.push((path, None));
+            }
+        }
+        let mut snapshots: Vec<ConfigSnapshot> = vec![];
+        for (path, before_contents) in files.into_iter() {
+            let after_contents = read_config(&self.root_dir().join(&path));
+            if before_contents != after_contents {
+                snapshots.push(ConfigSnapshot {
+                    path,
+                    before: before_contents,
+                    after: after_contents,
+                });
+            }
+        }
+        if snapshots.is_empty() {
+            return;
+        }
+
+        if let Err(e) = std::fs::create_dir_all(self.oplog_dir()) {
+            dprintln!("[ERROR] Cannot create operation log directory {}: {}", self.oplog_dir().display(), e);
+            return;
+        }
+        let mut oplog = self.get_operation_log();
+        oplog.push_operation(Operation {
+            command: command.to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            user: self.author(),
+            branch_before: before.branch,
+            branch_after: self.current_branch.clone(),
+            snapshots,
+        });
+        oplog.close();
+    }
+
+    // Uncommitted changes that checking out the other branch would throw away, if undoing or redoing switches branches
+    pub fn undo_blockers(&self, redo: bool) -> Result<Vec<RepoPath>, ()> {
+        let oplog = self.get_operation_log();
+        let operation = match redo {
+            true => oplog.redoable(),
+            false => oplog.undoable()
+        };
+        let target_branch = match (operation, redo) {
+            (Some(operation), true) => &operation.branch_after,
+            (Some(operation), false) => &operation.branch_before,
+            (None, _) => return Ok(vec![])
+        };
+        if *target_branch == self.current_branch {
+            return Ok(vec![]);
+        }
+        self.switch_blockers(target_branch)
+    }
+
+    // Puts the configs back to their state before (or with redo, after) the last operation. Every file is written
+    // next to its target first and only renamed into place once all of them were written.
+    // The working tree is only touched if the current branch changes, callers are expected to check undo_blockers first.
+    // The repository is discarded afterwards, as its state in memory is outdated
+    pub fn undo_operation(&mut self, redo: bool) -> Result<Operation, ()> {
+        if let Some(operation) = self.pending_operation() {
+            dprintln!("[ERROR] A {} is in progress", operation);
+            return Err(());
+        }
+        let mut oplog = self.get_operation_log();
+        let operation = match redo {
+            true => oplog.redoable().cloned(),
+            false => oplog.undoable().cloned()
+        };
+        let operation = match operation {
+            Some(operation) => operation,
+            None => {
+                dprintln!("[ERROR] Nothing to {}", if redo { "redo" } else { "undo" });
+                return Err(());
+            }
+        };
+
+        // a command that isn't logged changed the configs since, going back would throw its changes away
+        for snapshot in operation.snapshots.iter() {
+            let expected = match redo {
+                true => &snapshot.before,
+                false => &snapshot.after
+            };
+            if read_config(&self.root_dir().join(&snapshot.path)) != *expected {
+                dprintln!("[ERROR] {} was changed since the operation", snapshot.path.display());
+                return Err(());
+            }
+        }
+
+        let target_branch = match redo {
+            true => operation.branch_after.clone(),
+            false => operation.branch_before.clone()
+        };
+        let checkout_trees = match target_branch == self.current_branch {
+            true => None,
+            false => {
+                let current_config = self.get_branch(&self.current_branch)?;
+                let target_config = self.get_branch(&target_branch)?;
+                Some((self.get_tree(&current_config, current_config.current_commit)?,
+                    self.get_tree(&target_config, target_config.current_commit)?))
+            }
+        };
+        let heads_before: Vec<(String, usize)> = self.get_branches()?.iter()
+            .map(|branch_config| (branch_config.name.clone(), branch_config.current_commit))
+            .collect();
+
+        let mut written: Vec<(PathBuf, PathBuf)> = vec![];
+        for snapshot in operation.snapshots.iter() {
+            let contents = match redo {
+                true => &snapshot.after,
+                false => &snapshot.before
+            };
+            let target_path = self.root_dir().join(&snapshot.path);
+            if let Some(contents) = contents {
+                let mut temporary_path = target_path.clone();
+                temporary_path.set_extension(TEMPORARY_EXTENSION);
+                if let Err(e) = std::fs::write(&temporary_path, contents) {
+                    dprintln!("[ERROR] Cannot write {}: {}", temporary_path.display(), e);
+                    for (temporary_path, _) in written.iter() {
+                        let _ = std::fs::remove_file(temporary_path);
+                    }
+                    return Err(());
+                }
+                written.push((temporary_path, target_path));
+            }
+        }
+        for (temporary_path, target_path) in written.iter() {
+            if let Err(e) = std::fs::rename(temporary_path, target_path) {
+                dprintln!("[ERROR] Cannot replace {}: {}", target_path.display(), e);
+                return Err(());
+            }
+        }
+        for snapshot in operation.snapshots.iter() {
+            let contents = match redo {
+                true => &snapshot.after,
+                false => &snapshot.before
+            };
+            if contents.is_none() {
+                let _ = std::fs::remove_file(self.root_dir().join(&snapshot.path));
+            }
+        }
+
+        if let Some((current_tree, target_tree)) = checkout_trees {
+            self.checkout_tree(&current_tree, &target_tree)?;
+        }
+        let description = format!("{} {}", if redo { "redo" } else { "undo" }, operation.command);
+        for (branch, old_commit) in heads_before.into_iter() {
+            if let Ok(branch_config) = self.get_branch(&branch) {
+                if branch_config.current_commit != old_commit {
+                    self.log_head_change(&branch_config, old_commit, &description);
+                }
+            }
+        }
+        self.discard();
+        oplog.move_position(redo);
+        oplog.close();
+        Ok(operation)
+    }
+}
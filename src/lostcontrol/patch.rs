@@ -0,0 +1,733 @@
+use sha2::{Digest, Sha256};
+
+use crate::lostcontrol::{Commit, CommitSource, RenamedFile, Repo, RepoPath, dprintln};
+use crate::lostcontrol::diff::{format_stat, is_binary, DiffOptions, FilePatch, FileStat};
+use crate::lostcontrol::fs_operations::{create_symlink, remove_entry};
+use crate::lostcontrol::tree::{compare, ChangeKind, EntryKind, TreeEntry};
+
+// first line of every patch file, followed by the branch and id of the exported commit
+const PATCH_MAGIC: &str = "From lostcontrol";
+const SECTION_PREFIX: &str = "diff --lostcontrol ";
+const MESSAGE_INDENT: &str = "    ";
+// raw bytes per line of base85 encoded contents, the same as git uses
+const BASE85_LINE_BYTES: usize = 52;
+const BASE85_ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!#$%&()*+-;<=>?@^_`{|}~";
+// longest part of the subject that ends up in a patch file name
+const FILE_NAME_SUBJECT_LENGTH: usize = 52;
+
+// Every line starts with a letter for the number of bytes it holds, A-Z for 1-26 and a-z for 27-52
+fn encode_base85(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for line in data.chunks(BASE85_LINE_BYTES) {
+        let length_char = match line.len() {
+            length @ 1..=26 => (b'A' + length as u8 - 1) as char,
+            length => (b'a' + length as u8 - 27) as char
+        };
+        encoded.push(length_char);
+        for group in line.chunks(4) {
+            let mut padded = [0u8; 4];
+            padded[..group.len()].copy_from_slice(group);
+            let mut value = u32::from_be_bytes(padded);
+            let mut digits = [0u8; 5];
+            for digit in digits.iter_mut().rev() {
+                *digit = BASE85_ALPHABET[(value % 85) as usize];
+                value /= 85;
+            }
+            encoded.push_str(std::str::from_utf8(&digits).unwrap());
+        }
+        encoded.push('\n');
+    }
+    encoded
+}
+
+fn decode_base85_line(line: &str) -> Option<Vec<u8>> {
+    let bytes = line.as_bytes();
+    let length = match bytes.first()? {
+        length_char @ b'A'..=b'Z' => (length_char - b'A' + 1) as usize,
+        length_char @ b'a'..=b'z' => (length_char - b'a' + 27) as usize,
+        _ => return None
+    };
+    let digits = &bytes[1..];
+    if digits.len() != length.div_ceil(4) * 5 {
+        return None;
+    }
+    let mut decoded: Vec<u8> = Vec::with_capacity(digits.len() / 5 * 4);
+    for group in digits.chunks(5) {
+        let mut value: u64 = 0;
+        for digit in group.iter() {
+            let position = BASE85_ALPHABET.iter().position(|character| character == digit)?;
+            value = value * 85 + position as u64;
+        }
+        decoded.extend_from_slice(&u32::try_from(value).ok()?.to_be_bytes());
+    }
+    decoded.truncate(length);
+    Some(decoded)
+}
+
+fn hash_contents(data: &[u8]) -> String {
+    let digest = Sha256::digest(data);
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Binary files and files that aren't valid UTF-8 can't be shown as lines without losing bytes
+fn is_text(data: &[u8]) -> bool {
+    !is_binary(data) && std::str::from_utf8(data).is_ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchAction {
+    Create,
+    Modify,
+    Delete,
+}
+
+// A changed region as written in the patch, lines keep their line break unless the file ends without one
+#[derive(Debug, Clone)]
+struct PatchHunk {
+    // counting from 0
+    old_start: usize,
+    lines: Vec<(char, String)>,
+}
+
+#[derive(Debug, Clone)]
+enum PatchContents {
+    // directories and deleted entries
+    Empty,
+    Text(Vec<PatchHunk>),
+    Binary(Vec<u8>),
+    SymlinkTarget(RepoPath),
+}
+
+// The change of a single entry
+#[derive(Debug, Clone)]
+pub struct PatchSection {
+    pub path: RepoPath,
+    pub action: PatchAction,
+    // of the entry after the change, or of the deleted one
+    pub kind: EntryKind,
+    // hashes of the contents before and after, to notice files that don't match
+    old_hash: Option<String>,
+    new_hash: Option<String>,
+    contents: PatchContents,
+}
+
+// A commit read from a patch file
+#[derive(Debug, Clone)]
+pub struct CommitPatch {
+    // where the commit was exported from, as <branch>:<id>
+    pub source: String,
+    pub author: String,
+    // when the exported commit was written, it becomes the author date of the applied one
+    pub authored_datetime: String,
+    pub message: String,
+    pub renamed_files: Vec<RenamedFile>,
+    pub sections: Vec<PatchSection>,
+}
+
+fn kind_name(kind: EntryKind) -> &'static str {
+    match kind {
+        EntryKind::File => "file",
+        EntryKind::Directory => "directory",
+        EntryKind::Symlink => "symlink",
+    }
+}
+
+fn kind_from_name(name: &str) -> Option<EntryKind> {
+    match name {
+        "file" => Some(EntryKind::File),
+        "directory" => Some(EntryKind::Directory),
+        "symlink" => Some(EntryKind::Symlink),
+        _ => None
+    }
+}
+
+// Writes the section of one entry, old and new are None for entries that don't exist on that side
+fn format_section(path: &RepoPath, old: Option<&TreeEntry>, new: Option<&TreeEntry>) -> Result<String, ()> {
+    let read = |entry: Option<&TreeEntry>| -> Result<Vec<u8>, ()> {
+        match entry {
+            Some(entry) => entry.read().map_err(|e| {
+                dprintln!("[ERROR] Cannot read {}: {}", path, e);
+            }),
+            None => Ok(vec![])
+        }
+    };
+    let old_contents = read(old)?;
+    let new_contents = read(new)?;
+    let mut section = format!("{}{}\n", SECTION_PREFIX, path);
+    let kind = match (old, new) {
+        (_, Some(entry)) => entry.kind,
+        (Some(entry), None) => entry.kind,
+        (None, None) => return Ok(String::new())
+    };
+    match (old, new) {
+        (None, _) => section.push_str(&format!("new {}\n", kind_name(kind))),
+        (_, None) => section.push_str(&format!("deleted {}\n", kind_name(kind))),
+        _ => section.push_str(&format!("modified {}\n", kind_name(kind)))
+    }
+    if kind == EntryKind::Directory {
+        return Ok(section);
+    }
+    let hash_of = |entry: Option<&TreeEntry>, contents: &[u8]| entry.map_or(String::from("0"), |_| hash_contents(contents));
+    section.push_str(&format!("index {} {}\n", hash_of(old, &old_contents), hash_of(new, &new_contents)));
+    if new.is_none() {
+        return Ok(section);
+    }
+    if kind == EntryKind::Symlink {
+        section.push_str(&format!("symlink {}\n", RepoPath::from_bytes(new_contents).encode()));
+        return Ok(section);
+    }
+    if !is_text(&old_contents) || !is_text(&new_contents) {
+        section.push_str(&format!("binary {}\n", new_contents.len()));
+        section.push_str(&encode_base85(&new_contents));
+        return Ok(section);
+    }
+    let old_text = String::from_utf8_lossy(&old_contents);
+    let new_text = String::from_utf8_lossy(&new_contents);
+    let file_patch = FilePatch::new(&old_text, &new_text);
+    if !file_patch.hunks.is_empty() {
+        let old_name = old.map_or(String::from("/dev/null"), |_| format!("a/{}", path));
+        let new_name = format!("b/{}", path);
+        section.push_str(&format!("--- {}\n+++ {}\n", old_name, new_name));
+        for hunk in file_patch.hunks.iter() {
+            section.push_str(&file_patch.format_hunk(hunk));
+        }
+    }
+    Ok(section)
+}
+
+// Reads the numbers of a "@@ -start,count +start,count @@" line, counting from 1 like format_hunk writes them
+fn parse_hunk_header(line: &str) -> Option<(usize, usize, usize)> {
+    let ranges = line.strip_prefix("@@ -")?.split(" @@").next()?;
+    let (old_range, new_range) = ranges.split_once(" +")?;
+    let parse_range = |range: &str| -> Option<(usize, usize)> {
+        match range.split_once(',') {
+            Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+            None => Some((range.parse().ok()?, 1))
+        }
+    };
+    let (old_start, old_count) = parse_range(old_range)?;
+    let (_, new_count) = parse_range(new_range)?;
+    Some((old_start.saturating_sub(1), old_count, new_count))
+}
+
+// Parses the hunks of a text section, lines is positioned after the +++ line
+fn parse_hunks<'a, I: Iterator<Item = &'a str>>(lines: &mut std::iter::Peekable<I>) -> Option<Vec<PatchHunk>> {
+    let mut hunks: Vec<PatchHunk> = vec![];
+    while let Some(line) = lines.peek() {
+        if !line.starts_with("@@ ") {
+            break;
+        }
+        let (old_start, mut old_left, mut new_left) = parse_hunk_header(line.trim_end())?;
+        lines.next();
+        let mut hunk_lines: Vec<(char, String)> = vec![];
+        while old_left > 0 || new_left > 0 || lines.peek().is_some_and(|line| line.starts_with('\\')) {
+            let line = lines.next()?;
+            let tag = line.chars().next()?;
+            match tag {
+                ' ' => { old_left = old_left.checked_sub(1)?; new_left = new_left.checked_sub(1)?; },
+                '-' => old_left = old_left.checked_sub(1)?,
+                '+' => new_left = new_left.checked_sub(1)?,
+                // the line before has no line break
+                '\\' => {
+                    let (_, previous) = hunk_lines.last_mut()?;
+                    previous.pop();
+                    continue;
+                },
+                _ => return None
+            }
+            hunk_lines.push((tag, line[1..].to_string()));
+        }
+        hunks.push(PatchHunk { old_start, lines: hunk_lines });
+    }
+    Some(hunks)
+}
+
+fn parse_section<'a, I: Iterator<Item = &'a str>>(path: RepoPath, lines: &mut std::iter::Peekable<I>) -> Option<PatchSection> {
+    let (action, kind) = match lines.next()?.trim_end().split_once(' ')? {
+        ("new", kind) => (PatchAction::Create, kind_from_name(kind)?),
+        ("modified", kind) => (PatchAction::Modify, kind_from_name(kind)?),
+        ("deleted", kind) => (PatchAction::Delete, kind_from_name(kind)?),
+        _ => return None
+    };
+    let mut section = PatchSection {
+        path,
+        action,
+        kind,
+        old_hash: None,
+        new_hash: None,
+        contents: PatchContents::Empty,
+    };
+    if kind == EntryKind::Directory {
+        return Some(section);
+    }
+    let (old_hash, new_hash) = lines.next()?.trim_end().strip_prefix("index ")?.split_once(' ')?;
+    section.old_hash = Some(old_hash.to_string()).filter(|hash| hash != "0");
+    section.new_hash = Some(new_hash.to_string()).filter(|hash| hash != "0");
+    if action == PatchAction::Delete {
+        return Some(section);
+    }
+
+    let line = match lines.peek() {
+        Some(line) => line.trim_end_matches('\n'),
+        // an empty file
+        None => {
+            section.contents = PatchContents::Text(vec![]);
+            return Some(section);
+        }
+    };
+    if let Some(target) = line.strip_prefix("symlink ") {
+        section.contents = PatchContents::SymlinkTarget(RepoPath::decode(target));
+        lines.next();
+    }
+    else if let Some(length) = line.strip_prefix("binary ") {
+        let length: usize = length.parse().ok()?;
+        lines.next();
+        let mut contents: Vec<u8> = Vec::with_capacity(length);
+        while contents.len() < length {
+            contents.extend(decode_base85_line(lines.next()?.trim_end())?);
+        }
+        if contents.len() != length {
+            return None;
+        }
+        section.contents = PatchContents::Binary(contents);
+    }
+    else if line.starts_with("--- ") {
+        lines.next();
+        lines.next().filter(|line| line.starts_with("+++ "))?;
+        section.contents = PatchContents::Text(parse_hunks(lines)?);
+    }
+    else {
+        section.contents = PatchContents::Text(vec![]);
+    }
+    Some(section)
+}
+
+impl CommitPatch {
+    // Reads a patch written by Repo::format_patch, None if it is malformed
+    pub fn parse(text: &str) -> Option<CommitPatch> {
+        let mut lines = text.split_inclusive('\n').peekable();
+        let source = lines.next()?.trim_end().strip_prefix(PATCH_MAGIC)?.trim().to_string();
+        let mut patch = CommitPatch {
+            source,
+            author: String::new(),
+            authored_datetime: String::new(),
+            message: String::new(),
+            renamed_files: vec![],
+            sections: vec![],
+        };
+        let mut renamed_from: Option<RepoPath> = None;
+        for line in lines.by_ref() {
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                break;
+            }
+            match line.split_once(": ")? {
+                ("Author", author) => patch.author = author.to_string(),
+                ("Date", date) => patch.authored_datetime = date.to_string(),
+                ("Renamed-From", from) if renamed_from.is_none() => renamed_from = Some(RepoPath::decode(from)),
+                ("Renamed-To", to) => patch.renamed_files.push(RenamedFile { from: renamed_from.take()?, to: RepoPath::decode(to) }),
+                ("Renamed-From", _) => return None,
+                _ => {}
+            }
+        }
+        if renamed_from.is_some() {
+            return None;
+        }
+        // the commit time is shown with chrono later on, a broken one would bring that down
+        chrono::DateTime::parse_from_rfc3339(&patch.authored_datetime).ok()?;
+
+        let mut message_lines: Vec<&str> = vec![];
+        for line in lines.by_ref() {
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line == "---" {
+                break;
+            }
+            message_lines.push(line.strip_prefix(MESSAGE_INDENT).unwrap_or(line));
+        }
+        while message_lines.last() == Some(&"") {
+            message_lines.pop();
+        }
+        patch.message = message_lines.join("\n");
+
+        // the summary after --- is only meant for people reading the patch
+        while lines.peek().is_some_and(|line| !line.starts_with(SECTION_PREFIX)) {
+            lines.next();
+        }
+        while let Some(line) = lines.next() {
+            let path = line.trim_end_matches('\n').strip_prefix(SECTION_PREFIX)?;
+            patch.sections.push(parse_section(RepoPath::decode(path), &mut lines)?);
+        }
+        Some(patch)
+    }
+
+    // Every entry the patch touches
+    pub fn paths(&self) -> Vec<RepoPath> {
+        self.sections.iter().map(|section| section.path.clone()).collect()
+    }
+}
+
+// The new contents of a file after applying the hunks to old, None if they don't fit
+fn apply_hunks(old: &str, hunks: &[PatchHunk]) -> Option<String> {
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let mut output = String::new();
+    let mut position = 0;
+    for hunk in hunks.iter() {
+        if hunk.old_start < position || hunk.old_start > old_lines.len() {
+            return None;
+        }
+        output.extend(old_lines[position..hunk.old_start].iter().copied());
+        position = hunk.old_start;
+        for (tag, line) in hunk.lines.iter() {
+            match tag {
+                '+' => output.push_str(line),
+                _ => {
+                    if old_lines.get(position) != Some(&line.as_str()) {
+                        return None;
+                    }
+                    if *tag == ' ' {
+                        output.push_str(line);
+                    }
+                    position += 1;
+                }
+            }
+        }
+    }
+    output.extend(old_lines[position..].iter().copied());
+    Some(output)
+}
+
+// What an entry looks like once a section is applied
+enum AppliedEntry {
+    Directory,
+    File(Vec<u8>),
+    Symlink(RepoPath),
+}
+
+// A name for the patch file like 0001-fix-the-parser.patch, from its number in the series and the first line of the message
+pub fn patch_file_name(number: usize, commit: &Commit) -> String {
+    let subject = commit.message.lines().next().unwrap_or("");
+    let mut slug = String::new();
+    for character in subject.chars() {
+        if character.is_ascii_alphanumeric() {
+            slug.push(character.to_ascii_lowercase());
+        }
+        else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+        if slug.len() >= FILE_NAME_SUBJECT_LENGTH {
+            break;
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    match slug.is_empty() {
+        true => format!("{:04}.patch", number),
+        false => format!("{:04}-{}.patch", number, slug)
+    }
+}
+
+impl Repo {
+    // Writes a commit of the current branch as a patch: a header with its source, author and time, the indented message,
+    // a summary of the changed files and a section for every changed entry. Text files are stored as unified diffs
+    // against the parent commit, binary files completely in base85
+    pub fn format_patch(&self, commit_id: usize) -> Result<String, ()> {
+        let source = CommitSource {
+            branch: self.current_branch.clone(),
+            commit_id,
+        };
+        let (commit, parent_tree, commit_tree) = self.pick_trees(&source)?;
+        let changes = compare(&parent_tree, &commit_tree);
+
+        let mut patch = format!("{} {}\n", PATCH_MAGIC, source);
+        patch.push_str(&format!("Author: {}\n", commit.author));
+        patch.push_str(&format!("Date: {}\n", commit.authored_datetime()));
+        // a line each, paths may contain " -> " themselves
+        for renamed_file in commit.renamed_files.iter() {
+            patch.push_str(&format!("Renamed-From: {}\n", renamed_file.from));
+            patch.push_str(&format!("Renamed-To: {}\n", renamed_file.to));
+        }
+        patch.push('\n');
+        for line in commit.message.lines() {
+            patch.push_str(&format!("{}{}\n", MESSAGE_INDENT, line));
+        }
+        patch.push_str("---\n");
+        let stats: Vec<FileStat> = changes.iter().filter_map(FileStat::of_change).collect();
+        if !stats.is_empty() {
+            patch.push_str(&format_stat(&stats, &DiffOptions::default()));
+        }
+        patch.push('\n');
+
+        for change in changes.iter() {
+            let old_kind = change.old.as_ref().map(|entry| entry.kind);
+            let new_kind = change.new.as_ref().map(|entry| entry.kind);
+            // an entry that changed its type is deleted and created again
+            if change.kind == ChangeKind::Modified && old_kind != new_kind {
+                patch.push_str(&format_section(&change.path, change.old.as_ref(), None)?);
+                patch.push_str(&format_section(&change.path, None, change.new.as_ref())?);
+                continue;
+            }
+            patch.push_str(&format_section(&change.path, change.old.as_ref(), change.new.as_ref())?);
+        }
+        Ok(patch)
+    }
+
+    // What the entry of a section becomes when applied to the working tree, None if the section doesn't fit
+    fn apply_section(&self, section: &PatchSection) -> Option<Option<AppliedEntry>> {
+        let working_path = self.root_dir().join(section.path.to_path_buf());
+        let working_kind = EntryKind::of(&working_path);
+        let working_contents = match working_kind {
+            Some(EntryKind::File) => std::fs::read(&working_path).ok()?,
+            Some(EntryKind::Symlink) => std::fs::read_link(&working_path).ok()?.as_os_str().as_encoded_bytes().to_vec(),
+            _ => vec![]
+        };
+        match section.action {
+            // a type change deletes the entry first, so only untracked entries can be in the way here
+            PatchAction::Create if section.kind != EntryKind::Directory && working_kind.is_some() => return None,
+            PatchAction::Create if working_kind.is_some_and(|kind| kind != EntryKind::Directory) => return None,
+            PatchAction::Modify | PatchAction::Delete if working_kind != Some(section.kind) => return None,
+            _ => {}
+        }
+        if section.action == PatchAction::Delete {
+            // directories may still hold untracked entries, they are only removed if they end up empty
+            if section.kind != EntryKind::Directory && section.old_hash.as_deref() != Some(hash_contents(&working_contents).as_str()) {
+                return None;
+            }
+            return Some(None);
+        }
+        let applied = match &section.contents {
+            PatchContents::Empty => AppliedEntry::Directory,
+            PatchContents::SymlinkTarget(target) => AppliedEntry::Symlink(target.clone()),
+            PatchContents::Binary(contents) => {
+                // binary files are replaced as a whole, so they have to be exactly what the patch started from
+                if section.action == PatchAction::Modify && section.old_hash.as_deref() != Some(hash_contents(&working_contents).as_str()) {
+                    return None;
+                }
+                AppliedEntry::File(contents.clone())
+            },
+            PatchContents::Text(hunks) => {
+                let old_text = std::str::from_utf8(&working_contents).ok()?;
+                AppliedEntry::File(apply_hunks(old_text, hunks)?.into_bytes())
+            }
+        };
+        Some(Some(applied))
+    }
+
+    // Entries the patch touches whose working tree version differs from the current commit, applying would mix them in
+    pub fn patch_blockers(&self, patches: &[CommitPatch]) -> Result<Vec<RepoPath>, ()> {
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let head_tree = self.get_tree(&branch_config, branch_config.current_commit)?;
+        let mut blockers: Vec<RepoPath> = vec![];
+        for path in patches.iter().flat_map(|patch| patch.paths()) {
+            let head_entry = head_tree.get(&path);
+            let working_entry = self.working_entry(&path);
+            let unchanged = match (head_entry, &working_entry) {
+                (None, None) => true,
+                (Some(head_entry), Some(working_entry)) => head_entry.same_content(working_entry),
+                // a directory the patch creates may already be there untracked
+                (None, Some(working_entry)) => working_entry.kind == EntryKind::Directory,
+                _ => false
+            };
+            if !unchanged && !blockers.contains(&path) {
+                blockers.push(path);
+            }
+        }
+        Ok(blockers)
+    }
+
+    // Entries of the patch that don't fit the working tree, a modified file whose lines moved too far for instance
+    pub fn patch_conflicts(&self, patch: &CommitPatch) -> Vec<RepoPath> {
+        // an entry that changed its type has a delete and a create section, the create one only fits after deleting
+        patch.sections.iter()
+            .filter(|section| {
+                let deleted_before = section.action == PatchAction::Create && patch.sections.iter()
+                    .any(|other| other.path == section.path && other.action == PatchAction::Delete);
+                !deleted_before && self.apply_section(section).is_none()
+            })
+            .map(|section| section.path.clone())
+            .collect()
+    }
+
+    // Writes the changes of the patch into the working tree and commits them with the author and message of the patch,
+    // its time becomes the author date of the commit.
+    // Callers are expected to check patch_blockers and patch_conflicts first. Returns the id of the new commit
+    pub fn apply_patch(&mut self, patch: &CommitPatch, name: &str) -> Result<usize, ()> {
+        if let Some(operation) = self.pending_operation() {
+            dprintln!("[ERROR] A {} is in progress, continue or abort it first", operation);
+            return Err(());
+        }
+        if !self.staged_files.is_empty() || !self.staged_removals.is_empty() {
+            dprintln!("[ERROR] Commit or unstage the staged files before applying a patch");
+            return Err(());
+        }
+
+        // deletions go first, contents before their directories, so type changes and moves find their place free
+        let mut deleted: Vec<RepoPath> = vec![];
+        let mut deletions: Vec<&PatchSection> = patch.sections.iter().filter(|section| section.action == PatchAction::Delete).collect();
+        deletions.sort_by(|left, right| right.path.cmp(&left.path));
+        for section in deletions.into_iter() {
+            if self.apply_section(section).is_none() {
+                dprintln!("[ERROR] {} does not match the patch", section.path);
+                return Err(());
+            }
+            self.remove_working_entry(&section.path)?;
+            deleted.push(section.path.clone());
+        }
+
+        let mut changed: Vec<RepoPath> = vec![];
+        for section in patch.sections.iter().filter(|section| section.action != PatchAction::Delete) {
+            let applied = match self.apply_section(section) {
+                Some(Some(applied)) => applied,
+                _ => {
+                    dprintln!("[ERROR] {} does not match the patch", section.path);
+                    return Err(());
+                }
+            };
+            let working_path = self.root_dir().join(section.path.to_path_buf());
+            if let Some(parent) = working_path.parent() {
+                if let Err(e) = std::fs::create_dir_all(parent) {
+                    dprintln!("[ERROR] Cannot create directory {}: {}", parent.display(), e);
+                    return Err(());
+                }
+            }
+            let result = match applied {
+                AppliedEntry::Directory => std::fs::create_dir_all(&working_path),
+                AppliedEntry::File(contents) => std::fs::write(&working_path, contents),
+                AppliedEntry::Symlink(target) => remove_entry(&working_path)
+                    .and_then(|_| create_symlink(&target.to_path_buf(), &working_path))
+            };
+            if let Err(e) = result {
+                dprintln!("[ERROR] Cannot write {}: {}", working_path.display(), e);
+                return Err(());
+            }
+            changed.push(section.path.clone());
+        }
+
+        // entries that changed their type are only recorded as modified
+        deleted.retain(|path| !changed.contains(path));
+        deleted.sort();
+        self.set_staged_files(changed);
+        self.staged_removals = deleted;
+        self.staged_renames = patch.renamed_files.clone();
+
+        let mut commit = Commit::new(0, patch.message.clone(), patch.author.clone(), vec![]);
+        commit.authored_datetime = Some(patch.authored_datetime.clone());
+        self.record_commit(commit, &format!("apply-patch {}", name))?;
+        let branch_config = self.get_branch(&self.current_branch)?;
+        Ok(branch_config.current_commit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{decode_base85_line, encode_base85, CommitPatch};
+    use crate::lostcontrol::{RenamedFile, RepoPath};
+    use crate::lostcontrol::fs_operations::create_symlink;
+    use crate::lostcontrol::test_support::{commit_entries, open_repo, TestDir};
+    use crate::lostcontrol::tree::EntryKind;
+
+    fn decode_base85(encoded: &str) -> Option<Vec<u8>> {
+        let mut decoded: Vec<u8> = vec![];
+        for line in encoded.lines() {
+            decoded.extend(decode_base85_line(line)?);
+        }
+        Some(decoded)
+    }
+
+    #[test]
+    fn base85_round_trips_every_length() {
+        for length in 0..=160 {
+            let data: Vec<u8> = (0..length).map(|index| (index * 97 + 255) as u8).collect();
+            let encoded = encode_base85(&data);
+            assert_eq!(decode_base85(&encoded), Some(data), "length {}", length);
+        }
+        let every_byte: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode_base85(&encode_base85(&every_byte)), Some(every_byte));
+    }
+
+    #[test]
+    fn base85_rejects_broken_lines() {
+        assert_eq!(decode_base85_line(""), None);
+        // a single byte needs one group of five digits
+        assert_eq!(decode_base85_line("A0000"), None);
+        assert_eq!(decode_base85_line("A00 00"), None);
+        // larger than four bytes can hold
+        assert_eq!(decode_base85_line("D~~~~~"), None);
+    }
+
+    fn write_entries(root: &Path, files: &[(&str, &[u8])], links: &[(&str, &str)]) {
+        for (name, contents) in files {
+            std::fs::create_dir_all(root.join(name).parent().unwrap()).unwrap();
+            std::fs::write(root.join(name), contents).unwrap();
+        }
+        for (name, target) in links {
+            create_symlink(Path::new(target), &root.join(name)).unwrap();
+        }
+    }
+
+    // Kind and contents of every entry, symlinks by their target
+    fn entry_state(root: &Path, name: &str) -> Option<(EntryKind, Vec<u8>)> {
+        let path = root.join(name);
+        match EntryKind::of(&path)? {
+            EntryKind::File => Some((EntryKind::File, std::fs::read(&path).unwrap())),
+            EntryKind::Symlink => Some((EntryKind::Symlink, std::fs::read_link(&path).unwrap().as_os_str().as_encoded_bytes().to_vec())),
+            EntryKind::Directory => Some((EntryKind::Directory, vec![]))
+        }
+    }
+
+    #[test]
+    fn patch_round_trips_binary_files_symlinks_type_changes_and_moves() {
+        let binary: Vec<u8> = (0..=255).collect();
+        let changed_binary: Vec<u8> = (0..=255).rev().chain(0..7).collect();
+        let names = ["text", "unterminated", "binary", "link", "changes", "gone", "dir", "dir/new", "a -> b", "c -> d"];
+        let before_files: [(&str, &[u8]); 6] = [
+            ("text", b"a\nb\nc"),
+            ("unterminated", b"x\n"),
+            ("binary", &binary),
+            ("changes", b"a file\n"),
+            ("gone", b"removed\n"),
+            ("a -> b", b"moved\n"),
+        ];
+        let before_links = [("link", "text")];
+
+        let source_dir = TestDir::new("patch-source");
+        let mut source = open_repo("source", &source_dir.path);
+        write_entries(&source_dir.path, &before_files, &before_links);
+        commit_entries(&mut source, &source_dir.path, &names, "before");
+
+        std::fs::write(source_dir.path.join("text"), b"a\nB\nc").unwrap();
+        std::fs::write(source_dir.path.join("unterminated"), b"x\ny").unwrap();
+        std::fs::write(source_dir.path.join("binary"), &changed_binary).unwrap();
+        std::fs::remove_file(source_dir.path.join("link")).unwrap();
+        std::fs::remove_file(source_dir.path.join("changes")).unwrap();
+        std::fs::remove_file(source_dir.path.join("gone")).unwrap();
+        write_entries(&source_dir.path, &[("dir/new", b"new\n")], &[("link", "binary"), ("changes", "dir/new")]);
+        source.stage_rename(&source_dir.path.join("a -> b"), &source_dir.path.join("c -> d")).unwrap();
+        commit_entries(&mut source, &source_dir.path, &names, "after\n\nwith a body");
+        let text = source.format_patch(2).unwrap();
+        assert!(text.contains(&format!("binary {}\n", changed_binary.len())));
+        assert!(text.contains("symlink binary\n"));
+        assert!(text.contains("symlink dir/new\n"));
+        let patch = CommitPatch::parse(&text).unwrap();
+        assert_eq!(patch.message, "after\n\nwith a body");
+        let moved = RenamedFile {
+            from: RepoPath::from_path(Path::new("a -> b")),
+            to: RepoPath::from_path(Path::new("c -> d")),
+        };
+        assert_eq!(patch.renamed_files, vec![moved.clone()]);
+        // the old and the new path of a move come in pairs
+        assert!(CommitPatch::parse(&text.replace("Renamed-From: ", "Moved-From: ")).is_none());
+
+        let target_dir = TestDir::new("patch-target");
+        let mut target = open_repo("target", &target_dir.path);
+        write_entries(&target_dir.path, &before_files, &before_links);
+        commit_entries(&mut target, &target_dir.path, &names, "before");
+        target.apply_patch(&patch, "test").unwrap();
+
+        for name in names.iter() {
+            assert_eq!(entry_state(&target_dir.path, name), entry_state(&source_dir.path, name), "{}", name);
+        }
+        let branch_config = target.get_branch(&target.current_branch).unwrap();
+        assert_eq!(branch_config.get_commits().last().unwrap().renamed_files, vec![moved]);
+    }
+}
@@ -0,0 +1,491 @@
+use std::path::{Path, PathBuf};
+use std::io::{BufReader, BufRead, Write};
+use serde::{self, Deserialize, Serialize};
+use serde_yaml::{self};
+
+use crate::lostcontrol::{BranchConfig, Commit, CommitSource, Repo, RepoPath, CURRENT_CONFIG_VERSION, dprintln};
+use crate::lostcontrol::tree::compare;
+
+pub(crate) const REBASE_DIR: &str = "rebase";
+const REBASE_CONFIG_FILE: &str = "rebase.conf";
+const REBASE_TODO_FILE: &str = "todo";
+// the original commits are moved to this branch while the rebase runs, the leading dot keeps it apart from user branches
+pub(crate) const REBASE_BACKUP_BRANCH: &str = ".rebase-original";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RebaseAction {
+    Pick,
+    Reword,
+    Squash,
+    Drop,
+}
+
+impl RebaseAction {
+    fn parse(word: &str) -> Option<RebaseAction> {
+        match word {
+            "pick" | "p" => Some(RebaseAction::Pick),
+            "reword" | "r" => Some(RebaseAction::Reword),
+            "squash" | "s" => Some(RebaseAction::Squash),
+            "drop" | "d" => Some(RebaseAction::Drop),
+            _ => None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            RebaseAction::Pick => "pick",
+            RebaseAction::Reword => "reword",
+            RebaseAction::Squash => "squash",
+            RebaseAction::Drop => "drop",
+        }
+    }
+}
+
+// One line of the todo list, message is the new message for reword and only informative otherwise
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RebaseStep {
+    pub action: RebaseAction,
+    pub commit_id: usize,
+    pub message: String,
+}
+
+// Progress of a running rebase, the first step is the one being applied
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RebaseState {
+    #[serde(skip)]
+    closed: bool,
+    #[serde(skip)]
+    modified: bool,
+    #[serde(skip)]
+    config_path: PathBuf,
+    pub branch: String,
+    pub onto: String,
+    pub steps: Vec<RebaseStep>,
+    // commits replayed so far, a squash needs one to fold into
+    pub replayed: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub conflicts: Vec<RepoPath>,
+}
+
+impl RebaseState {
+    fn new(config_path: &Path, branch: String, onto: String, steps: Vec<RebaseStep>) -> RebaseState {
+        RebaseState {
+            closed: false,
+            modified: true,
+            config_path: config_path.to_path_buf(),
+            branch,
+            onto,
+            steps,
+            replayed: 0,
+            conflicts: vec![],
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<RebaseState, ()> {
+        let mut rebase_config = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                dprintln!("[ERROR] Cannot load Rebase State {}: {}", path.display(), e);
+                return Err(());
+            }
+        };
+        let mut version = String::new();
+        let mut contents = String::new();
+
+        let buf_reader = BufReader::new(&mut rebase_config);
+        for (index, line) in buf_reader.lines().enumerate() {
+            match index {
+                0 => version = line.unwrap(),
+                _ => {
+                    let line_string: String = line.unwrap() + "\n";
+                    contents.push_str(line_string.as_str());
+                }
+            }
+        }
+
+        if version != CURRENT_CONFIG_VERSION {
+            dprintln!("[ERROR] Rebase State {}: Rebase State version {} is not supported", path.display(), version);
+            return Err(())
+        }
+
+        let mut state: RebaseState = match serde_yaml::from_str(&contents) {
+            Ok(state) => state,
+            Err(_) => {
+                dprintln!("[ERROR] Rebase State {}: Cannot parse Rebase State", path.display());
+                return Err(());
+            }
+        };
+        state.config_path = path.to_path_buf();
+        Ok(state)
+    }
+
+    // Drops the first step once it is done
+    fn finish_step(&mut self, replayed: bool) {
+        self.steps.remove(0);
+        self.conflicts.clear();
+        if replayed {
+            self.replayed += 1;
+        }
+        self.modified = true;
+    }
+
+    fn set_conflicts(&mut self, conflicts: Vec<RepoPath>) {
+        self.conflicts = conflicts;
+        self.modified = true;
+    }
+
+    pub fn close(&mut self) {
+        if self.closed || !self.modified {
+            return;
+        }
+        let config_str = serde_yaml::to_string(&self).unwrap();
+        let mut rebase_config = match std::fs::File::create(&self.config_path) {
+            Ok(file) => file,
+            Err(e) => panic!("Cannot create Rebase State {}: {}", self.config_path.display(), e)
+        };
+
+        writeln!(rebase_config, "{}", CURRENT_CONFIG_VERSION).unwrap();
+        write!(rebase_config, "{}", config_str).unwrap();
+
+        self.closed = true;
+    }
+}
+
+impl Drop for RebaseState {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+// Turns an edited todo list back into steps, lines can be reordered and removing one drops its commit
+pub fn parse_todo(contents: &str) -> Result<Vec<RebaseStep>, String> {
+    let mut steps: Vec<RebaseStep> = vec![];
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(3, char::is_whitespace);
+        let action = match parts.next().and_then(RebaseAction::parse) {
+            Some(action) => action,
+            None => return Err(format!("line {}: unknown command", index + 1))
+        };
+        let commit_id = match parts.next().map(|id| id.parse::<usize>()) {
+            Some(Ok(commit_id)) => commit_id,
+            _ => return Err(format!("line {}: missing or invalid commit id", index + 1))
+        };
+        let message = parts.next().unwrap_or("").trim().to_string();
+        if action == RebaseAction::Reword && message.is_empty() {
+            return Err(format!("line {}: reword needs the new message after the commit id", index + 1));
+        }
+        if steps.iter().any(|step| step.commit_id == commit_id) {
+            return Err(format!("line {}: commit {} is listed twice", index + 1, commit_id));
+        }
+        steps.push(RebaseStep {
+            action,
+            commit_id,
+            message,
+        });
+    }
+    Ok(steps)
+}
+
+// Commits both branches still share, they were copied when one branch was created from the other
+fn shared_commits(branch: &BranchConfig, onto: &BranchConfig) -> usize {
+    branch.get_commits().iter()
+        .zip(onto.get_commits().iter())
+        .take_while(|(own, other)| {
+            // amending keeps the timestamp, so the contents have to match as well
+            own.id == other.id
+                && own.creation_datetime == other.creation_datetime
+                && own.message == other.message
+                && own.modified_files == other.modified_files
+        })
+        .count()
+}
+
+impl Repo {
+    fn rebase_dir(&self) -> PathBuf {
+        self.repos_dir().join(REBASE_DIR)
+    }
+
+    fn rebase_state_path(&self) -> PathBuf {
+        self.rebase_dir().join(REBASE_CONFIG_FILE)
+    }
+
+    pub fn rebase_in_progress(&self) -> bool {
+        self.rebase_state_path().exists()
+    }
+
+    pub fn get_rebase_state(&self) -> Result<RebaseState, ()> {
+        RebaseState::from_file(&self.rebase_state_path())
+    }
+
+    // The commits of the current branch that onto doesn't have, as a list of picks
+    pub fn rebase_steps(&self, onto: &String) -> Result<Vec<RebaseStep>, ()> {
+        if !self.branches.contains(onto) || *onto == self.current_branch {
+            dprintln!("[ERROR] Cannot rebase {} onto {}", self.current_branch, onto);
+            return Err(());
+        }
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let onto_config = self.get_branch(onto)?;
+        let shared = shared_commits(&branch_config, &onto_config);
+
+        let mut steps: Vec<RebaseStep> = vec![];
+        for commit in branch_config.get_commits().into_iter().skip(shared) {
+            steps.push(RebaseStep {
+                action: RebaseAction::Pick,
+                commit_id: commit.id,
+                message: commit.message,
+            });
+            if commit.id == branch_config.current_commit {
+                break;
+            }
+        }
+        Ok(steps)
+    }
+
+    // Writes the todo list for the steps and returns its path, so it can be edited
+    pub fn write_rebase_todo(&self, onto: &String, steps: &[RebaseStep]) -> Result<PathBuf, ()> {
+        let mut contents = format!("# Rebase {} onto {}\n", self.current_branch, onto);
+        contents.push_str("# Commands: pick <id>, reword <id> <new message>, squash <id>, drop <id>\n");
+        contents.push_str("# Lines are replayed from top to bottom, removing a line drops the commit\n");
+        for step in steps.iter() {
+            contents.push_str(&format!("{} {} {}\n", step.action.name(), step.commit_id, step.message.replace('\n', " ")));
+        }
+        let todo_path = self.rebase_dir().join(REBASE_TODO_FILE);
+        let written = std::fs::create_dir_all(self.rebase_dir()).and_then(|_| std::fs::write(&todo_path, contents));
+        if let Err(e) = written {
+            dprintln!("[ERROR] Cannot write rebase todo list {}: {}", todo_path.display(), e);
+            return Err(());
+        }
+        Ok(todo_path)
+    }
+
+    // Replays the steps on top of the current commit of onto, the current branch then continues from there.
+    // Returns the conflicting entries if a step stopped, the rebase waits for rebase_continue then.
+    // Callers are expected to check switch_blockers for onto first
+    pub fn rebase(&mut self, onto: &String, steps: Vec<RebaseStep>) -> Result<Vec<RepoPath>, ()> {
+        if let Some(operation) = self.pending_operation() {
+            dprintln!("[ERROR] A {} is already in progress", operation);
+            return Err(());
+        }
+        if !self.staged_files.is_empty() || !self.staged_removals.is_empty() {
+            dprintln!("[ERROR] Commit or unstage the staged files before rebasing");
+            return Err(());
+        }
+        let own_steps = self.rebase_steps(onto)?;
+        if let Some(step) = steps.iter().find(|step| !own_steps.iter().any(|own| own.commit_id == step.commit_id)) {
+            dprintln!("[ERROR] Commit {} is not one of the commits to rebase", step.commit_id);
+            return Err(());
+        }
+
+        let branch = self.current_branch.clone();
+        let branch_config = self.get_branch(&branch)?;
+        let onto_config = self.get_branch(onto)?;
+        let current_tree = self.get_tree(&branch_config, branch_config.current_commit)?;
+        let onto_tree = self.get_tree(&onto_config, onto_config.current_commit)?;
+        drop(branch_config);
+
+        // the original commits stay around as the backup branch until the rebase is finished or aborted
+        let backup_branch = REBASE_BACKUP_BRANCH.to_string();
+        if self.repos_dir().join(REBASE_BACKUP_BRANCH).exists() {
+            dprintln!("[ERROR] A backup of an earlier rebase is still in the way");
+            return Err(());
+        }
+        if let Err(e) = std::fs::create_dir_all(self.rebase_dir()) {
+            dprintln!("[ERROR] Cannot create rebase directory {}: {}", self.rebase_dir().display(), e);
+            return Err(());
+        }
+        let original_commit = self.get_branch(&branch)?.current_commit;
+        self.move_branch(&branch, &backup_branch)?;
+        self.copy_branch(&onto_config, &branch)?;
+        self.checkout_tree(&current_tree, &onto_tree)?;
+        let branch_config = self.get_branch(&branch)?;
+        self.log_head_change(&branch_config, original_commit, &format!("rebase onto {}", onto));
+        drop(branch_config);
+
+        let mut state = RebaseState::new(&self.rebase_state_path(), branch, onto.clone(), steps);
+        state.close();
+        self.replay_steps()
+    }
+
+    // Applies the remaining steps one after the other, until one runs into conflicts
+    fn replay_steps(&mut self) -> Result<Vec<RepoPath>, ()> {
+        let backup_branch = REBASE_BACKUP_BRANCH.to_string();
+        loop {
+            let mut state = self.get_rebase_state()?;
+            let step = match state.steps.first() {
+                Some(step) => step.clone(),
+                None => break
+            };
+            if step.action == RebaseAction::Drop {
+                dprintln!("[INFO] Dropping commit {}", step.commit_id);
+                state.finish_step(false);
+                continue;
+            }
+
+            let source = CommitSource {
+                branch: backup_branch.clone(),
+                commit_id: step.commit_id,
+            };
+            let (_, parent_tree, commit_tree) = self.pick_trees(&source)?;
+            let theirs_label = format!("commit {}", step.commit_id);
+            let outcome = self.apply_changes(&parent_tree, &commit_tree, "onto", &theirs_label)?;
+            self.set_staged_files(outcome.changed_files);
+            self.staged_removals = outcome.removed_files;
+            if !outcome.conflicts.is_empty() {
+                state.set_conflicts(outcome.conflicts.clone());
+                return Ok(outcome.conflicts);
+            }
+            let replayed = self.commit_step(&step, state.replayed)?;
+            state.finish_step(replayed);
+        }
+        self.finish_rebase()?;
+        Ok(vec![])
+    }
+
+    // Commits the staged result of a step, returns false if there was nothing left to commit
+    fn commit_step(&mut self, step: &RebaseStep, replayed: usize) -> Result<bool, ()> {
+        if self.staged_files.is_empty() && self.staged_removals.is_empty() {
+            dprintln!("[INFO] Commit {} is already applied, skipping it", step.commit_id);
+            return Ok(false);
+        }
+        let backup_config = self.get_branch(&REBASE_BACKUP_BRANCH.to_string())?;
+        let original = match backup_config.get_commit(step.commit_id) {
+            Some(commit) => commit.clone(),
+            None => return Err(())
+        };
+        drop(backup_config);
+
+        // a squash at the very start has nothing of its own to fold into and is picked instead
+        if step.action == RebaseAction::Squash && replayed > 0 {
+            let branch_config = self.get_branch(&self.current_branch)?;
+            let previous_message = branch_config.get_commit(branch_config.current_commit).unwrap().message.clone();
+            drop(branch_config);
+            self.amend_commit(Some(format!("{}\n{}", previous_message, original.message)))?;
+            return Ok(false);
+        }
+        let message = match step.action {
+            RebaseAction::Reword => step.message.clone(),
+            _ => original.message.clone()
+        };
+        let mut commit = Commit::new(0, message, original.author.clone(), vec![]);
+        commit.picked_from = original.picked_from.clone();
+        commit.reverted_commit = original.reverted_commit;
+        self.record_commit(commit, &format!("rebase ({})", step.action.name()))?;
+        Ok(true)
+    }
+
+    // Keeps the original commits as detached commits of the rebased branch, so the reflog can go back to them,
+    // then drops the backup once every step is done
+    fn finish_rebase(&mut self) -> Result<(), ()> {
+        let backup_config = self.get_branch(&REBASE_BACKUP_BRANCH.to_string())?;
+        let mut branch_config = self.get_branch(&self.current_branch)?;
+        let stored: Vec<Commit> = branch_config.get_commits().into_iter().chain(branch_config.get_detached_commits()).collect();
+        for mut commit in backup_config.get_commits().into_iter().chain(backup_config.get_detached_commits()) {
+            // commits that came with onto are stored already
+            if stored.iter().any(|existing| existing.creation_datetime == commit.creation_datetime) {
+                continue;
+            }
+            let source_path = self.commit_dir(&backup_config, commit.id);
+            commit.id = branch_config.next_commit_id();
+            let target_path = self.commit_dir(&branch_config, commit.id);
+            if let Err(e) = std::fs::rename(&source_path, &target_path) {
+                dprintln!("[ERROR] Cannot keep original commit {}: {}", source_path.display(), e);
+                continue;
+            }
+            branch_config.push_detached(commit);
+        }
+        branch_config.close();
+        drop(backup_config);
+
+        let backup_dir = self.repos_dir().join(REBASE_BACKUP_BRANCH);
+        if let Err(e) = std::fs::remove_dir_all(&backup_dir) {
+            dprintln!("[ERROR] Cannot remove rebase backup {}: {}", backup_dir.display(), e);
+        }
+        if let Err(e) = std::fs::remove_dir_all(self.rebase_dir()) {
+            dprintln!("[ERROR] Cannot remove rebase directory {}: {}", self.rebase_dir().display(), e);
+            return Err(());
+        }
+        self.prune_chunks();
+        Ok(())
+    }
+
+    // Conflicting entries of the current step that were not staged yet or still contain conflict markers
+    pub fn rebase_unresolved(&self) -> Vec<RepoPath> {
+        match self.get_rebase_state() {
+            Ok(state) => self.unresolved_conflicts(&state.conflicts),
+            Err(()) => vec![]
+        }
+    }
+
+    // Commits the resolved step and carries on with the remaining ones, callers are expected to check rebase_unresolved first
+    pub fn rebase_continue(&mut self) -> Result<Vec<RepoPath>, ()> {
+        let mut state = self.get_rebase_state()?;
+        let step = match state.steps.first() {
+            Some(step) => step.clone(),
+            None => {
+                drop(state);
+                self.finish_rebase()?;
+                return Ok(vec![]);
+            }
+        };
+        self.stage_resolved_removals(&state.conflicts);
+        let replayed = self.commit_step(&step, state.replayed)?;
+        state.finish_step(replayed);
+        drop(state);
+        self.replay_steps()
+    }
+
+    // Throws away the replayed commits and puts the branch and the working tree back to where they were
+    pub fn rebase_abort(&mut self) -> Result<(), ()> {
+        let state = self.get_rebase_state()?;
+        let backup_branch = REBASE_BACKUP_BRANCH.to_string();
+        let branch_config = self.get_branch(&state.branch)?;
+        let backup_config = self.get_branch(&backup_branch)?;
+        let head_commit = branch_config.current_commit;
+        let head_tree = self.get_tree(&branch_config, head_commit)?;
+        let original_tree = self.get_tree(&backup_config, backup_config.current_commit)?;
+        drop(branch_config);
+        drop(backup_config);
+
+        // entries of the step that stopped may not be committed anywhere yet
+        let mut touched: Vec<RepoPath> = match state.steps.first() {
+            Some(step) => {
+                let source = CommitSource {
+                    branch: backup_branch.clone(),
+                    commit_id: step.commit_id,
+                };
+                match self.pick_trees(&source) {
+                    Ok((_, parent_tree, commit_tree)) => compare(&parent_tree, &commit_tree).into_iter().map(|change| change.path).collect(),
+                    Err(()) => vec![]
+                }
+            },
+            None => vec![]
+        };
+        touched.extend(self.staged_files.iter().cloned());
+        touched.extend(self.staged_removals.iter().cloned());
+        self.reset_working_entries(&touched, &head_tree)?;
+        self.checkout_tree(&head_tree, &original_tree)?;
+
+        let branch_dir = self.repos_dir().join(&state.branch);
+        if let Err(e) = std::fs::remove_dir_all(&branch_dir) {
+            dprintln!("[ERROR] Cannot remove rebased branch {}: {}", branch_dir.display(), e);
+            return Err(());
+        }
+        self.move_branch(&backup_branch, &state.branch)?;
+        let branch_config = self.get_branch(&state.branch)?;
+        self.log_head_change(&branch_config, head_commit, "rebase abort");
+        drop(branch_config);
+        drop(state);
+        if let Err(e) = std::fs::remove_dir_all(self.rebase_dir()) {
+            dprintln!("[ERROR] Cannot remove rebase directory {}: {}", self.rebase_dir().display(), e);
+            return Err(());
+        }
+        self.unstage_all();
+        self.prune_chunks();
+        Ok(())
+    }
+}
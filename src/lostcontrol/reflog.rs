@@ -0,0 +1,199 @@
+use std::path::PathBuf;
+use std::io::Write;
+use serde::{self, Deserialize, Serialize};
+
+use crate::lostcontrol::{BranchConfig, Commit, Repo, RepoPath, CURRENT_CONFIG_VERSION, dprintln};
+
+pub(crate) const REFLOG_DIR: &str = "reflog";
+const TIME_FORMAT_STRING: &str = "%Y-%m-%d %H:%M:%S";
+
+// A commit of the history an entry recorded. Ids of kept commits can change when a rebase
+// stores the original ones again, the creation time still tells them apart
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReflogCommit {
+    pub id: usize,
+    pub creation_datetime: String,
+}
+
+// One movement of a branch head, together with the history the branch had afterwards
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReflogEntry {
+    pub old_commit: usize,
+    pub new_commit: usize,
+    pub operation: String,
+    pub timestamp: String,
+    pub user: String,
+    pub commits: Vec<ReflogCommit>,
+}
+
+impl ReflogEntry {
+    pub fn get_time_formatted(&self) -> String {
+        let timestamp: chrono::DateTime<chrono::Local> =
+            chrono::DateTime::from(chrono::DateTime::parse_from_rfc3339(&self.timestamp).unwrap());
+        timestamp.format(TIME_FORMAT_STRING).to_string()
+    }
+}
+
+impl std::fmt::Display for ReflogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {} {}: {} -> {}", self.get_time_formatted(), self.user, self.operation, self.old_commit, self.new_commit)
+    }
+}
+
+impl Repo {
+    fn reflog_path(&self, branch: &str) -> PathBuf {
+        self.repos_dir().join(REFLOG_DIR).join(branch)
+    }
+
+    // Appends an entry for a change of the head of branch_config, which has to hold the new state already.
+    // A reflog that can't be written doesn't stop the operation that moved the head
+    pub(crate) fn log_head_change(&self, branch_config: &BranchConfig, old_commit: usize, operation: &str) {
+        let entry = ReflogEntry {
+            old_commit,
+            new_commit: branch_config.current_commit,
+            operation: operation.to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            user: self.author(),
+            commits: branch_config.get_commits().iter()
+                .map(|commit| ReflogCommit {
+                    id: commit.id,
+                    creation_datetime: commit.creation_datetime.clone(),
+                })
+                .collect(),
+        };
+        // a one element list, so the entries of the file read back as a single list
+        let entry_str = serde_yaml::to_string(&vec![entry]).unwrap();
+        let entry_str = entry_str.strip_prefix("---\n").unwrap_or(&entry_str);
+
+        let reflog_path = self.reflog_path(&branch_config.name);
+        let written = std::fs::create_dir_all(reflog_path.parent().unwrap())
+            .and_then(|_| std::fs::OpenOptions::new().create(true).append(true).open(&reflog_path))
+            .and_then(|mut reflog| {
+                if reflog.metadata()?.len() == 0 {
+                    writeln!(reflog, "{}", CURRENT_CONFIG_VERSION)?;
+                }
+                write!(reflog, "{}", entry_str)?;
+                if !entry_str.ends_with('\n') {
+                    writeln!(reflog)?;
+                }
+                Ok(())
+            });
+        if let Err(e) = written {
+            dprintln!("[ERROR] Cannot write reflog {}: {}", reflog_path.display(), e);
+        }
+    }
+
+    pub(crate) fn remove_reflog(&self, branch: &str) {
+        let reflog_path = self.reflog_path(branch);
+        if reflog_path.exists() {
+            if let Err(e) = std::fs::remove_file(&reflog_path) {
+                dprintln!("[ERROR] Cannot remove reflog {}: {}", reflog_path.display(), e);
+            }
+        }
+    }
+
+    // Every recorded head change of the branch, the oldest first
+    pub fn get_reflog(&self, branch: &String) -> Result<Vec<ReflogEntry>, ()> {
+        if !self.branches.contains(branch) {
+            dprintln!("[ERROR] Branch {} does not exist", branch);
+            return Err(());
+        }
+        let reflog_path = self.reflog_path(branch);
+        if !reflog_path.exists() {
+            return Ok(vec![]);
+        }
+        let contents = match std::fs::read_to_string(&reflog_path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                dprintln!("[ERROR] Cannot load reflog {}: {}", reflog_path.display(), e);
+                return Err(());
+            }
+        };
+        let (version, entries) = contents.split_once('\n').unwrap_or((&contents, ""));
+        if version != CURRENT_CONFIG_VERSION {
+            dprintln!("[ERROR] Reflog {}: Reflog version {} is not supported", reflog_path.display(), version);
+            return Err(());
+        }
+        if entries.trim().is_empty() {
+            return Ok(vec![]);
+        }
+        match serde_yaml::from_str(entries) {
+            Ok(entries) => Ok(entries),
+            Err(_) => {
+                dprintln!("[ERROR] Reflog {}: Cannot parse reflog", reflog_path.display());
+                Err(())
+            }
+        }
+    }
+
+    // The commits the entry recorded, looked up among everything the branch still stores
+    fn reflog_history(&self, branch_config: &BranchConfig, entry: &ReflogEntry) -> Result<Vec<Commit>, ()> {
+        let stored: Vec<Commit> = branch_config.get_commits().into_iter()
+            .chain(branch_config.get_detached_commits())
+            .collect();
+        let mut history: Vec<Commit> = vec![];
+        for recorded in entry.commits.iter() {
+            let found = stored.iter()
+                .find(|commit| commit.id == recorded.id && commit.creation_datetime == recorded.creation_datetime)
+                .or_else(|| stored.iter().find(|commit| commit.creation_datetime == recorded.creation_datetime));
+            match found {
+                Some(commit) => history.push(commit.clone()),
+                None => {
+                    dprintln!("[ERROR] Commit {} of this entry is no longer stored on branch {}", recorded.id, branch_config.name);
+                    return Err(());
+                }
+            }
+        }
+        Ok(history)
+    }
+
+    // Entries with uncommitted changes that restoring the current branch to the entry would throw away
+    pub fn reflog_restore_blockers(&self, branch: &String, entry: &ReflogEntry) -> Result<Vec<RepoPath>, ()> {
+        if *branch != self.current_branch {
+            return Ok(vec![]);
+        }
+        let mut target_config = self.get_branch(branch)?;
+        let current_tree = self.get_tree(&target_config, target_config.current_commit)?;
+        let history = self.reflog_history(&target_config, entry)?;
+        target_config.set_history(history);
+        let target_tree = self.get_tree(&target_config, target_config.current_commit);
+        target_config.discard();
+        Ok(self.checkout_blockers(&current_tree, &target_tree?))
+    }
+
+    // Gives the branch the history it had after the entry, the commits that drop out stay stored.
+    // The working tree follows if it is the current branch, callers are expected to check reflog_restore_blockers first
+    pub fn reflog_restore(&mut self, branch: &String, entry: &ReflogEntry, description: &str) -> Result<(), ()> {
+        if let Some(operation) = self.pending_operation() {
+            dprintln!("[ERROR] A {} is in progress", operation);
+            return Err(());
+        }
+        let is_current = *branch == self.current_branch;
+        if is_current && (!self.staged_files.is_empty() || !self.staged_removals.is_empty()) {
+            dprintln!("[ERROR] Commit or unstage the staged files before restoring the branch");
+            return Err(());
+        }
+
+        let mut branch_config = self.get_branch(branch)?;
+        let history = self.reflog_history(&branch_config, entry)?;
+        let old_commit = branch_config.current_commit;
+        let current_tree = self.get_tree(&branch_config, old_commit)?;
+        branch_config.set_history(history);
+        let target_tree = match self.get_tree(&branch_config, branch_config.current_commit) {
+            Ok(tree) => tree,
+            Err(()) => {
+                branch_config.discard();
+                return Err(());
+            }
+        };
+        if is_current {
+            if let Err(()) = self.checkout_tree(&current_tree, &target_tree) {
+                branch_config.discard();
+                return Err(());
+            }
+        }
+        branch_config.close();
+        self.log_head_change(&branch_config, old_commit, &format!("reflog restore to {}", description));
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+use std::cell::OnceCell;
+use std::path::Path;
+use similar::TextDiff;
+
+use crate::lostcontrol::{Repo, RepoPath, dprintln};
+use crate::lostcontrol::chunks::Manifest;
+use crate::lostcontrol::diff::is_binary;
+use crate::lostcontrol::tree::{compare, ChangeKind, EntryKind, Tree, TreeChange, TreeEntry};
+
+// minimum similarity in percent for an added file to count as a moved or copied one
+pub const DEFAULT_RENAME_THRESHOLD: u8 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenameKind {
+    Renamed,
+    Copied,
+}
+
+// An added file that is a moved or copied version of another one
+#[derive(Debug, Clone)]
+pub struct DetectedRename {
+    pub kind: RenameKind,
+    pub from: RepoPath,
+    pub to: RepoPath,
+    // in percent, 100 only for identical contents
+    pub similarity: u8,
+    pub old: TreeEntry,
+    pub new: TreeEntry,
+}
+
+impl std::fmt::Display for DetectedRename {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let label = match self.kind {
+            RenameKind::Renamed => "renamed",
+            RenameKind::Copied => "copied",
+        };
+        write!(f, "{}: {} -> {} ({}%)", label, self.from, self.to, self.similarity)
+    }
+}
+
+// How much of the lines two versions share, binary contents only match if they are identical
+pub fn similarity(old: &[u8], new: &[u8]) -> u8 {
+    if old == new {
+        return 100;
+    }
+    if is_binary(old) || is_binary(new) {
+        return 0;
+    }
+    let old_text = String::from_utf8_lossy(old);
+    let new_text = String::from_utf8_lossy(new);
+    let ratio = TextDiff::from_lines(old_text.as_ref(), new_text.as_ref()).ratio();
+    // rounding up to 100 would claim the versions are identical
+    ((ratio * 100.0) as u8).min(99)
+}
+
+// Above this many added files times possible origins, only identical contents are paired, like git's diff.renameLimit
+const RENAME_LIMIT: usize = 1000;
+
+// A file or symlink that may be the origin or the target of a rename
+struct Candidate<'a> {
+    path: &'a RepoPath,
+    entry: &'a TreeEntry,
+    size: u64,
+    // read on first use, None for chunked files, which are only compared by their manifest
+    contents: OnceCell<Option<Vec<u8>>>,
+}
+
+impl Candidate<'_> {
+    fn contents(&self) -> Option<&Vec<u8>> {
+        self.contents.get_or_init(|| match self.entry.chunks {
+            Some(_) => None,
+            None => self.entry.read().ok()
+        }).as_ref()
+    }
+}
+
+// Files and symlinks take part, directories are matched through their contents.
+// Empty files are left out, they would match every other empty file
+fn candidate<'a>(path: &'a RepoPath, entry: &'a TreeEntry) -> Option<Candidate<'a>> {
+    let size = match (entry.kind, &entry.chunks) {
+        (EntryKind::Directory, _) => return None,
+        (_, Some(_)) => Manifest::from_file(&entry.source).ok()?.size,
+        // the length of a symlink is the length of its target
+        (_, None) => std::fs::symlink_metadata(&entry.source).ok()?.len()
+    };
+    if size == 0 {
+        return None;
+    }
+    Some(Candidate {
+        path,
+        entry,
+        size,
+        contents: OnceCell::new(),
+    })
+}
+
+// The similarity of two candidates if it reaches threshold. Files whose sizes are too far apart to reach it are skipped
+// without reading them. Chunked and binary files, and every file with exact_only, only match if they are identical
+fn score(old: &Candidate, new: &Candidate, threshold: u8, exact_only: bool) -> Option<u8> {
+    if old.entry.kind != new.entry.kind {
+        return None;
+    }
+    if old.size != new.size {
+        let (smaller, larger) = (old.size.min(new.size), old.size.max(new.size));
+        if exact_only || smaller * 100 < threshold as u64 * larger {
+            return None;
+        }
+    }
+    if exact_only || old.entry.chunks.is_some() || new.entry.chunks.is_some() {
+        return match old.size == new.size && old.entry.same_content(new.entry) {
+            true => Some(100),
+            false => None
+        };
+    }
+    let score = similarity(old.contents()?, new.contents()?);
+    match score >= threshold {
+        true => Some(score),
+        false => None
+    }
+}
+
+// Pairs added files of changes with removed ones whose contents are at least threshold percent similar.
+// With copy_sources every entry of that tree can also be the origin of an added file, which then counts as copied.
+// Every removed file is the origin of at most one rename, the most similar pairs win
+pub fn detect_renames(changes: &[TreeChange], copy_sources: Option<&Tree>, threshold: u8) -> Vec<DetectedRename> {
+    let mut removed: Vec<Candidate> = vec![];
+    let mut added: Vec<Candidate> = vec![];
+    for change in changes.iter() {
+        match (change.kind, &change.old, &change.new) {
+            (ChangeKind::Removed, Some(entry), _) => removed.extend(candidate(&change.path, entry)),
+            (ChangeKind::Added, _, Some(entry)) => added.extend(candidate(&change.path, entry)),
+            _ => {}
+        }
+    }
+    let copied: Vec<Candidate> = match copy_sources {
+        Some(copy_sources) => copy_sources.iter().filter_map(|(path, entry)| candidate(path, entry)).collect(),
+        None => vec![]
+    };
+    let exact_only = added.len() * (removed.len() + copied.len()) > RENAME_LIMIT * RENAME_LIMIT;
+    if exact_only {
+        dprintln!("[WARN] Too many added and removed files, only identical ones are detected as moved or copied");
+    }
+
+    // (similarity, removed index, added index)
+    let mut pairs: Vec<(u8, usize, usize)> = vec![];
+    for (added_index, new) in added.iter().enumerate() {
+        for (removed_index, old) in removed.iter().enumerate() {
+            if let Some(score) = score(old, new, threshold, exact_only) {
+                pairs.push((score, removed_index, added_index));
+            }
+        }
+    }
+    // most similar first, ties keep the path order
+    pairs.sort_by(|left, right| right.0.cmp(&left.0).then(left.1.cmp(&right.1)).then(left.2.cmp(&right.2)));
+
+    let mut detected: Vec<DetectedRename> = vec![];
+    let mut used_removed: Vec<usize> = vec![];
+    let mut matched_added: Vec<usize> = vec![];
+    for (score, removed_index, added_index) in pairs.into_iter() {
+        if used_removed.contains(&removed_index) || matched_added.contains(&added_index) {
+            continue;
+        }
+        used_removed.push(removed_index);
+        matched_added.push(added_index);
+        let old = &removed[removed_index];
+        let new = &added[added_index];
+        detected.push(DetectedRename {
+            kind: RenameKind::Renamed,
+            from: old.path.clone(),
+            to: new.path.clone(),
+            similarity: score,
+            old: old.entry.clone(),
+            new: new.entry.clone(),
+        });
+    }
+
+    for (added_index, new) in added.iter().enumerate() {
+        if matched_added.contains(&added_index) {
+            continue;
+        }
+        let mut best: Option<(u8, &Candidate)> = None;
+        for old in copied.iter() {
+            if old.path == new.path {
+                continue;
+            }
+            if let Some(score) = score(old, new, threshold, exact_only) {
+                if best.is_none_or(|(best_score, _)| score > best_score) {
+                    best = Some((score, old));
+                }
+            }
+        }
+        if let Some((score, old)) = best {
+            detected.push(DetectedRename {
+                kind: RenameKind::Copied,
+                from: old.path.clone(),
+                to: new.path.clone(),
+                similarity: score,
+                old: old.entry.clone(),
+                new: new.entry.clone(),
+            });
+        }
+    }
+    detected.sort_by(|left, right| left.to.cmp(&right.to));
+    detected
+}
+
+// A commit that changed a path, as part of its history
+pub struct PathHistoryEntry {
+    pub commit_id: usize,
+    // set if the commit moved or copied the path here from another one
+    pub renamed: Option<DetectedRename>,
+}
+
+impl Repo {
+    // Commits of the current branch that changed the path or anything below it, newest first.
+    // With follow, the history goes on under the old name once the commit that created the path moved or copied it
+    pub fn path_history(&self, path: &Path, follow: bool) -> Result<Vec<PathHistoryEntry>, ()> {
+        let (_, mut key) = self.resolve_path(path)?;
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let commits = branch_config.get_commits();
+
+        // the tree after every commit, history is linear so each one builds on the one before
+        let mut trees: Vec<Tree> = vec![];
+        for commit in commits.iter() {
+            let mut tree = trees.last().cloned().unwrap_or_default();
+            self.overlay_stored(&mut tree, &self.commit_dir(&branch_config, commit.id), &commit.chunked_files);
+            for file in commit.removed_files.iter() {
+                tree.remove(file);
+            }
+            trees.push(tree);
+        }
+
+        let empty_tree = Tree::new();
+        let mut history: Vec<PathHistoryEntry> = vec![];
+        for (index, commit) in commits.iter().enumerate().rev() {
+            let parent_tree = match index {
+                0 => &empty_tree,
+                _ => &trees[index - 1]
+            };
+            let changes = compare(parent_tree, &trees[index]);
+            let touched = changes.iter().any(|change| key.is_empty() || change.path == key || change.path.is_inside(&key));
+            if !touched {
+                continue;
+            }
+            let created = changes.iter().any(|change| change.path == key && change.kind == ChangeKind::Added);
+            let mut renamed: Option<DetectedRename> = None;
+            if follow && created {
+                // moves recorded with stage mv count no matter how much the contents changed
+                let recorded = commit.renamed_files.iter().find(|renamed_file| renamed_file.to == key);
+                renamed = match recorded {
+                    Some(renamed_file) => match (parent_tree.get(&renamed_file.from), trees[index].get(&key)) {
+                        (Some(old), Some(new)) => Some(DetectedRename {
+                            kind: RenameKind::Renamed,
+                            from: renamed_file.from.clone(),
+                            to: key.clone(),
+                            similarity: similarity(&old.read().unwrap_or_default(), &new.read().unwrap_or_default()),
+                            old: old.clone(),
+                            new: new.clone(),
+                        }),
+                        _ => None
+                    },
+                    None => detect_renames(&changes, Some(parent_tree), self.rename_threshold).into_iter()
+                        .find(|detected| detected.to == key)
+                };
+            }
+            history.push(PathHistoryEntry {
+                commit_id: commit.id,
+                renamed: renamed.clone(),
+            });
+            if let Some(renamed) = renamed {
+                dprintln!("[INFO] Following {} back to {}", key, renamed.from);
+                key = renamed.from;
+            }
+        }
+        Ok(history)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect_renames, similarity, RenameKind};
+    use crate::lostcontrol::chunks::ChunkStore;
+    use crate::lostcontrol::test_support::{tree_with, TestDir};
+    use crate::lostcontrol::tree::{compare, EntryKind, Tree, TreeEntry};
+
+    const ORIGINAL: &str = "a\nb\nc\nd\n";
+    // three of four lines are kept, 75% similar
+    const EDITED: &str = "a\nb\nc\nX\n";
+
+    // (kind, from, to, similarity) of every detected rename
+    fn renames(old: &Tree, new: &Tree, copy_sources: Option<&Tree>, threshold: u8) -> Vec<(RenameKind, String, String, u8)> {
+        detect_renames(&compare(old, new), copy_sources, threshold).into_iter()
+            .map(|detected| (detected.kind, detected.from.to_string(), detected.to.to_string(), detected.similarity))
+            .collect()
+    }
+
+    fn renamed(from: &str, to: &str, similarity: u8) -> (RenameKind, String, String, u8) {
+        (RenameKind::Renamed, from.to_string(), to.to_string(), similarity)
+    }
+
+    #[test]
+    fn renames_are_detected_from_the_threshold_on() {
+        let test_dir = TestDir::new("rename-threshold");
+        let old = tree_with(&test_dir.path.join("old"), &[("f", ORIGINAL)]);
+        let new = tree_with(&test_dir.path.join("new"), &[("g", EDITED)]);
+        assert_eq!(similarity(ORIGINAL.as_bytes(), EDITED.as_bytes()), 75);
+        assert_eq!(renames(&old, &new, None, 75), vec![renamed("f", "g", 75)]);
+        assert!(renames(&old, &new, None, 76).is_empty());
+    }
+
+    #[test]
+    fn a_removed_file_is_the_origin_of_its_most_similar_added_file_only() {
+        let test_dir = TestDir::new("rename-compete");
+        let old = tree_with(&test_dir.path.join("old"), &[("f", ORIGINAL)]);
+        let new = tree_with(&test_dir.path.join("new"), &[("g", EDITED), ("h", ORIGINAL)]);
+        assert_eq!(renames(&old, &new, None, 50), vec![renamed("f", "h", 100)]);
+
+        // with the old tree as copy sources, the other one counts as copied
+        assert_eq!(renames(&old, &new, Some(&old), 50), vec![
+            (RenameKind::Copied, "f".to_string(), "g".to_string(), 75),
+            renamed("f", "h", 100),
+        ]);
+    }
+
+    #[test]
+    fn copies_come_from_the_most_similar_source() {
+        let test_dir = TestDir::new("rename-copy");
+        let old = tree_with(&test_dir.path.join("old"), &[("close", ORIGINAL), ("far", EDITED), ("kept", "kept\n")]);
+        let mut new = old.clone();
+        for (path, entry) in tree_with(&test_dir.path.join("new"), &[("copy", ORIGINAL)]).iter() {
+            new.insert(path.clone(), entry.clone());
+        }
+        assert!(renames(&old, &new, None, 50).is_empty());
+        assert_eq!(renames(&old, &new, Some(&old), 50), vec![(RenameKind::Copied, "close".to_string(), "copy".to_string(), 100)]);
+    }
+
+    #[test]
+    fn ties_keep_the_path_order() {
+        let test_dir = TestDir::new("rename-ties");
+        let old = tree_with(&test_dir.path.join("old"), &[("a", ORIGINAL), ("b", ORIGINAL)]);
+        let new = tree_with(&test_dir.path.join("new"), &[("x", ORIGINAL), ("y", ORIGINAL), ("z", ORIGINAL)]);
+        assert_eq!(renames(&old, &new, None, 50), vec![renamed("a", "x", 100), renamed("b", "y", 100)]);
+    }
+
+    #[test]
+    fn files_of_too_different_sizes_are_skipped() {
+        let test_dir = TestDir::new("rename-size");
+        let short_lines = "1\n2\n3\n4\n5\n6\n7\n8\n9\n0\n";
+        let with_long_line = format!("{}{}\n", short_lines, "long".repeat(100));
+        let old = tree_with(&test_dir.path.join("old"), &[("f", short_lines)]);
+        let new = tree_with(&test_dir.path.join("new"), &[("g", with_long_line.as_str())]);
+        // the lines are similar enough, the sizes are not
+        assert!(similarity(short_lines.as_bytes(), with_long_line.as_bytes()) >= 90);
+        assert!(renames(&old, &new, None, 50).is_empty());
+    }
+
+    #[test]
+    fn binary_files_only_match_when_identical() {
+        let test_dir = TestDir::new("rename-binary");
+        let binary: Vec<u8> = (0..=255).collect();
+        let mut changed = binary.clone();
+        changed[200] = b'x';
+        let old = tree_with(&test_dir.path.join("old"), &[("f", &binary), ("g", &binary)]);
+        let new = tree_with(&test_dir.path.join("new"), &[("f-moved", &binary), ("g-moved", &changed)]);
+        assert_eq!(renames(&old, &new, None, 50), vec![renamed("f", "f-moved", 100)]);
+    }
+
+    #[test]
+    fn chunked_files_only_match_when_identical() {
+        let test_dir = TestDir::new("rename-chunked");
+        let store = ChunkStore::new(test_dir.path.join("chunks"));
+        let chunked_tree = |name: &str, files: &[(&str, &str)]| -> Tree {
+            let sources = tree_with(&test_dir.path.join(name), files);
+            let mut tree = Tree::new();
+            for (path, entry) in sources.iter() {
+                let manifest_path = entry.source.with_extension("manifest");
+                store.store_file(&entry.source, &manifest_path).unwrap();
+                tree.insert(path.clone(), TreeEntry {
+                    kind: EntryKind::File,
+                    source: manifest_path,
+                    chunks: Some(store.clone()),
+                });
+            }
+            tree
+        };
+        let old = chunked_tree("old", &[("f", ORIGINAL), ("g", ORIGINAL)]);
+        let new = chunked_tree("new", &[("f-moved", ORIGINAL), ("g-moved", EDITED)]);
+        assert_eq!(renames(&old, &new, None, 50), vec![renamed("f", "f-moved", 100)]);
+    }
+
+    #[test]
+    fn above_the_rename_limit_only_identical_files_match() {
+        let test_dir = TestDir::new("rename-limit");
+        // every filler file has a size of its own, so no pair has to be read
+        let removed_fillers: Vec<(String, String)> = (0..1000).map(|index| (format!("removed-{}", index), "r".repeat(100 + index))).collect();
+        let added_fillers: Vec<(String, String)> = (0..1000).map(|index| (format!("added-{}", index), "a".repeat(2000 + index))).collect();
+        let mut old_files: Vec<(&str, &str)> = vec![("f", ORIGINAL), ("g", "g\nh\n")];
+        old_files.extend(removed_fillers.iter().map(|(name, contents)| (name.as_str(), contents.as_str())));
+        let mut new_files: Vec<(&str, &str)> = vec![("f-edited", EDITED), ("g-moved", "g\nh\n")];
+        new_files.extend(added_fillers.iter().map(|(name, contents)| (name.as_str(), contents.as_str())));
+        let old = tree_with(&test_dir.path.join("old"), &old_files);
+        let new = tree_with(&test_dir.path.join("new"), &new_files);
+
+        // f-edited would be a rename below the limit
+        assert_eq!(renames(&old, &new, None, 50), vec![renamed("g", "g-moved", 100)]);
+    }
+}
@@ -1,14 +1,37 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::{BufReader, BufRead, Write};
 use serde::{self, Deserialize, Serialize};
 use serde_yaml::{self};
 
-use crate::lostcontrol::{BranchConfig, Commit, CURRENT_CONFIG_VERSION, dprintln};
+use crate::lostcontrol::{BranchConfig, Commit, RenamedFile, RepoPath, CURRENT_CONFIG_VERSION, dprintln};
 use crate::lostcontrol::fs_operations::*;
+use crate::lostcontrol::chunks::ChunkStore;
+use crate::lostcontrol::revert::PendingRevert;
+use crate::lostcontrol::cherry_pick::PendingCherryPick;
+use crate::lostcontrol::tree::{compare, normalize_key, same_entry, ChangeKind, EntryKind, Tree, TreeChange, TreeEntry};
+use crate::lostcontrol::stash::STASH_DIR;
+use crate::lostcontrol::rebase::{REBASE_BACKUP_BRANCH, REBASE_DIR};
+use crate::lostcontrol::reflog::REFLOG_DIR;
+use crate::lostcontrol::oplog::OPLOG_DIR;
+use crate::lostcontrol::bisect::BISECT_DIR;
+use crate::lostcontrol::restore::BACKUP_DIR;
+use crate::lostcontrol::index::INDEX_DIR;
+use crate::lostcontrol::rename::DEFAULT_RENAME_THRESHOLD;
 
 const DEFAULT_BRANCH: &str = "master";
 const DEFAULT_CONFIG_FILE: &str = ".lostcontrol.conf";
 const DEFAULT_REPOS_DIR: &str = ".lostcontrol";
+const DEFAULT_CHUNKS_DIR: &str = "chunks";
+// files of at least this size are split into deduplicated chunks instead of being copied
+const DEFAULT_CHUNK_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+fn default_chunk_threshold() -> u64 {
+    DEFAULT_CHUNK_THRESHOLD
+}
+
+fn default_rename_threshold() -> u8 {
+    DEFAULT_RENAME_THRESHOLD
+}
 
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,46 +41,155 @@ pub struct Repo {
     #[serde(skip)]
     repos_dir: PathBuf,
     #[serde(skip)]
+    root_dir: PathBuf,
+    #[serde(skip)]
     closed: bool,
     #[serde(skip)]
     modified: bool,
     pub name: String, 
     pub current_branch: String,
     pub branches: Vec<String>,
+    #[serde(default = "default_chunk_threshold")]
+    pub chunk_threshold: u64,
+    // how similar in percent an added file has to be to a removed one to count as moved
+    #[serde(default = "default_rename_threshold")]
+    pub rename_threshold: u8,
     #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
-    pub ignored_files: Vec<String>,
+    pub ignored_files: Vec<RepoPath>,
     #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
-    pub ignored_dirs: Vec<String>,
+    pub ignored_dirs: Vec<RepoPath>,
     #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
-    pub staged_files: Vec<String>,
+    pub staged_files: Vec<RepoPath>,
+    // tracked entries that the next commit records as deleted
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub staged_removals: Vec<RepoPath>,
+    // moves the next commit records, their sources are part of staged_removals and their targets of staged_files
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub staged_renames: Vec<RenamedFile>,
+    // staged files whose staged contents are a snapshot in the index instead of the working tree
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub staged_snapshots: Vec<RepoPath>,
+    // staged snapshots that are large enough to be stored as a manifest of chunks
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub chunked_snapshots: Vec<RepoPath>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub pending_revert: Option<PendingRevert>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub pending_cherry_pick: Option<PendingCherryPick>,
+    // written into every new commit, the user name of the environment is used if it isn't set
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub author: Option<String>,
 }
 
 // A repository struct should store the repo name, the current branch and the available branches
 impl Repo {
-    fn filter_paths(&mut self, entries: &Vec<PathBuf>) -> Vec<String> {
-        let mut files: Vec<String> = vec![];
+    fn filter_paths(&mut self, entries: &[PathBuf]) -> Vec<RepoPath> {
+        let mut files: Vec<RepoPath> = vec![];
         for entry in entries.iter() {
-            if entry.is_file() {
-                files.push(entry.to_str().unwrap().to_string());
+            let (entry, entry_key) = match self.resolve_path(entry) {
+                Ok(resolved) => resolved,
+                Err(()) => continue
+            };
+            // symlinks are staged as links, is_file() and is_dir() would follow them
+            if is_symlink(&entry) || entry.is_file() {
+                files.push(entry_key);
             }
             else if entry.is_dir(){
-                let dir_entries = expand_directory(entry, &self.ignored_dirs);
+                // directories are entries of their own, so empty ones survive a commit
+                if !entry_key.is_empty() {
+                    files.push(entry_key);
+                }
+                let dir_entries = expand_directory(&entry, &self.ignored_dir_paths());
                 for dir_entry in dir_entries.iter() {
-                    files.push(dir_entry.to_str().unwrap().to_string());
+                    files.push(self.key_for(dir_entry));
                 }
             }
             else {
-                dprintln!("[WARN] File {} is not a file or directory, ignoring it for now", entry.to_str().unwrap());
+                dprintln!("[WARN] File {} is not a file or directory, ignoring it for now", entry.display());
             }
         }
         files
     }
 
+    // Turns a path as typed by the user into an absolute path and its key relative to the repository root
+    pub(crate) fn resolve_path(&self, path: &Path) -> Result<(PathBuf, RepoPath), ()> {
+        let absolute_path = normalize_path(&std::env::current_dir().unwrap().join(path));
+        // resolve symlinks in the parent directories, but never the entry itself
+        let absolute_path = match (absolute_path.parent(), absolute_path.file_name()) {
+            (Some(parent), Some(file_name)) => match std::fs::canonicalize(parent) {
+                Ok(parent) => parent.join(file_name),
+                Err(_) => absolute_path
+            },
+            _ => absolute_path
+        };
+        match absolute_path.strip_prefix(&self.root_dir) {
+            Ok(relative_path) => {
+                let key = normalize_key(relative_path);
+                Ok((absolute_path, key))
+            },
+            Err(_) => {
+                dprintln!("[WARN] {} is outside of the repository, skipping...", path.display());
+                Err(())
+            }
+        }
+    }
+
+    // Paths that resolve_path rejects, so the caller can tell the user about them
+    pub fn outside_paths<'a>(&self, paths: &'a [PathBuf]) -> Vec<&'a PathBuf> {
+        paths.iter().filter(|path| self.resolve_path(path).is_err()).collect()
+    }
+
+    // Paths found while walking the repository root are always below it
+    fn key_for(&self, path: &Path) -> RepoPath {
+        normalize_key(path.strip_prefix(&self.root_dir).unwrap_or(path))
+    }
+
+    fn ignored_dir_paths(&self) -> Vec<PathBuf> {
+        self.ignored_dirs.iter().map(|dir| self.root_dir.join(dir.to_path_buf())).collect()
+    }
+
+    pub(crate) fn is_ignored(&self, path: &RepoPath) -> bool {
+        self.ignored_files.contains(path)
+            || self.ignored_dirs.iter().any(|dir| path == dir || path.is_inside(dir))
+    }
+
+    // Older metafiles stored paths exactly as typed, bring them into the root-relative form
+    fn normalize_stored_paths(&mut self) {
+        let root_dir = self.root_dir.clone();
+        let normalize = |paths: &Vec<RepoPath>| -> Vec<RepoPath> {
+            let mut normalized: Vec<RepoPath> = vec![];
+            for path in paths.iter() {
+                let absolute_path = normalize_path(&root_dir.join(path.to_path_buf()));
+                let key = match absolute_path.strip_prefix(&root_dir) {
+                    Ok(relative_path) => normalize_key(relative_path),
+                    Err(_) => continue
+                };
+                if !key.is_empty() && !normalized.contains(&key) {
+                    normalized.push(key);
+                }
+            }
+            normalized
+        };
+        let staged_files = normalize(&self.staged_files);
+        let ignored_files = normalize(&self.ignored_files);
+        let ignored_dirs = normalize(&self.ignored_dirs);
+        if staged_files != self.staged_files || ignored_files != self.ignored_files || ignored_dirs != self.ignored_dirs {
+            self.staged_files = staged_files;
+            self.ignored_files = ignored_files;
+            self.ignored_dirs = ignored_dirs;
+            self.modified = true;
+        }
+    }
+
     fn format_branch_dir(&self, branch: &BranchConfig, id: usize) -> String {
         format!("{}-commit-{}", branch.name, id)
     }
 
-    pub fn new(name: &String, root_path: &PathBuf) -> Result<Repo, ()> {
+    pub(crate) fn commit_dir(&self, branch: &BranchConfig, id: usize) -> PathBuf {
+        self.repos_dir.join(&branch.name).join(self.format_branch_dir(branch, id))
+    }
+
+    pub fn new(name: &str, root_path: &Path) -> Result<Repo, ()> {
         // write a new metafile
         let mf_path = root_path.join(DEFAULT_CONFIG_FILE);
         let mf_repos_dir = root_path.join(DEFAULT_REPOS_DIR);
@@ -72,28 +204,44 @@ impl Repo {
         let config = Repo {
             metafile_path: mf_path.clone(),
             repos_dir: mf_repos_dir.clone(),
+            root_dir: root_path.to_path_buf(),
             closed: false,
             modified: true,
             name: name.to_string(),
             current_branch: String::from(DEFAULT_BRANCH),
             branches: vec![String::from(DEFAULT_BRANCH)],
+            chunk_threshold: DEFAULT_CHUNK_THRESHOLD,
+            rename_threshold: DEFAULT_RENAME_THRESHOLD,
             ignored_files: vec![
-                format!("./{}", mf_path.to_str().unwrap())
+                RepoPath::from_path(Path::new(DEFAULT_CONFIG_FILE))
             ],
             ignored_dirs: vec![
-                format!("./{}",mf_repos_dir.to_str().unwrap())
+                RepoPath::from_path(Path::new(DEFAULT_REPOS_DIR))
             ],
             staged_files: vec![],
+            staged_removals: vec![],
+            staged_renames: vec![],
+            staged_snapshots: vec![],
+            chunked_snapshots: vec![],
+            pending_revert: None,
+            pending_cherry_pick: None,
+            author: None,
         };
         dprintln!("[INFO] Metafile {} created!", DEFAULT_CONFIG_FILE);
         Ok(config)
     }
 
     pub fn from_file(dir: Option<&String>) -> Result<Repo, ()> {
-        // if no directory is specified, use the current directory
+        // if no directory is specified, search the current directory and its parents
         let mf_path_base = match dir {
             Some(dir) => std::fs::canonicalize(PathBuf::from(dir)).unwrap(),
-            None => std::env::current_dir().unwrap(),
+            None => {
+                let current_dir = std::env::current_dir().unwrap();
+                match current_dir.ancestors().find(|dir| dir.join(DEFAULT_CONFIG_FILE).exists()) {
+                    Some(root_dir) => root_dir.to_path_buf(),
+                    None => return Err(())
+                }
+            },
         };
         let mf_path = mf_path_base.join(DEFAULT_CONFIG_FILE);
         let mf_repos_dir = mf_path_base.join(DEFAULT_REPOS_DIR);
@@ -104,7 +252,10 @@ impl Repo {
 
         let mut metafile = match std::fs::File::open(&mf_path) {
             Ok(file) => file,
-            Err(e) => panic!("Cannot load metafile {}: {}", DEFAULT_CONFIG_FILE, e)
+            Err(e) => {
+                dprintln!("[ERROR] Cannot load metafile {}: {}", mf_path.display(), e);
+                return Err(());
+            }
         };
         let mut version = String::new();
         let mut contents = String::new();
@@ -114,8 +265,8 @@ impl Repo {
             match index {
                 0 => version = line.unwrap(),
                 _ => { 
-                    let line_string: String = String::from(line.unwrap()) + "\n";
-                    contents.push_str(&line_string.as_str());
+                    let line_string: String = line.unwrap() + "\n";
+                    contents.push_str(line_string.as_str());
                 }
             }
         }
@@ -125,76 +276,169 @@ impl Repo {
             return Err(());
         }
 
-        let mut config: Repo = serde_yaml::from_str(&contents).unwrap();
-        config.metafile_path = mf_path.clone();
-        config.repos_dir = mf_repos_dir.clone();
+        let mut config: Repo = match serde_yaml::from_str(&contents) {
+            Ok(config) => config,
+            Err(e) => {
+                dprintln!("[ERROR] Cannot parse metafile {}: {}", mf_path.display(), e);
+                return Err(());
+            }
+        };
+        config.metafile_path = mf_path;
+        config.repos_dir = mf_repos_dir;
+        config.root_dir = mf_path_base;
+        config.normalize_stored_paths();
         dprintln!("[INFO] Metafile for repository {} loaded!", config.name);
         Ok(config)
     }
 
-    pub fn stage_files(&mut self, entries: &Vec<PathBuf>){
+    pub fn stage_files(&mut self, entries: &[PathBuf]){
         if self.closed {
             return;
         }
 
-        let files: Vec<String> = self.filter_paths(entries);
-        for file in files.iter() {
-            if !self.staged_files.contains(&file) {
-                if !self.ignored_files.contains(&file) {
-                    self.staged_files.push(file.clone());
-                    dprintln!("[INFO] File {} staged!", file);
-                }
-                else {
-                    dprintln!("[INFO] File {} is on ignore list, skipping...", file);
+        // tracked entries that are gone are staged as removed
+        let (entries, missing): (Vec<PathBuf>, Vec<PathBuf>) = entries.iter().cloned()
+            .partition(|entry| entry_exists(entry));
+        let (missing, mut vanished_keys): (Vec<PathBuf>, Vec<RepoPath>) = missing.into_iter()
+            .filter_map(|entry| self.resolve_path(&entry).ok().map(|(_, key)| (entry, key)))
+            .unzip();
+
+        let files: Vec<RepoPath> = self.filter_paths(&entries);
+        // entries inside staged directories may be gone as well
+        for entry in entries.iter() {
+            if EntryKind::of(entry) == Some(EntryKind::Directory) {
+                if let Ok((_, key)) = self.resolve_path(entry) {
+                    vanished_keys.push(key);
                 }
             }
+        }
+        if !vanished_keys.is_empty() && self.stage_vanished(&vanished_keys).is_err() {
+            dprintln!("[ERROR] Cannot look for removed entries in the current commit");
+        }
+        for (entry, key) in missing.iter().zip(vanished_keys.iter()) {
+            let removed = self.staged_removals.iter()
+                .any(|removal| removal == key || key.is_inside(removal) || removal.is_inside(key));
+            if !removed {
+                dprintln!("[WARN] {} does not exist and is not tracked, skipping...", entry.display());
+            }
+        }
+        let mut snapshots: Vec<RepoPath> = vec![];
+        for file in files.iter() {
+            if self.is_ignored(file) {
+                dprintln!("[INFO] File {} is on ignore list, skipping...", file);
+                continue;
+            }
+            if !self.staged_files.contains(file) {
+                self.staged_files.push(file.clone());
+                dprintln!("[INFO] File {} staged!", file);
+            }
             else {
-                dprintln!("[INFO] File {} is already staged, skipping...", file);
+                dprintln!("[INFO] File {} is already staged, taking a new snapshot...", file);
             }
+            // an entry that is back replaces its staged removal, what else was inside stays removed
+            let lifted: Vec<RepoPath> = self.staged_removals.iter()
+                .filter(|removal| *removal == file || file.is_inside(removal))
+                .cloned()
+                .collect();
+            if !lifted.is_empty() {
+                self.staged_removals.retain(|removal| !lifted.contains(removal));
+                self.staged_renames.retain(|renamed_file| !lifted.iter().any(|removal| renamed_file.from.is_inside(removal) || renamed_file.from == *removal));
+                let _ = self.stage_vanished(&lifted);
+            }
+            snapshots.push(file.clone());
         }
         self.modified = true;
+        // the commit takes exactly what the entries look like right now, later edits need staging again
+        self.snapshot_entries(&snapshots);
     }
 
-    pub fn unstage_files(&mut self, entries: &Vec<PathBuf>){
-        let files: Vec<String> = self.filter_paths(entries);
-        self.staged_files.retain(|x| !files.contains(x));
-        for file in files.iter() {
-            dprintln!("[INFO] File {} unstaged!", file);
+    // Entries don't have to exist anymore, unstaging a directory unstages everything inside it
+    pub fn unstage_files(&mut self, entries: &[PathBuf]){
+        let mut keys: Vec<RepoPath> = vec![];
+        for entry in entries.iter() {
+            if let Ok((_, key)) = self.resolve_path(entry) {
+                keys.push(key);
+            }
         }
+        let unstaged = |file: &RepoPath| keys.iter().any(|key| key.is_empty() || file == key || file.is_inside(key));
+        self.staged_files.retain(|file| {
+            let unstage = unstaged(file);
+            if unstage {
+                dprintln!("[INFO] File {} unstaged!", file);
+            }
+            !unstage
+        });
+        self.staged_removals.retain(|file| {
+            let unstage = unstaged(file);
+            if unstage {
+                dprintln!("[INFO] Removal of {} unstaged!", file);
+            }
+            !unstage
+        });
+        // a move is only recorded while both of its halves are staged
+        self.staged_renames.retain(|renamed_file| !unstaged(&renamed_file.from) && !unstaged(&renamed_file.to));
         self.modified = true;
+        self.prune_index();
     }
 
     pub fn unstage_all(&mut self){
         self.staged_files.clear();
+        self.staged_removals.clear();
+        self.staged_renames.clear();
         self.modified = true;
+        self.prune_index();
     }
 
     pub fn commit(&mut self, commit_msg: String) -> Result<usize, ()>{
+        if self.pending_cherry_pick.is_some() || self.rebase_in_progress() || self.bisect_in_progress() {
+            dprintln!("[ERROR] A {} is in progress, continue or abort it instead", self.pending_operation().unwrap());
+            return Err(());
+        }
+        let mut commit = Commit::new(0, commit_msg, self.author(), vec![]);
+        commit.reverted_commit = self.pending_revert.as_ref().map(|pending| pending.commit_id);
+        let operation = match commit.reverted_commit {
+            Some(reverted_commit) => format!("revert {}", reverted_commit),
+            None => "commit".to_string()
+        };
+        let staged_files_count = self.record_commit(commit, &operation)?;
+        self.pending_revert = None;
+        Ok(staged_files_count)
+    }
+
+    // Stores the staged entries as a new commit on the current branch, the id and file lists of commit are filled in here.
+    // operation names the command in the reflog
+    pub(crate) fn record_commit(&mut self, mut commit: Commit, operation: &str) -> Result<usize, ()> {
         if self.closed {
             dprintln!("[WARN] Repository {} is closed, skipping commit!", self.name);
             return Err(());
         }
 
-        if self.staged_files.is_empty() {
+        if self.staged_files.is_empty() && self.staged_removals.is_empty() {
             dprintln!("[WARN] No staged files, skipping commit!");
             return Err(());
         }
 
         // create a config path with this pattern: DEFAULT_REPOS_DIR/<current_branch>/<current_branch>.conf
-        let staged_files_count = self.staged_files.len();
+        let staged_files_count = self.staged_files.len() + self.staged_removals.len();
         let branch_path = self.repos_dir.clone().join(self.current_branch.clone());
 
         let mut branch_config = self.get_branch(&self.current_branch).unwrap();
 
-        let commit = Commit::new(
-            branch_config.commit_count() + 1 as usize, 
-            commit_msg, 
-            self.staged_files.clone()
-        );
+        commit.id = branch_config.next_commit_id();
+        commit.modified_files = self.staged_files.clone();
+        commit.removed_files = self.staged_removals.clone();
+        commit.renamed_files = self.staged_renames.clone();
 
         // now we create the commit archive file
         let commit_path = branch_path.join(self.format_branch_dir(&branch_config, commit.id));
         
+        // an undone commit leaves its directory behind, nothing refers to it anymore
+        if commit_path.exists() {
+            if let Err(e) = std::fs::remove_dir_all(&commit_path) {
+                dprintln!("[ERROR] Cannot remove leftover commit directory {}: {}", commit_path.display(), e);
+                return Err(());
+            }
+        }
         dprintln!("[INFO] Writing staged files to commit directory {}...", commit_path.display());
         match std::fs::create_dir(&commit_path) {
             Ok(_) => {},
@@ -203,141 +447,467 @@ impl Repo {
                 return Err(());
             }
         };
-        for file in self.staged_files.iter() {
-            let staged_file_path = PathBuf::from(&file);
-            let commit_file_path = commit_path.join(&staged_file_path);
-
-            dprintln!("[INFO] Copying staged file {} to commit directory {}...", staged_file_path.display(), commit_file_path.display());
-            if !commit_file_path.parent().unwrap().exists() {
-                match std::fs::create_dir_all(commit_file_path.parent().unwrap()) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        dprintln!("[ERROR] Cannot create commit directory {}: {}", commit_file_path.parent().unwrap().display(), e);
-                        return Err(());
-                    }
-                }
+        commit.chunked_files = match self.store_staged_entries(&self.staged_files, &commit_path) {
+            Ok(chunked_files) => chunked_files,
+            Err(()) => {
+                dprintln!("[ERROR] Aborting commit!");
+                let _ = std::fs::remove_dir_all(&commit_path);
+                return Err(());
             }
-            match std::fs::copy(&staged_file_path, &commit_file_path) {
-                Ok(_) => {},
-                Err(e) => {
-                    dprintln!("[ERROR] Failed to copy file to commit directory {}: {}", &staged_file_path.display(), e);
-                    dprintln!("[ERROR] Aborting commit!");
+        };
+
+        let old_commit = branch_config.current_commit;
+        branch_config.push_commit(commit);
+        self.log_head_change(&branch_config, old_commit, operation);
+        self.staged_files.clear();
+        self.staged_removals.clear();
+        self.staged_renames.clear();
+        self.modified = true;
+        self.prune_index();
+        Ok(staged_files_count)
+    }
+
+    // Folds the staged files into the current commit and/or replaces its message. The result is stored as a new commit
+    // that takes the place of the current one, which stays stored so the reflog can restore it.
+    // Returns the number of files that were added to the commit
+    pub fn amend_commit(&mut self, commit_msg: Option<String>) -> Result<usize, ()> {
+        if self.closed {
+            dprintln!("[WARN] Repository {} is closed, skipping amend!", self.name);
+            return Err(());
+        }
+        if let Some(operation) = self.pending_operation() {
+            dprintln!("[ERROR] A {} is in progress, continue or abort it instead", operation);
+            return Err(());
+        }
+
+        if self.staged_files.is_empty() && self.staged_removals.is_empty() && commit_msg.is_none() {
+            dprintln!("[WARN] No staged files and no new message, skipping amend!");
+            return Err(());
+        }
+
+        let mut branch_config = self.get_branch(&self.current_branch)?;
+        let old_commit = match branch_config.get_commit(branch_config.current_commit) {
+            Some(commit) => commit.clone(),
+            None => {
+                dprintln!("[ERROR] Branch {} has no commit to amend", branch_config.name);
+                return Err(());
+            }
+        };
+        let mut commit = old_commit.clone();
+        commit.id = branch_config.next_commit_id();
+        commit.creation_datetime = chrono::Utc::now().to_rfc3339();
+
+        // the amended commit starts out as a copy of the old one
+        let old_path = self.commit_dir(&branch_config, old_commit.id);
+        let commit_path = self.commit_dir(&branch_config, commit.id);
+        if commit_path.exists() {
+            if let Err(e) = std::fs::remove_dir_all(&commit_path) {
+                dprintln!("[ERROR] Cannot remove leftover commit directory {}: {}", commit_path.display(), e);
+                return Err(());
+            }
+        }
+        if let Err(e) = copy_directory(&old_path, &commit_path) {
+            dprintln!("[ERROR] Cannot copy commit {} to {}: {}", old_commit.id, commit_path.display(), e);
+            let _ = std::fs::remove_dir_all(&commit_path);
+            return Err(());
+        }
+
+        let staged_files_count = self.staged_files.len() + self.staged_removals.len();
+        if !self.staged_files.is_empty() {
+            let chunked_files = match self.store_staged_entries(&self.staged_files, &commit_path) {
+                Ok(chunked_files) => chunked_files,
+                Err(()) => {
+                    dprintln!("[ERROR] Aborting amend!");
+                    let _ = std::fs::remove_dir_all(&commit_path);
                     return Err(());
                 }
+            };
+            commit.chunked_files.retain(|file| !self.staged_files.contains(file));
+            commit.chunked_files.extend(chunked_files);
+            for file in self.staged_files.iter() {
+                if !commit.modified_files.contains(file) {
+                    commit.modified_files.push(file.clone());
+                }
+            }
+            commit.removed_files.retain(|file| !self.staged_files.contains(file));
+        }
+        for file in self.staged_removals.iter() {
+            let stored_path = commit_path.join(file.to_path_buf());
+            if let Err(e) = remove_entry(&stored_path) {
+                dprintln!("[ERROR] Cannot remove {} from commit {}: {}", file, commit.id, e);
+                let _ = std::fs::remove_dir_all(&commit_path);
+                return Err(());
+            }
+            commit.modified_files.retain(|modified| modified != file && !modified.is_inside(file));
+            commit.chunked_files.retain(|chunked| chunked != file && !chunked.is_inside(file));
+            if !commit.removed_files.contains(file) {
+                commit.removed_files.push(file.clone());
+            }
+        }
+        for renamed_file in self.staged_renames.iter() {
+            if !commit.renamed_files.contains(renamed_file) {
+                commit.renamed_files.push(renamed_file.clone());
             }
         }
+        if let Some(commit_msg) = commit_msg {
+            commit.message = commit_msg;
+        }
 
-        branch_config.push_commit(commit);
+        let mut history = branch_config.get_commits();
+        if let Some(last) = history.last_mut() {
+            *last = commit;
+        }
+        branch_config.set_history(history);
+        self.log_head_change(&branch_config, old_commit.id, "commit amend");
         self.staged_files.clear();
+        self.staged_removals.clear();
+        self.staged_renames.clear();
         self.modified = true;
+        self.prune_index();
         Ok(staged_files_count)
     }
 
+    // Takes the commit out of the history of the current branch, its directory stays so the reflog can restore it
     pub fn remove_commit(&mut self, commit_id: usize) -> Result<(), ()>{
         if self.closed {
             dprintln!("[WARN] Repository {} is closed, skipping commit!", self.name);
             return Err(());
         }
 
-        let branch_path = self.repos_dir.clone().join(self.current_branch.clone());
         let mut branch_config = self.get_branch(&self.current_branch).unwrap();
+        let old_commit = branch_config.current_commit;
+        branch_config.remove_commit(commit_id)?;
+        self.log_head_change(&branch_config, old_commit, &format!("commit remove {}", commit_id));
+        self.modified = true;
+        Ok(())
+    }
 
-        // remove commit directory
-        let commit_path = branch_path.join(self.format_branch_dir(&branch_config, commit_id));
-        dprintln!("[INFO] Removing commit directory {}...", commit_path.display());
-        match std::fs::remove_dir_all(&commit_path) {
-            Ok(_) => {},
-            Err(e) => {
-                dprintln!("[ERROR] Cannot remove commit directory {}: {}", commit_path.display(), e);
+    // Copies working tree entries into a commit or stash directory, large files go into the chunk store.
+    // Returns the files that were stored as a manifest of chunks
+    pub(crate) fn store_entries(&self, entries: &[RepoPath], target_dir: &Path) -> Result<Vec<RepoPath>, ()> {
+        self.copy_entries(entries, target_dir, false)
+    }
+
+    // Like store_entries, but partially staged files are taken from their snapshot in the index
+    pub(crate) fn store_staged_entries(&self, entries: &[RepoPath], target_dir: &Path) -> Result<Vec<RepoPath>, ()> {
+        self.copy_entries(entries, target_dir, true)
+    }
+
+    fn copy_entries(&self, entries: &[RepoPath], target_dir: &Path, from_index: bool) -> Result<Vec<RepoPath>, ()> {
+        let mut chunked_files: Vec<RepoPath> = vec![];
+        for file in entries.iter() {
+            let (source_path, chunked_snapshot) = match from_index {
+                true => self.staged_source(file)?,
+                false => (self.root_dir.join(file.to_path_buf()), false)
+            };
+            let target_path = target_dir.join(file.to_path_buf());
+
+            // an amended commit may already store something else under this name
+            let source_kind = EntryKind::of(&source_path);
+            let target_kind = EntryKind::of(&target_path);
+            if target_kind.is_some() && target_kind != source_kind {
+                if let Err(e) = remove_entry(&target_path) {
+                    dprintln!("[ERROR] Cannot replace {}: {}", target_path.display(), e);
+                    return Err(());
+                }
+            }
+
+            if source_kind == Some(EntryKind::Directory) {
+                dprintln!("[INFO] Creating directory {} in {}...", file, target_dir.display());
+                if let Err(e) = std::fs::create_dir_all(&target_path) {
+                    dprintln!("[ERROR] Cannot create directory {}: {}", target_path.display(), e);
+                    return Err(());
+                }
+                continue;
+            }
+
+            dprintln!("[INFO] Copying file {} to {}...", source_path.display(), target_path.display());
+            if let Err(e) = std::fs::create_dir_all(target_path.parent().unwrap()) {
+                dprintln!("[ERROR] Cannot create directory {}: {}", target_path.parent().unwrap().display(), e);
+                return Err(());
+            }
+            if is_symlink(&source_path) {
+                if let Err(e) = copy_symlink(&source_path, &target_path) {
+                    dprintln!("[ERROR] Failed to copy symlink {}: {}", source_path.display(), e);
+                    return Err(());
+                }
+                continue;
+            }
+            // the chunks of a snapshot are stored already, only its manifest is copied
+            if chunked_snapshot {
+                if let Err(e) = std::fs::copy(&source_path, &target_path) {
+                    dprintln!("[ERROR] Failed to copy manifest {}: {}", source_path.display(), e);
+                    return Err(());
+                }
+                chunked_files.push(file.clone());
+                continue;
+            }
+            let file_size = match std::fs::metadata(&source_path) {
+                Ok(metadata) => metadata.len(),
+                Err(e) => {
+                    dprintln!("[ERROR] Cannot read file {}: {}", source_path.display(), e);
+                    return Err(());
+                }
+            };
+            if file_size >= self.chunk_threshold {
+                if let Err(e) = self.chunk_store().store_file(&source_path, &target_path) {
+                    dprintln!("[ERROR] Failed to store chunks of {}: {}", source_path.display(), e);
+                    return Err(());
+                }
+                chunked_files.push(file.clone());
+                continue;
+            }
+            if let Err(e) = std::fs::copy(&source_path, &target_path) {
+                dprintln!("[ERROR] Failed to copy file {}: {}", source_path.display(), e);
                 return Err(());
             }
+        }
+        Ok(chunked_files)
+    }
+
+    pub(crate) fn chunk_store(&self) -> ChunkStore {
+        ChunkStore::new(self.repos_dir.join(DEFAULT_CHUNKS_DIR))
+    }
+
+    // Removes chunks that neither a commit on any branch, the stash nor a staged snapshot refers to anymore
+    pub(crate) fn prune_chunks(&self) {
+        let branches = match self.get_branches() {
+            Ok(branches) => branches,
+            Err(()) => return
+        };
+        let mut manifest_paths: Vec<PathBuf> = self.stash_manifests();
+        manifest_paths.extend(self.snapshot_manifests());
+        // a rebase keeps the original commits around until it is finished
+        let rebase_backup = match self.rebase_in_progress() {
+            true => self.get_branch(&REBASE_BACKUP_BRANCH.to_string()).ok(),
+            false => None
         };
+        for branch_config in branches.iter().chain(rebase_backup.iter()) {
+            for commit in branch_config.get_commits().iter().chain(branch_config.get_detached_commits().iter()) {
+                let commit_path = self.commit_dir(branch_config, commit.id);
+                for file in commit.chunked_files.iter() {
+                    manifest_paths.push(commit_path.join(file.to_path_buf()));
+                }
+            }
+        }
+        match self.chunk_store().prune(&manifest_paths) {
+            Ok(removed) => dprintln!("[INFO] Pruned {} unused chunks", removed),
+            Err(e) => dprintln!("[ERROR] Cannot prune chunks: {}", e)
+        }
+    }
 
-        match branch_config.remove_commit(commit_id) {
-            Ok(_) => {
-                self.modified = true;
-                Ok(())
-            },
-            Err(()) => Err(())
+    pub fn set_chunk_threshold(&mut self, chunk_threshold: u64) {
+        self.chunk_threshold = chunk_threshold;
+        self.modified = true;
+    }
+
+    pub fn set_rename_threshold(&mut self, rename_threshold: u8) {
+        self.rename_threshold = rename_threshold;
+        self.modified = true;
+    }
+
+    pub fn set_author(&mut self, author: String) {
+        self.author = Some(author);
+        self.modified = true;
+    }
+
+    pub fn author(&self) -> String {
+        if let Some(author) = &self.author {
+            return author.clone();
+        }
+        std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_else(|_| String::from("unknown"))
+    }
+
+    // Name of the revert, cherry-pick or rebase that is waiting for its conflicts to be resolved
+    pub fn pending_operation(&self) -> Option<&'static str> {
+        if self.rebase_in_progress() {
+            return Some("rebase");
+        }
+        if self.pending_revert.is_some() {
+            return Some("revert");
+        }
+        if self.pending_cherry_pick.is_some() {
+            return Some("cherry-pick");
         }
+        if self.bisect_in_progress() {
+            return Some("bisect");
+        }
+        None
     }
 
-    // TODO: Remove files that aren't part of the commit
-    // TODO: Check if commit id is valid
     pub fn restore_commit(&self, commit_id: usize) -> Result<(), ()>{
         if self.closed {
             dprintln!("[WARN] Repository {} is closed, skipping close!", self.name);
             return Err(());
         }
 
-        let restore_path = std::env::current_dir().unwrap();
-        let branch_path = self.repos_dir.clone().join(self.current_branch.clone());
         let branch_config = match self.get_branch(&self.current_branch) {
             Ok(config) => config,
             Err(()) => return Err(())
         };
-        
-        let commit_path = branch_path.join(self.format_branch_dir(&branch_config, commit_id));
-        dprintln!("[INFO] Restoring commit directory {}...", commit_path.display());
-        let commit_files = expand_directory(&commit_path, &Vec::new());
-        let restore_dir_files = expand_directory(&restore_path, &self.ignored_files);
-        /*
-        for rest_file in restore_dir_files.iter() {
-            if !commit_files.contains(rest_file) && !self.ignored_files.contains(&rest_file.to_str().unwrap().to_string()) {
-                dprintln!("[INFO] Removing file {} from restore directory...", rest_file.display());
-                match std::fs::remove_file(rest_file) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        dprintln!("[ERROR] Cannot remove file {}: {}", rest_file.display(), e);
-                        return Err(());
+        if branch_config.get_commit(commit_id).is_none() {
+            dprintln!("[ERROR] Commit {} does not exist on branch {}", commit_id, branch_config.name);
+            return Err(());
+        }
+
+        let target_tree = self.get_tree(&branch_config, commit_id)?;
+        let head_tree = self.get_tree(&branch_config, branch_config.current_commit)?;
+        self.checkout_tree(&head_tree, &target_tree)
+    }
+
+    // Replaces the entries of current_tree in the working directory with the ones of target_tree,
+    // untracked entries are left alone
+    pub(crate) fn checkout_tree(&self, current_tree: &Tree, target_tree: &Tree) -> Result<(), ()> {
+        let restore_path = self.root_dir.clone();
+
+        // remove tracked entries that aren't part of the restored commit, contents before their directories
+        for (path, entry) in current_tree.iter().rev() {
+            if target_tree.contains(path) {
+                continue;
+            }
+            let restore_entry_path = restore_path.join(path.to_path_buf());
+            match (entry.kind, EntryKind::of(&restore_entry_path)) {
+                (_, None) => {},
+                (EntryKind::Directory, Some(EntryKind::Directory)) => {
+                    // untracked files inside keep the directory alive
+                    if std::fs::remove_dir(&restore_entry_path).is_ok() {
+                        dprintln!("[INFO] Removed directory {}", restore_entry_path.display());
                     }
-                }
-                if rest_file.parent().unwrap().read_dir().unwrap().next().is_none() {
-                    dprintln!("[INFO] Removing empty directory {}...", rest_file.parent().unwrap().display());
-                    match std::fs::remove_dir(rest_file.parent().unwrap()) {
-                        Ok(_) => {},
-                        Err(e) => {
-                            dprintln!("[ERROR] Cannot remove directory {}: {}", rest_file.parent().unwrap().display(), e);
-                            return Err(());
-                        }
+                },
+                // never remove a directory that took the place of a tracked file
+                (_, Some(EntryKind::Directory)) | (EntryKind::Directory, _) => {},
+                (_, Some(_)) => {
+                    dprintln!("[INFO] Removing {} from restore directory...", restore_entry_path.display());
+                    if let Err(e) = remove_entry(&restore_entry_path) {
+                        dprintln!("[ERROR] Cannot remove {}: {}", restore_entry_path.display(), e);
+                        return Err(());
                     }
                 }
             }
-        }*/
+        }
 
-        for file in commit_files.iter() {
-            let commit_file_path = &file;
-            let commit_file_diff = match pathdiff::diff_paths(&commit_file_path, &commit_path) {
-                Some(diff) => diff,
-                None => {
-                    dprintln!("[ERROR] Cannot diff file {}", commit_file_path.display());
-                    return Err(());
-                }
-            };
+        // directories are sorted before their contents, so parents are always created first
+        for (path, entry) in target_tree.iter() {
+            restore_entry(entry, &restore_path.join(path.to_path_buf()))?;
+        }
+        Ok(())
+    }
 
-            let restore_file_path = restore_path.join(&commit_file_diff);
-            // if the file exists in the restore directory, but not in the commit files, remove it
+    // Puts the passed entries back to their state in tree, the ones tree doesn't contain are removed
+    pub(crate) fn reset_working_entries(&self, paths: &[RepoPath], tree: &Tree) -> Result<(), ()> {
+        let mut paths = paths.to_vec();
+        paths.sort();
+        paths.dedup();
+        for path in paths.iter().rev().filter(|path| !tree.contains(path)) {
+            self.remove_working_entry(path)?;
+        }
+        for path in paths.iter() {
+            if let Some(entry) = tree.get(path) {
+                restore_entry(entry, &self.root_dir.join(path.to_path_buf()))?;
+            }
+        }
+        Ok(())
+    }
 
-            dprintln!("[INFO] Copying commit file {} to restore directory {}...", commit_file_path.display(), restore_file_path.display());
-            if !restore_file_path.parent().unwrap().exists() {
-                match std::fs::create_dir_all(restore_file_path.parent().unwrap()) {
-                    Ok(_) => {},
-                    Err(e) => {
-                        dprintln!("[ERROR] Cannot create restore directory {}: {}", restore_file_path.parent().unwrap().display(), e);
-                        return Err(());
-                    }
-                }
+    // Builds the full tree of a commit by layering every commit up to and including it
+    pub fn get_tree(&self, branch_config: &BranchConfig, commit_id: usize) -> Result<Tree, ()> {
+        let mut tree = Tree::new();
+        if commit_id == 0 {
+            return Ok(tree);
+        }
+        for commit in branch_config.get_commits().iter() {
+            let commit_path = self.commit_dir(branch_config, commit.id);
+            self.overlay_stored(&mut tree, &commit_path, &commit.chunked_files);
+            for file in commit.removed_files.iter() {
+                tree.remove(file);
             }
-            match std::fs::copy(&commit_file_path, &restore_file_path) {
-                Ok(_) => {},
-                Err(e) => {
-                    dprintln!("[ERROR] Failed to copy file to restore directory {}: {}", &commit_file_path.display(), e);
-                    dprintln!("[ERROR] Aborting commit!");
-                    return Err(());
-                }
+            if commit.id == commit_id {
+                return Ok(tree);
             }
         }
-        return Ok(());
+        dprintln!("[ERROR] Commit {} does not exist on branch {}", commit_id, branch_config.name);
+        Err(())
+    }
+
+    // Adds the entries of a commit or stash directory to tree
+    pub(crate) fn overlay_stored(&self, tree: &mut Tree, dir: &Path, chunked_files: &[RepoPath]) {
+        tree.overlay_directory(dir, &[]);
+        for file in chunked_files.iter() {
+            if let Some(entry) = tree.get_mut(file) {
+                entry.chunks = Some(self.chunk_store());
+            }
+        }
+    }
+
+    pub(crate) fn working_entry(&self, path: &RepoPath) -> Option<TreeEntry> {
+        let working_path = self.root_dir.join(path.to_path_buf());
+        EntryKind::of(&working_path).map(|kind| TreeEntry {
+            kind,
+            source: working_path,
+            chunks: None,
+        })
+    }
+
+    // The tree of the working directory, minus everything on the ignore lists
+    pub fn get_working_tree(&self) -> Tree {
+        let mut tree = Tree::new();
+        tree.overlay_directory(&self.root_dir, &self.ignored_dir_paths());
+        for ignored_file in self.ignored_files.iter() {
+            tree.remove(ignored_file);
+        }
+        tree
+    }
+
+    // Changes between the head of the current branch and the working directory
+    pub fn working_changes(&self) -> Result<Vec<TreeChange>, ()> {
+        let branch_config = self.get_branch(&self.current_branch)?;
+        // while bisecting, the working tree holds the commit under test
+        let base_commit = match self.bisect_in_progress() {
+            true => self.get_bisect_state()?.current,
+            false => branch_config.current_commit
+        };
+        let base_tree = self.get_tree(&branch_config, base_commit)?;
+        Ok(compare(&base_tree, &self.get_working_tree()))
+    }
+
+    pub fn root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+
+    pub(crate) fn metafile_path(&self) -> &Path {
+        &self.metafile_path
+    }
+
+    // Drops the repository without writing the metafile
+    pub(crate) fn discard(&mut self) {
+        self.closed = true;
+    }
+
+    pub(crate) fn repos_dir(&self) -> &Path {
+        &self.repos_dir
+    }
+
+    pub(crate) fn set_pending_cherry_pick(&mut self, pending_cherry_pick: Option<PendingCherryPick>) {
+        self.pending_cherry_pick = pending_cherry_pick;
+        self.modified = true;
+    }
+
+    pub(crate) fn set_staged_files(&mut self, staged_files: Vec<RepoPath>) {
+        self.staged_files = staged_files;
+        self.modified = true;
+    }
+
+    pub(crate) fn set_staged_snapshots(&mut self, staged_snapshots: Vec<RepoPath>) {
+        self.chunked_snapshots.retain(|file| staged_snapshots.contains(file));
+        self.staged_snapshots = staged_snapshots;
+        self.modified = true;
+    }
+
+    pub(crate) fn set_chunked_snapshots(&mut self, chunked_snapshots: Vec<RepoPath>) {
+        self.chunked_snapshots = chunked_snapshots;
+        self.modified = true;
     }
 
     pub fn get_branch(&self, branch: &String) -> Result<BranchConfig, ()>{
@@ -346,14 +916,11 @@ impl Repo {
             return Err(());
         }
 
-        let branch_path = self.repos_dir.clone().join(&branch);
-        let mut branch_config_path = branch_path.join(&branch);
+        let branch_path = self.repos_dir.clone().join(branch);
+        let mut branch_config_path = branch_path.join(branch);
         branch_config_path.set_extension("conf");
 
-        match BranchConfig::from_file(&branch_config_path) {
-            Ok(config) => return Ok(config),
-            Err(()) => return Err(())
-        };
+        BranchConfig::from_file(&branch_config_path)
     }
 
     pub fn get_branches(&self) -> Result<Vec<BranchConfig>, ()> {
@@ -365,7 +932,7 @@ impl Repo {
         let mut branches: Vec<BranchConfig> = Vec::new();
         let branch_base = self.repos_dir.clone();
         for branch in self.branches.iter() {
-            let mut branch_config_path = branch_base.join(&branch).join(&branch);
+            let mut branch_config_path = branch_base.join(branch).join(branch);
             branch_config_path.set_extension("conf");
 
             match BranchConfig::from_file(&branch_config_path) {
@@ -376,6 +943,186 @@ impl Repo {
         Ok(branches)
     }
 
+    // Branch names become directory names next to the chunk store and the stash
+    fn valid_branch_name(name: &str) -> bool {
+        !name.is_empty()
+            && !name.starts_with('.')
+            && !name.starts_with('-')
+            && !name.chars().any(|character| character == '/' || character == '\\' || character == ':' || character.is_whitespace())
+            && name != DEFAULT_CHUNKS_DIR
+            && name != STASH_DIR
+            && name != REBASE_DIR
+            && name != REFLOG_DIR
+            && name != OPLOG_DIR
+            && name != BISECT_DIR
+            && name != BACKUP_DIR
+            && name != INDEX_DIR
+    }
+
+    // Creates a branch that starts out with every commit of the current branch up to its current commit
+    pub fn create_branch(&mut self, name: &str) -> Result<(), ()> {
+        if self.closed {
+            return Err(());
+        }
+        if !Repo::valid_branch_name(name) {
+            dprintln!("[ERROR] {} is not a valid branch name", name);
+            return Err(());
+        }
+        if self.branches.iter().any(|branch| branch == name) {
+            dprintln!("[ERROR] Branch {} already exists", name);
+            return Err(());
+        }
+
+        // an undone branch add leaves the directory of the branch behind
+        let branch_dir = self.repos_dir.join(name);
+        if branch_dir.exists() {
+            if let Err(e) = std::fs::remove_dir_all(&branch_dir) {
+                dprintln!("[ERROR] Cannot remove leftover branch directory {}: {}", branch_dir.display(), e);
+                return Err(());
+            }
+        }
+        let source_config = self.get_branch(&self.current_branch)?;
+        self.copy_branch(&source_config, name)?;
+        let branch_config = self.get_branch(&name.to_string())?;
+        self.log_head_change(&branch_config, 0, &format!("branch add from {}", self.current_branch));
+
+        self.branches.push(name.to_string());
+        self.modified = true;
+        Ok(())
+    }
+
+    // Writes a new branch with every commit of source up to its current commit
+    pub(crate) fn copy_branch(&self, source_config: &BranchConfig, name: &str) -> Result<(), ()> {
+        let branch_dir = self.repos_dir.join(name);
+        if let Err(e) = std::fs::create_dir_all(&branch_dir) {
+            dprintln!("[ERROR] Cannot create branch directory {}: {}", branch_dir.display(), e);
+            return Err(());
+        }
+        let mut branch_config = BranchConfig::new(name.to_string(), &self.repos_dir);
+        if source_config.current_commit != 0 {
+            for commit in source_config.get_commits() {
+                let source_path = self.commit_dir(source_config, commit.id);
+                let target_path = self.commit_dir(&branch_config, commit.id);
+                if let Err(e) = copy_directory(&source_path, &target_path) {
+                    dprintln!("[ERROR] Cannot copy commit {} to branch {}: {}", commit.id, name, e);
+                    branch_config.discard();
+                    let _ = std::fs::remove_dir_all(&branch_dir);
+                    return Err(());
+                }
+                let commit_id = commit.id;
+                branch_config.push_commit(commit);
+                if commit_id == source_config.current_commit {
+                    break;
+                }
+            }
+        }
+        branch_config.close();
+        Ok(())
+    }
+
+    // Renames the directory, commit directories and config of a branch, the list of branches is left alone
+    pub(crate) fn move_branch(&self, from: &str, to: &str) -> Result<(), ()> {
+        let mut branch_config = self.get_branch(&from.to_string())?;
+        let from_dir = self.repos_dir.join(from);
+        let to_dir = self.repos_dir.join(to);
+        let rename = |source: PathBuf, target: PathBuf| -> Result<(), ()> {
+            std::fs::rename(&source, &target).map_err(|e| {
+                dprintln!("[ERROR] Cannot rename {} to {}: {}", source.display(), target.display(), e);
+            })
+        };
+
+        rename(from_dir.clone(), to_dir.clone())?;
+        for commit in branch_config.get_commits().into_iter().chain(branch_config.get_detached_commits()) {
+            let commit_dir_name = self.format_branch_dir(&branch_config, commit.id);
+            rename(to_dir.join(&commit_dir_name), to_dir.join(format!("{}-commit-{}", to, commit.id)))?;
+        }
+        rename(to_dir.join(format!("{}.conf", from)), to_dir.join(format!("{}.conf", to)))?;
+        branch_config.discard();
+
+        let mut branch_config = self.get_branch(&to.to_string())?;
+        branch_config.set_name(to.to_string());
+        branch_config.close();
+        Ok(())
+    }
+
+    // Uncommitted changes that switching to the branch would throw away
+    pub fn switch_blockers(&self, name: &String) -> Result<Vec<RepoPath>, ()> {
+        let current_config = self.get_branch(&self.current_branch)?;
+        let target_config = self.get_branch(name)?;
+        let current_tree = self.get_tree(&current_config, current_config.current_commit)?;
+        let target_tree = self.get_tree(&target_config, target_config.current_commit)?;
+        Ok(self.checkout_blockers(&current_tree, &target_tree))
+    }
+
+    // Changes of the working tree against current_tree that checking out target_tree would throw away
+    pub(crate) fn checkout_blockers(&self, current_tree: &Tree, target_tree: &Tree) -> Vec<RepoPath> {
+        let mut blockers: Vec<RepoPath> = vec![];
+        for change in compare(current_tree, &self.get_working_tree()) {
+            // untracked entries only matter if the target has something else at their place
+            let overwritten = change.kind == ChangeKind::Added
+                && target_tree.contains(&change.path)
+                && !same_entry(change.new.as_ref(), target_tree.get(&change.path));
+            if change.kind != ChangeKind::Added || overwritten {
+                blockers.push(change.path);
+            }
+        }
+        blockers
+    }
+
+    // Replaces the working tree with the current commit of another branch, callers are expected to check switch_blockers first
+    pub fn switch_branch(&mut self, name: &String) -> Result<(), ()> {
+        if self.closed {
+            return Err(());
+        }
+        if *name == self.current_branch {
+            dprintln!("[WARN] Already on branch {}", name);
+            return Err(());
+        }
+        if let Some(operation) = self.pending_operation() {
+            dprintln!("[ERROR] A {} is in progress", operation);
+            return Err(());
+        }
+        if !self.staged_files.is_empty() || !self.staged_removals.is_empty() {
+            dprintln!("[ERROR] Commit or unstage the staged files before switching branches");
+            return Err(());
+        }
+
+        let current_config = self.get_branch(&self.current_branch)?;
+        let target_config = self.get_branch(name)?;
+        let current_tree = self.get_tree(&current_config, current_config.current_commit)?;
+        let target_tree = self.get_tree(&target_config, target_config.current_commit)?;
+        self.checkout_tree(&current_tree, &target_tree)?;
+
+        self.current_branch = name.clone();
+        self.modified = true;
+        Ok(())
+    }
+
+    pub fn remove_branch(&mut self, name: &String) -> Result<(), ()> {
+        if self.closed {
+            return Err(());
+        }
+        if !self.branches.contains(name) {
+            dprintln!("[ERROR] Branch {} does not exist", name);
+            return Err(());
+        }
+        if *name == self.current_branch {
+            dprintln!("[ERROR] Cannot remove the current branch {}", name);
+            return Err(());
+        }
+
+        let branch_dir = self.repos_dir.join(name);
+        if let Err(e) = std::fs::remove_dir_all(&branch_dir) {
+            dprintln!("[ERROR] Cannot remove branch directory {}: {}", branch_dir.display(), e);
+            return Err(());
+        }
+        self.remove_reflog(name);
+        self.branches.retain(|branch| branch != name);
+        self.modified = true;
+        self.prune_chunks();
+        Ok(())
+    }
+
     pub fn close(&mut self) {
         if self.closed || !self.modified {
             return;
@@ -387,7 +1134,7 @@ impl Repo {
             Err(e) => panic!("Cannot create metafile {}: {}", DEFAULT_CONFIG_FILE, e)
         };
 
-        write!(metafile, "{}\n", CURRENT_CONFIG_VERSION).unwrap();
+        writeln!(metafile, "{}", CURRENT_CONFIG_VERSION).unwrap();
         write!(metafile, "{}", config_str).unwrap();
         //dprintln!("[INFO] Metafile {} updated!", DEFAULT_CONFIG_FILE);
 
@@ -399,10 +1146,10 @@ impl Repo {
         }
 
         for branch in self.branches.iter() {
-            if !self.repos_dir.join(&branch).exists() {
-                match std::fs::create_dir(&self.repos_dir.join(&branch)) {
-                    Ok(_) => dprintln!("[INFO] Repository directory {} created!", self.repos_dir.join(&branch).display()),
-                    Err(e) => panic!("Cannot create repository directory {}: {}", self.repos_dir.join(&branch).display(), e)
+            if !self.repos_dir.join(branch).exists() {
+                match std::fs::create_dir(self.repos_dir.join(branch)) {
+                    Ok(_) => dprintln!("[INFO] Repository directory {} created!", self.repos_dir.join(branch).display()),
+                    Err(e) => panic!("Cannot create repository directory {}: {}", self.repos_dir.join(branch).display(), e)
                 }
                 BranchConfig::new(branch.clone(), &self.repos_dir);
             }
@@ -418,3 +1165,40 @@ impl Drop for Repo {
         self.close();
     }
 }
+
+// Writes a single tree entry to destination, replacing whatever is in the way
+pub(crate) fn restore_entry(entry: &TreeEntry, destination: &Path) -> Result<(), ()> {
+    let existing_kind = EntryKind::of(destination);
+    if existing_kind.is_some() && existing_kind != Some(entry.kind) {
+        if let Err(e) = remove_entry(destination) {
+            dprintln!("[ERROR] Cannot remove {}: {}", destination.display(), e);
+            return Err(());
+        }
+    }
+
+    if entry.kind == EntryKind::Directory {
+        if let Err(e) = std::fs::create_dir_all(destination) {
+            dprintln!("[ERROR] Cannot create restore directory {}: {}", destination.display(), e);
+            return Err(());
+        }
+        return Ok(());
+    }
+
+    if let Some(parent) = destination.parent() {
+        if let Err(e) = std::fs::create_dir_all(parent) {
+            dprintln!("[ERROR] Cannot create restore directory {}: {}", parent.display(), e);
+            return Err(());
+        }
+    }
+    dprintln!("[INFO] Copying commit file {} to restore directory {}...", entry.source.display(), destination.display());
+    let result = match (entry.kind, &entry.chunks) {
+        (EntryKind::Symlink, _) => copy_symlink(&entry.source, destination),
+        (_, Some(chunk_store)) => chunk_store.restore_file(&entry.source, destination),
+        (_, None) => std::fs::copy(&entry.source, destination).map(|_| ())
+    };
+    if let Err(e) = result {
+        dprintln!("[ERROR] Failed to copy file to restore directory {}: {}", entry.source.display(), e);
+        return Err(());
+    }
+    Ok(())
+}
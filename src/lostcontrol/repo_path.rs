@@ -0,0 +1,151 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use serde::{self, Deserialize, Deserializer, Serialize, Serializer};
+
+// A path as stored in the metafile, branch configs and commits.
+// The raw bytes are kept, so names that aren't valid UTF-8 survive a round trip. They are written
+// as UTF-8 text with invalid bytes, control characters and '%' itself escaped as %XX.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RepoPath {
+    bytes: Vec<u8>,
+}
+
+impl RepoPath {
+    pub fn from_path(path: &Path) -> RepoPath {
+        RepoPath {
+            bytes: path.as_os_str().as_encoded_bytes().to_vec()
+        }
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> RepoPath {
+        RepoPath {
+            bytes
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn to_path_buf(&self) -> PathBuf {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(OsStr::from_bytes(&self.bytes))
+    }
+
+    // Names that aren't valid UTF-8 can't be represented on other platforms anyway
+    #[cfg(not(unix))]
+    pub fn to_path_buf(&self) -> PathBuf {
+        PathBuf::from(String::from_utf8_lossy(&self.bytes).into_owned())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // True if self lies somewhere below the directory dir
+    pub fn is_inside(&self, dir: &RepoPath) -> bool {
+        self.bytes.len() > dir.bytes.len()
+            && self.bytes.starts_with(&dir.bytes)
+            && self.bytes[dir.bytes.len()] == b'/'
+    }
+
+    pub fn encode(&self) -> String {
+        let mut encoded = String::with_capacity(self.bytes.len());
+        for chunk in self.bytes.utf8_chunks() {
+            for character in chunk.valid().chars() {
+                if character == '%' || character.is_control() {
+                    let mut buffer = [0; 4];
+                    for byte in character.encode_utf8(&mut buffer).bytes() {
+                        encoded.push_str(&format!("%{:02X}", byte));
+                    }
+                }
+                else {
+                    encoded.push(character);
+                }
+            }
+            for byte in chunk.invalid() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        encoded
+    }
+
+    // A '%' that isn't followed by two hex digits is taken literally
+    pub fn decode(encoded: &str) -> RepoPath {
+        let input = encoded.as_bytes();
+        let mut bytes: Vec<u8> = Vec::with_capacity(input.len());
+        let mut index = 0;
+        while index < input.len() {
+            if input[index] == b'%' && index + 2 < input.len()
+                && input[index + 1].is_ascii_hexdigit() && input[index + 2].is_ascii_hexdigit() {
+                let hex = std::str::from_utf8(&input[index + 1..index + 3]).unwrap();
+                bytes.push(u8::from_str_radix(hex, 16).unwrap());
+                index += 3;
+                continue;
+            }
+            bytes.push(input[index]);
+            index += 1;
+        }
+        RepoPath {
+            bytes
+        }
+    }
+}
+
+impl std::fmt::Display for RepoPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.encode())
+    }
+}
+
+impl Serialize for RepoPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.encode())
+    }
+}
+
+impl<'de> Deserialize<'de> for RepoPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<RepoPath, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        Ok(RepoPath::decode(&encoded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RepoPath;
+
+    fn round_trip(bytes: &[u8]) -> String {
+        let path = RepoPath::from_bytes(bytes.to_vec());
+        let encoded = path.encode();
+        assert_eq!(RepoPath::decode(&encoded), path);
+        encoded
+    }
+
+    #[test]
+    fn plain_names_are_kept() {
+        assert_eq!(round_trip(b"src/main.rs"), "src/main.rs");
+        assert_eq!(round_trip("d\u{e9}j\u{e0} vu.txt".as_bytes()), "d\u{e9}j\u{e0} vu.txt");
+    }
+
+    #[test]
+    fn invalid_utf8_is_escaped() {
+        assert_eq!(round_trip(b"n\xFF"), "n%FF");
+        assert_eq!(round_trip(b"\xC3(\xA0"), "%C3(%A0");
+    }
+
+    #[test]
+    fn literal_percent_is_escaped() {
+        assert_eq!(round_trip(b"50%41"), "50%2541");
+        assert_eq!(round_trip(b"%"), "%25");
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        assert_eq!(round_trip(b"line\nbreak"), "line%0Abreak");
+        assert_eq!(round_trip(b"tab\there\r"), "tab%09here%0D");
+    }
+
+    #[test]
+    fn incomplete_escapes_are_literal() {
+        assert_eq!(RepoPath::decode("100%"), RepoPath::from_bytes(b"100%".to_vec()));
+        assert_eq!(RepoPath::decode("%zz"), RepoPath::from_bytes(b"%zz".to_vec()));
+    }
+}
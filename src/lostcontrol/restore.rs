@@ -0,0 +1,226 @@
+use std::path::{Path, PathBuf};
+
+use crate::lostcontrol::{Repo, RepoPath, dprintln};
+use crate::lostcontrol::fs_operations::copy_symlink;
+use crate::lostcontrol::repo::restore_entry;
+use crate::lostcontrol::tree::{EntryKind, Tree, TreeEntry};
+
+pub(crate) const BACKUP_DIR: &str = "backup";
+const BACKUP_TIME_FORMAT_STRING: &str = "%Y%m%d-%H%M%S-%6f";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreAction {
+    Create,
+    Overwrite,
+    Delete,
+}
+
+impl std::fmt::Display for RestoreAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RestoreAction::Create => write!(f, "create"),
+            RestoreAction::Overwrite => write!(f, "overwrite"),
+            RestoreAction::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+// A single change a restore makes on disk
+pub struct RestoreStep {
+    pub action: RestoreAction,
+    pub path: RepoPath,
+    // only used to show directories with a trailing slash
+    pub kind: EntryKind,
+}
+
+impl std::fmt::Display for RestoreStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.kind {
+            EntryKind::Directory => write!(f, "{} {}/", self.action, self.path),
+            _ => write!(f, "{} {}", self.action, self.path)
+        }
+    }
+}
+
+// What checkout_tree would do below dir, entries already matching the target are left out
+fn restore_plan(current_tree: &Tree, target_tree: &Tree, dir: &Path) -> Vec<RestoreStep> {
+    let mut steps: Vec<RestoreStep> = vec![];
+    for (path, entry) in current_tree.iter() {
+        if target_tree.contains(path) {
+            continue;
+        }
+        // the same cases checkout_tree removes, directories only go away if nothing untracked is left inside
+        let deleted = match (entry.kind, EntryKind::of(&dir.join(path.to_path_buf()))) {
+            (_, None) => false,
+            (EntryKind::Directory, Some(EntryKind::Directory)) => true,
+            (_, Some(EntryKind::Directory)) | (EntryKind::Directory, _) => false,
+            (_, Some(_)) => true
+        };
+        if deleted {
+            steps.push(RestoreStep { action: RestoreAction::Delete, path: path.clone(), kind: entry.kind });
+        }
+    }
+    for (path, entry) in target_tree.iter() {
+        let on_disk = dir.join(path.to_path_buf());
+        let action = match EntryKind::of(&on_disk) {
+            None => RestoreAction::Create,
+            Some(kind) => {
+                let existing = TreeEntry {
+                    kind,
+                    source: on_disk,
+                    chunks: None,
+                };
+                if existing.same_content(entry) {
+                    continue;
+                }
+                RestoreAction::Overwrite
+            }
+        };
+        steps.push(RestoreStep { action, path: path.clone(), kind: entry.kind });
+    }
+    steps.sort_by(|left, right| left.path.cmp(&right.path));
+    steps
+}
+
+fn tree_of(entries: Vec<(RepoPath, TreeEntry)>) -> Tree {
+    let mut tree = Tree::new();
+    for (path, entry) in entries.into_iter() {
+        tree.insert(path, entry);
+    }
+    tree
+}
+
+impl Repo {
+    // The entries of a commit on the current branch that are at or below the passed paths.
+    // Every path has to be part of the commit
+    pub(crate) fn commit_entries(&self, commit_id: usize, paths: &[PathBuf]) -> Result<Vec<(RepoPath, TreeEntry)>, ()> {
+        let branch_config = self.get_branch(&self.current_branch)?;
+        if branch_config.get_commit(commit_id).is_none() {
+            dprintln!("[ERROR] Commit {} does not exist on branch {}", commit_id, branch_config.name);
+            return Err(());
+        }
+        let tree = self.get_tree(&branch_config, commit_id)?;
+
+        let mut entries: Vec<(RepoPath, TreeEntry)> = vec![];
+        for path in paths.iter() {
+            let (_, key) = self.resolve_path(path)?;
+            let selected: Vec<(RepoPath, TreeEntry)> = tree.iter()
+                .filter(|(entry_path, _)| key.is_empty() || **entry_path == key || entry_path.is_inside(&key))
+                .map(|(entry_path, entry)| (entry_path.clone(), entry.clone()))
+                .collect();
+            if selected.is_empty() {
+                dprintln!("[ERROR] {} is not part of commit {}", key, commit_id);
+                return Err(());
+            }
+            for (entry_path, entry) in selected.into_iter() {
+                if !entries.iter().any(|(existing, _)| *existing == entry_path) {
+                    entries.push((entry_path, entry));
+                }
+            }
+        }
+        // directories before their contents
+        entries.sort_by(|(left, _), (right, _)| left.cmp(right));
+        Ok(entries)
+    }
+
+    // The trees of the current commit and of the commit to restore
+    fn restore_trees(&self, commit_id: usize) -> Result<(Tree, Tree), ()> {
+        let branch_config = self.get_branch(&self.current_branch)?;
+        if branch_config.get_commit(commit_id).is_none() {
+            dprintln!("[ERROR] Commit {} does not exist on branch {}", commit_id, branch_config.name);
+            return Err(());
+        }
+        Ok((self.get_tree(&branch_config, branch_config.current_commit)?, self.get_tree(&branch_config, commit_id)?))
+    }
+
+    // Every entry restore_commit would create, overwrite or delete
+    pub fn restore_commit_plan(&self, commit_id: usize) -> Result<Vec<RestoreStep>, ()> {
+        let (head_tree, target_tree) = self.restore_trees(commit_id)?;
+        Ok(restore_plan(&head_tree, &target_tree, self.root_dir()))
+    }
+
+    // Entries that differ from the current commit and would be overwritten or deleted by restoring the commit
+    pub fn restore_commit_blockers(&self, commit_id: usize) -> Result<Vec<RepoPath>, ()> {
+        let (head_tree, target_tree) = self.restore_trees(commit_id)?;
+        Ok(self.checkout_blockers(&head_tree, &target_tree))
+    }
+
+    // Every entry restore_paths would create or overwrite, below destination or the working directory
+    pub fn restore_paths_plan(&self, commit_id: usize, paths: &[PathBuf], destination: Option<&Path>) -> Result<Vec<RestoreStep>, ()> {
+        let target_tree = tree_of(self.commit_entries(commit_id, paths)?);
+        Ok(restore_plan(&Tree::new(), &target_tree, destination.unwrap_or(self.root_dir())))
+    }
+
+    // Entries of the working directory that differ from the current commit and would be overwritten by restore_paths.
+    // Entries deleted from the working directory are only created again, nothing of them is lost
+    pub fn restore_paths_blockers(&self, commit_id: usize, paths: &[PathBuf]) -> Result<Vec<RepoPath>, ()> {
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let head_tree = self.get_tree(&branch_config, branch_config.current_commit)?;
+        let target_tree = tree_of(self.commit_entries(commit_id, paths)?);
+        Ok(self.checkout_blockers(&head_tree, &target_tree).into_iter()
+            .filter(|path| target_tree.contains(path) && EntryKind::of(&self.root_dir().join(path.to_path_buf())).is_some())
+            .collect())
+    }
+
+    // Copies the passed entries below dir, or the working directory, into a new directory below .lostcontrol/backup,
+    // directories and entries that don't exist are skipped. Returns the backup directory
+    pub fn backup_working_entries(&self, paths: &[RepoPath], dir: Option<&Path>) -> Result<PathBuf, ()> {
+        let source_dir = dir.unwrap_or(self.root_dir());
+        // two backups within the same microsecond still get their own directory
+        let name = chrono::Local::now().format(BACKUP_TIME_FORMAT_STRING).to_string();
+        let mut backup_dir = self.repos_dir().join(BACKUP_DIR).join(&name);
+        let mut suffix = 1;
+        while backup_dir.exists() {
+            backup_dir = self.repos_dir().join(BACKUP_DIR).join(format!("{}-{}", name, suffix));
+            suffix += 1;
+        }
+        for path in paths.iter() {
+            let source = source_dir.join(path.to_path_buf());
+            let destination = backup_dir.join(path.to_path_buf());
+            let kind = match EntryKind::of(&source) {
+                Some(EntryKind::Directory) | None => continue,
+                Some(kind) => kind
+            };
+            if let Some(parent) = destination.parent() {
+                if let Err(e) = std::fs::create_dir_all(parent) {
+                    dprintln!("[ERROR] Cannot create backup directory {}: {}", parent.display(), e);
+                    return Err(());
+                }
+            }
+            let result = match kind {
+                EntryKind::Symlink => copy_symlink(&source, &destination),
+                _ => std::fs::copy(&source, &destination).map(|_| ())
+            };
+            if let Err(e) = result {
+                dprintln!("[ERROR] Cannot back up {}: {}", source.display(), e);
+                return Err(());
+            }
+        }
+        Ok(backup_dir)
+    }
+
+    // Writes the passed files and directories as they were in the commit into the working tree,
+    // or below destination, keeping their paths relative to the repository root.
+    // Callers are expected to check restore_paths_blockers first. Returns the restored entries
+    pub fn restore_paths(&self, commit_id: usize, paths: &[PathBuf], destination: Option<&Path>) -> Result<Vec<RepoPath>, ()> {
+        let entries = self.commit_entries(commit_id, paths)?;
+        let target_dir = destination.unwrap_or(self.root_dir());
+        for (path, entry) in entries.iter() {
+            restore_entry(entry, &target_dir.join(path.to_path_buf()))?;
+        }
+        Ok(entries.into_iter().map(|(path, _)| path).collect())
+    }
+
+    // The stored contents of a single file of the commit
+    pub fn read_committed_file(&self, commit_id: usize, path: &Path) -> Result<Vec<u8>, ()> {
+        let entries = self.commit_entries(commit_id, &[path.to_path_buf()])?;
+        let (key, entry) = &entries[0];
+        if entries.len() > 1 || entry.kind == EntryKind::Directory {
+            dprintln!("[ERROR] {} is a directory in commit {}", key, commit_id);
+            return Err(());
+        }
+        entry.read().map_err(|e| {
+            dprintln!("[ERROR] Cannot read {} of commit {}: {}", key, commit_id, e);
+        })
+    }
+}
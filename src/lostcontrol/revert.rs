@@ -0,0 +1,135 @@
+use serde::{self, Deserialize, Serialize};
+
+use crate::lostcontrol::{BranchConfig, Commit, Repo, RepoPath, dprintln};
+use crate::lostcontrol::tree::{compare, same_entry, Tree};
+
+// A revert that stopped because of conflicts, kept in the metafile until it is continued or aborted
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingRevert {
+    pub commit_id: usize,
+    pub conflicts: Vec<RepoPath>,
+}
+
+fn revert_message(commit: &Commit) -> String {
+    format!("Revert \"{}\"", commit.message)
+}
+
+impl Repo {
+    // The trees before and after the commit, in that order
+    fn revert_trees(&self, branch_config: &BranchConfig, commit_id: usize) -> Result<(Tree, Tree), ()> {
+        let parent_id = match branch_config.get_parent_id(commit_id) {
+            Some(parent_id) => parent_id,
+            None => {
+                dprintln!("[ERROR] Commit {} does not exist on branch {}", commit_id, branch_config.name);
+                return Err(());
+            }
+        };
+        Ok((self.get_tree(branch_config, parent_id)?, self.get_tree(branch_config, commit_id)?))
+    }
+
+    // Entries touched by the commit that have uncommitted changes, a revert would mix them into its commit
+    pub fn revert_blockers(&self, commit_id: usize) -> Result<Vec<RepoPath>, ()> {
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let (parent_tree, commit_tree) = self.revert_trees(&branch_config, commit_id)?;
+        let head_tree = self.get_tree(&branch_config, branch_config.current_commit)?;
+
+        let mut blockers: Vec<RepoPath> = vec![];
+        for change in compare(&parent_tree, &commit_tree) {
+            if !same_entry(self.working_entry(&change.path).as_ref(), head_tree.get(&change.path)) {
+                blockers.push(change.path);
+            }
+        }
+        Ok(blockers)
+    }
+
+    // Undoes the changes of a commit in the working tree and commits the result.
+    // Returns the entries that could not be reverted cleanly, the revert is then pending and nothing is committed.
+    // Callers are expected to check revert_blockers first
+    pub fn revert(&mut self, commit_id: usize) -> Result<Vec<RepoPath>, ()> {
+        if let Some(operation) = self.pending_operation() {
+            dprintln!("[ERROR] A {} is already in progress", operation);
+            return Err(());
+        }
+        if !self.staged_files.is_empty() || !self.staged_removals.is_empty() {
+            dprintln!("[ERROR] Commit or unstage the staged files before reverting");
+            return Err(());
+        }
+
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let (parent_tree, commit_tree) = self.revert_trees(&branch_config, commit_id)?;
+        let message = revert_message(branch_config.get_commit(commit_id).unwrap());
+        drop(branch_config);
+
+        let outcome = self.apply_changes(&commit_tree, &parent_tree, "current", &format!("revert of commit {}", commit_id))?;
+        if outcome.changed_files.is_empty() && outcome.removed_files.is_empty() && outcome.conflicts.is_empty() {
+            dprintln!("[WARN] The changes of commit {} are already undone", commit_id);
+            return Err(());
+        }
+
+        self.set_staged_files(outcome.changed_files);
+        self.staged_removals = outcome.removed_files;
+        self.pending_revert = Some(PendingRevert {
+            commit_id,
+            conflicts: outcome.conflicts.clone(),
+        });
+        if outcome.conflicts.is_empty() {
+            self.commit(message)?;
+        }
+        Ok(outcome.conflicts)
+    }
+
+    // Conflicting entries of the pending revert that were not staged yet or still contain conflict markers
+    pub fn revert_unresolved(&self) -> Vec<RepoPath> {
+        match &self.pending_revert {
+            Some(pending) => self.unresolved_conflicts(&pending.conflicts),
+            None => vec![]
+        }
+    }
+
+    // Commits a pending revert once its conflicts are resolved and staged, callers are expected to check revert_unresolved first
+    pub fn revert_continue(&mut self) -> Result<usize, ()> {
+        let pending = match &self.pending_revert {
+            Some(pending) => pending.clone(),
+            None => {
+                dprintln!("[ERROR] No revert in progress");
+                return Err(());
+            }
+        };
+        let commit_id = pending.commit_id;
+        self.stage_resolved_removals(&pending.conflicts);
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let message = match branch_config.get_commit(commit_id) {
+            Some(commit) => revert_message(commit),
+            None => format!("Revert commit {}", commit_id)
+        };
+        drop(branch_config);
+        self.commit(message)
+    }
+
+    // Puts every entry the pending revert touched back to the state of the current commit
+    pub fn revert_abort(&mut self) -> Result<(), ()> {
+        let commit_id = match &self.pending_revert {
+            Some(pending) => pending.commit_id,
+            None => {
+                dprintln!("[ERROR] No revert in progress");
+                return Err(());
+            }
+        };
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let head_tree = self.get_tree(&branch_config, branch_config.current_commit)?;
+        let changes = match self.revert_trees(&branch_config, commit_id) {
+            Ok((parent_tree, commit_tree)) => compare(&parent_tree, &commit_tree),
+            // the reverted commit is gone, only the staged entries are known to be touched
+            Err(()) => vec![]
+        };
+        drop(branch_config);
+
+        let mut touched: Vec<RepoPath> = changes.into_iter().map(|change| change.path).collect();
+        touched.extend(self.staged_files.iter().cloned());
+        touched.extend(self.staged_removals.iter().cloned());
+        self.reset_working_entries(&touched, &head_tree)?;
+        self.unstage_all();
+        self.pending_revert = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,293 @@
+use std::path::{Path, PathBuf};
+
+use crate::lostcontrol::{RenamedFile, Repo, RepoPath, dprintln};
+use crate::lostcontrol::fs_operations::entry_exists;
+use crate::lostcontrol::tree::{same_entry, ChangeKind, EntryKind, Tree};
+
+// How many entries stage_changes staged of each kind
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StageCounts {
+    pub added: usize,
+    pub modified: usize,
+    pub removed: usize,
+}
+
+fn at_or_below(path: &RepoPath, key: &RepoPath) -> bool {
+    key.is_empty() || path == key || path.is_inside(key)
+}
+
+impl Repo {
+    fn head_tree(&self) -> Result<Tree, ()> {
+        let branch_config = self.get_branch(&self.current_branch)?;
+        self.get_tree(&branch_config, branch_config.current_commit)
+    }
+
+    // Tracked entries at or below the passed paths whose working tree version differs from the current commit,
+    // removing them from the working tree would lose these changes
+    pub fn removal_blockers(&self, paths: &[PathBuf]) -> Result<Vec<RepoPath>, ()> {
+        let head_tree = self.head_tree()?;
+        let mut blockers: Vec<RepoPath> = vec![];
+        for path in paths.iter() {
+            let (_, key) = self.resolve_path(path)?;
+            for (entry_path, entry) in head_tree.iter() {
+                if at_or_below(entry_path, &key)
+                    && !same_entry(Some(entry), self.working_entry(entry_path).as_ref())
+                    && !blockers.contains(entry_path) {
+                    blockers.push(entry_path.clone());
+                }
+            }
+        }
+        Ok(blockers)
+    }
+
+    // Records the entries as deleted for the next commit, anything staged at or below them is unstaged
+    fn stage_removal_keys(&mut self, keys: &[RepoPath]) {
+        let mut staged_files = self.staged_files.clone();
+        staged_files.retain(|file| !keys.iter().any(|key| at_or_below(file, key)));
+        self.set_staged_files(staged_files);
+
+        let mut staged_removals = self.staged_removals.clone();
+        for key in keys.iter() {
+            // a removed directory takes everything inside it along
+            staged_removals.retain(|removal| !removal.is_inside(key));
+            if !staged_removals.iter().any(|removal| at_or_below(key, removal)) {
+                staged_removals.push(key.clone());
+                dprintln!("[INFO] Removal of {} staged!", key);
+            }
+        }
+        self.staged_removals = staged_removals;
+        self.prune_index();
+    }
+
+    // Stages the deletion of tracked entries and, unless keep_working is set, deletes them from the working tree.
+    // Callers are expected to check removal_blockers first. Returns the entries whose removal was staged
+    pub fn stage_removals(&mut self, paths: &[PathBuf], keep_working: bool) -> Result<Vec<RepoPath>, ()> {
+        let head_tree = self.head_tree()?;
+        let mut keys: Vec<RepoPath> = vec![];
+        for path in paths.iter() {
+            let (_, key) = self.resolve_path(path)?;
+            if key.is_empty() || !head_tree.contains(&key) {
+                dprintln!("[WARN] {} is not tracked in the current commit, skipping...", path.display());
+                continue;
+            }
+            keys.push(key);
+        }
+        if keys.is_empty() {
+            return Err(());
+        }
+        self.stage_removal_keys(&keys);
+
+        if !keep_working {
+            // contents before their directories, untracked files keep a directory alive
+            for (entry_path, _) in head_tree.iter().rev() {
+                if keys.iter().any(|key| at_or_below(entry_path, key)) {
+                    self.remove_working_entry(entry_path)?;
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    // Stages the removal of tracked entries at or below the keys that are gone from the working tree.
+    // Returns the entries whose removal was staged
+    pub(crate) fn stage_vanished(&mut self, keys: &[RepoPath]) -> Result<Vec<RepoPath>, ()> {
+        let head_tree = self.head_tree()?;
+        let mut vanished: Vec<RepoPath> = vec![];
+        for (entry_path, _) in head_tree.iter() {
+            if !keys.iter().any(|key| at_or_below(entry_path, key)) || self.working_entry(entry_path).is_some() {
+                continue;
+            }
+            // only the topmost missing entry is recorded, its contents go with it
+            if vanished.iter().any(|missing| entry_path.is_inside(missing)) || self.is_ignored(entry_path) {
+                continue;
+            }
+            vanished.push(entry_path.clone());
+        }
+        vanished.retain(|missing| !self.staged_removals.iter().any(|removal| at_or_below(missing, removal)));
+        if !vanished.is_empty() {
+            self.stage_removal_keys(&vanished);
+        }
+        Ok(vanished)
+    }
+
+    // Moves an entry in the working tree and stages the move, into the directory if to is an existing one
+    pub fn stage_rename(&mut self, from: &Path, to: &Path) -> Result<RenamedFile, ()> {
+        let (from_path, from_key) = self.resolve_path(from)?;
+        let (mut to_path, mut to_key) = self.resolve_path(to)?;
+        if from_key.is_empty() || !entry_exists(&from_path) {
+            dprintln!("[ERROR] {} does not exist", from.display());
+            return Err(());
+        }
+        if to_path.is_dir() {
+            let file_name = match from_path.file_name() {
+                Some(file_name) => file_name,
+                None => return Err(())
+            };
+            to_path = to_path.join(file_name);
+            to_key = self.resolve_path(&to_path)?.1;
+        }
+        if entry_exists(&to_path) {
+            dprintln!("[ERROR] {} already exists", to_path.display());
+            return Err(());
+        }
+        if to_key == from_key || to_key.is_inside(&from_key) {
+            dprintln!("[ERROR] Cannot move {} into itself", from_key);
+            return Err(());
+        }
+        if self.is_ignored(&to_key) {
+            dprintln!("[ERROR] {} is on the ignore list", to_key);
+            return Err(());
+        }
+
+        if let Some(parent) = to_path.parent() {
+            if let Err(e) = std::fs::create_dir_all(parent) {
+                dprintln!("[ERROR] Cannot create directory {}: {}", parent.display(), e);
+                return Err(());
+            }
+        }
+        if let Err(e) = std::fs::rename(&from_path, &to_path) {
+            dprintln!("[ERROR] Cannot move {} to {}: {}", from_path.display(), to_path.display(), e);
+            return Err(());
+        }
+
+        let tracked = self.head_tree()?.contains(&from_key);
+        // moving an entry that was already moved only changes where it ends up, staging below may drop renames
+        let earlier_rename = self.staged_renames.iter()
+            .position(|renamed_file| renamed_file.to == from_key)
+            .map(|index| self.staged_renames.remove(index));
+        if tracked {
+            self.stage_removal_keys(std::slice::from_ref(&from_key));
+        }
+        else {
+            // a new entry that was never committed just gets staged under its new name
+            let mut staged_files = self.staged_files.clone();
+            staged_files.retain(|file| !at_or_below(file, &from_key));
+            self.set_staged_files(staged_files);
+        }
+        self.stage_files(&[to_path]);
+
+        let renamed_file = RenamedFile {
+            from: match &earlier_rename {
+                Some(earlier_rename) => earlier_rename.from.clone(),
+                None => from_key
+            },
+            to: to_key,
+        };
+        // moving an entry back to where it came from cancels the move
+        if (tracked || earlier_rename.is_some()) && renamed_file.from != renamed_file.to {
+            self.staged_renames.push(renamed_file.clone());
+        }
+        Ok(renamed_file)
+    }
+
+    // Stages every entry that differs from the current commit, new entries only unless tracked_only is set.
+    // Ignored entries are left out, deleted entries are staged as removed
+    pub fn stage_changes(&mut self, tracked_only: bool) -> Result<StageCounts, ()> {
+        let changes = self.working_changes()?;
+        let mut counts = StageCounts::default();
+        let mut paths: Vec<PathBuf> = vec![];
+        let mut vanished_keys: Vec<RepoPath> = vec![];
+        let mut added_dirs: Vec<RepoPath> = vec![];
+        for change in changes.iter() {
+            match change.kind {
+                ChangeKind::Added if tracked_only => continue,
+                ChangeKind::Added => {
+                    // directories aren't files, what is inside a new one counts by itself
+                    if change.new.as_ref().is_some_and(|entry| entry.kind != EntryKind::Directory) {
+                        counts.added += 1;
+                    }
+                    // staging a new directory takes everything inside it along
+                    if added_dirs.iter().any(|dir| change.path.is_inside(dir)) {
+                        continue;
+                    }
+                    added_dirs.push(change.path.clone());
+                },
+                ChangeKind::Modified => counts.modified += 1,
+                ChangeKind::Removed => {
+                    if !self.is_ignored(&change.path) {
+                        counts.removed += 1;
+                        vanished_keys.push(change.path.clone());
+                    }
+                    continue;
+                }
+            }
+            paths.push(self.root_dir().join(change.path.to_path_buf()));
+        }
+        if !vanished_keys.is_empty() {
+            self.stage_vanished(&vanished_keys)?;
+        }
+        if !paths.is_empty() {
+            self.stage_files(&paths);
+        }
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::lostcontrol::{RenamedFile, RepoPath};
+    use crate::lostcontrol::fs_operations::create_symlink;
+    use crate::lostcontrol::test_support::{commit_entries, open_repo, TestDir};
+
+    fn key(name: &str) -> RepoPath {
+        RepoPath::from_path(Path::new(name))
+    }
+
+    #[test]
+    fn moving_a_staged_move_back_cancels_it() {
+        let test_dir = TestDir::new("staging-move-back");
+        let mut repo = open_repo("test", &test_dir.path);
+        std::fs::write(test_dir.path.join("a"), "hi\n").unwrap();
+        commit_entries(&mut repo, &test_dir.path, &["a"], "first");
+
+        repo.stage_rename(&test_dir.path.join("a"), &test_dir.path.join("b")).unwrap();
+        let renamed_file = repo.stage_rename(&test_dir.path.join("b"), &test_dir.path.join("a")).unwrap();
+        assert_eq!(renamed_file.from, renamed_file.to);
+        assert!(repo.staged_renames.is_empty());
+        assert!(repo.staged_removals.is_empty());
+        assert!(!repo.staged_files.contains(&key("b")));
+        assert_eq!(std::fs::read_to_string(test_dir.path.join("a")).unwrap(), "hi\n");
+        assert!(!test_dir.path.join("b").exists());
+    }
+
+    #[test]
+    fn moving_a_staged_move_again_keeps_its_origin() {
+        let test_dir = TestDir::new("staging-move-twice");
+        let mut repo = open_repo("test", &test_dir.path);
+        std::fs::write(test_dir.path.join("a"), "hi\n").unwrap();
+        commit_entries(&mut repo, &test_dir.path, &["a"], "first");
+
+        repo.stage_rename(&test_dir.path.join("a"), &test_dir.path.join("b")).unwrap();
+        repo.stage_rename(&test_dir.path.join("b"), &test_dir.path.join("c")).unwrap();
+        let expected = RenamedFile {
+            from: key("a"),
+            to: key("c"),
+        };
+        assert_eq!(repo.staged_renames, vec![expected]);
+        assert_eq!(repo.staged_removals, vec![key("a")]);
+        assert_eq!(repo.staged_files, vec![key("c")]);
+    }
+
+    #[test]
+    fn new_directories_are_not_counted_as_files() {
+        let test_dir = TestDir::new("staging-count-added");
+        let mut repo = open_repo("test", &test_dir.path);
+        std::fs::write(test_dir.path.join("a"), "a\n").unwrap();
+        commit_entries(&mut repo, &test_dir.path, &["a"], "first");
+
+        std::fs::create_dir_all(test_dir.path.join("dir/nested")).unwrap();
+        std::fs::create_dir_all(test_dir.path.join("empty")).unwrap();
+        std::fs::write(test_dir.path.join("dir/b"), "b\n").unwrap();
+        std::fs::write(test_dir.path.join("dir/nested/c"), "c\n").unwrap();
+        std::fs::write(test_dir.path.join("d"), "d\n").unwrap();
+        create_symlink(Path::new("d"), &test_dir.path.join("link")).unwrap();
+        std::fs::write(test_dir.path.join("a"), "changed\n").unwrap();
+
+        let counts = repo.stage_changes(false).unwrap();
+        assert_eq!((counts.added, counts.modified, counts.removed), (4, 1, 0));
+        assert!(repo.staged_files.contains(&key("dir/nested/c")));
+        assert!(repo.staged_files.contains(&key("empty")));
+    }
+}
@@ -0,0 +1,462 @@
+use std::path::{Path, PathBuf};
+use std::io::{BufReader, BufRead, Write};
+use serde::{self, Deserialize, Serialize};
+use serde_yaml::{self};
+
+use crate::lostcontrol::{RenamedFile, Repo, RepoPath, CURRENT_CONFIG_VERSION, dprintln};
+use crate::lostcontrol::fs_operations::remove_entry;
+use crate::lostcontrol::repo::restore_entry;
+use crate::lostcontrol::tree::{ChangeKind, EntryKind, Tree};
+
+pub(crate) const STASH_DIR: &str = "stash";
+const STASH_CONFIG_FILE: &str = "stash.conf";
+const TIME_FORMAT_STRING: &str = "%Y-%m-%d %H:%M:%S";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StashEntry {
+    pub id: usize,
+    pub message: String,
+    pub creation_datetime: String,
+    pub branch: String,
+    // the head of the branch when the entry was created
+    pub base_commit: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub staged_files: Vec<RepoPath>,
+    // entries whose working tree contents were saved
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub saved_files: Vec<RepoPath>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub removed_files: Vec<RepoPath>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub chunked_files: Vec<RepoPath>,
+    // staged snapshots of the index, saved next to the entry directory
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub staged_snapshots: Vec<RepoPath>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub chunked_snapshots: Vec<RepoPath>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub staged_removals: Vec<RepoPath>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub staged_renames: Vec<RenamedFile>,
+}
+
+impl StashEntry {
+    pub fn get_time_formatted(&self) -> String {
+        let creation_datetime: chrono::DateTime<chrono::Local> =
+            chrono::DateTime::from(chrono::DateTime::parse_from_rfc3339(&self.creation_datetime).unwrap());
+        creation_datetime.format(TIME_FORMAT_STRING).to_string()
+    }
+}
+
+impl std::fmt::Display for StashEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "On branch: {}\nMessage: {}\nCreated at: {}\nBased on commit: {}",
+            self.branch,
+            self.message,
+            self.get_time_formatted(),
+            self.base_commit,
+        )?;
+        if !self.saved_files.is_empty() {
+            writeln!(f, "Saved:")?;
+            for file in &self.saved_files {
+                writeln!(f, "  {}", file)?;
+            }
+        }
+        if !self.removed_files.is_empty() {
+            writeln!(f, "Deleted:")?;
+            for file in &self.removed_files {
+                writeln!(f, "  {}", file)?;
+            }
+        }
+        if !self.staged_files.is_empty() || !self.staged_removals.is_empty() {
+            writeln!(f, "Staged:")?;
+            for file in &self.staged_files {
+                writeln!(f, "  {}", file)?;
+            }
+            for renamed_file in &self.staged_renames {
+                writeln!(f, "  renamed: {} -> {}", renamed_file.from, renamed_file.to)?;
+            }
+            for file in &self.staged_removals {
+                if !self.staged_renames.iter().any(|renamed_file| renamed_file.from == *file) {
+                    writeln!(f, "  deleted: {}", file)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The list of stashed changes, the newest entry comes first
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Stash {
+    #[serde(skip)]
+    closed: bool,
+    #[serde(skip)]
+    modified: bool,
+    #[serde(skip)]
+    config_path: PathBuf,
+    next_id: usize,
+    entries: Vec<StashEntry>,
+}
+
+impl Stash {
+    pub fn new(config_path: &Path) -> Stash {
+        Stash {
+            closed: false,
+            modified: false,
+            config_path: config_path.to_path_buf(),
+            next_id: 1,
+            entries: vec![],
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Stash, ()> {
+        let mut stash_config = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                dprintln!("[ERROR] Cannot load Stash {}: {}", path.display(), e);
+                return Err(());
+            }
+        };
+        let mut version = String::new();
+        let mut contents = String::new();
+
+        let buf_reader = BufReader::new(&mut stash_config);
+        for (index, line) in buf_reader.lines().enumerate() {
+            match index {
+                0 => version = line.unwrap(),
+                _ => {
+                    let line_string: String = line.unwrap() + "\n";
+                    contents.push_str(line_string.as_str());
+                }
+            }
+        }
+
+        if version != CURRENT_CONFIG_VERSION {
+            dprintln!("[ERROR] Stash {}: Stash version {} is not supported", path.display(), version);
+            return Err(())
+        }
+
+        let mut stash: Stash = match serde_yaml::from_str(&contents) {
+            Ok(stash) => stash,
+            Err(_) => {
+                dprintln!("[ERROR] Stash {}: Cannot parse Stash", path.display());
+                return Err(());
+            }
+        };
+        stash.config_path = path.to_path_buf();
+        Ok(stash)
+    }
+
+    pub fn get_entries(&self) -> &[StashEntry] {
+        &self.entries
+    }
+
+    pub fn get_entry(&self, index: usize) -> Option<&StashEntry> {
+        self.entries.get(index)
+    }
+
+    fn push_entry(&mut self, entry: StashEntry) {
+        self.next_id = entry.id + 1;
+        self.entries.insert(0, entry);
+        self.modified = true;
+    }
+
+    fn remove_entry(&mut self, index: usize) -> Option<StashEntry> {
+        if index >= self.entries.len() {
+            return None;
+        }
+        self.modified = true;
+        Some(self.entries.remove(index))
+    }
+
+    pub fn close(&mut self) {
+        if self.closed || !self.modified {
+            return;
+        }
+        let config_str = serde_yaml::to_string(&self).unwrap();
+        let mut stash_config = match std::fs::File::create(&self.config_path) {
+            Ok(file) => file,
+            Err(e) => panic!("Cannot create Stash {}: {}", self.config_path.display(), e)
+        };
+
+        writeln!(stash_config, "{}", CURRENT_CONFIG_VERSION).unwrap();
+        write!(stash_config, "{}", config_str).unwrap();
+
+        self.closed = true;
+    }
+}
+
+impl Drop for Stash {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Repo {
+    fn stash_dir(&self) -> PathBuf {
+        self.repos_dir().join(STASH_DIR)
+    }
+
+    fn stash_entry_dir(&self, id: usize) -> PathBuf {
+        self.stash_dir().join(format!("stash-{}", id))
+    }
+
+    fn stash_index_dir(&self, id: usize) -> PathBuf {
+        self.stash_dir().join(format!("stash-{}-index", id))
+    }
+
+    fn remove_stash_dirs(&self, id: usize) {
+        for dir in [self.stash_entry_dir(id), self.stash_index_dir(id)] {
+            if dir.exists() {
+                if let Err(e) = std::fs::remove_dir_all(&dir) {
+                    dprintln!("[ERROR] Cannot remove stash directory {}: {}", dir.display(), e);
+                }
+            }
+        }
+    }
+
+    pub fn get_stash(&self) -> Stash {
+        let config_path = self.stash_dir().join(STASH_CONFIG_FILE);
+        if config_path.exists() {
+            if let Ok(stash) = Stash::from_file(&config_path) {
+                return stash;
+            }
+        }
+        Stash::new(&config_path)
+    }
+
+    fn stash_tree(&self, entry: &StashEntry) -> Tree {
+        let mut tree = Tree::new();
+        self.overlay_stored(&mut tree, &self.stash_entry_dir(entry.id), &entry.chunked_files);
+        tree
+    }
+
+    // Entries stashed without anything staged, or by older versions, have no index directory
+    fn stash_index_tree(&self, entry: &StashEntry) -> Tree {
+        let mut tree = Tree::new();
+        if !entry.staged_snapshots.is_empty() {
+            self.overlay_stored(&mut tree, &self.stash_index_dir(entry.id), &entry.chunked_snapshots);
+        }
+        tree
+    }
+
+    // Saves every tracked modification plus staged new entries and resets them to the current commit.
+    // What is staged, including the snapshots in the index, is saved as well and unstaged.
+    // Returns the number of saved entries
+    pub fn stash_push(&mut self, message: Option<String>) -> Result<usize, ()> {
+        let branch_config = self.get_branch(&self.current_branch)?;
+        let head_tree = self.get_tree(&branch_config, branch_config.current_commit)?;
+        let changes: Vec<_> = self.working_changes()?.into_iter()
+            .filter(|change| change.kind != ChangeKind::Added || self.staged_files.contains(&change.path))
+            .collect();
+        if changes.is_empty() && self.staged_files.is_empty() && self.staged_removals.is_empty() {
+            dprintln!("[WARN] No local changes to stash");
+            return Err(());
+        }
+
+        let mut stash = self.get_stash();
+        let stash_id = stash.next_id;
+        let stash_entry_dir = self.stash_entry_dir(stash_id);
+        if let Err(e) = std::fs::create_dir_all(&stash_entry_dir) {
+            dprintln!("[ERROR] Cannot create stash directory {}: {}", stash_entry_dir.display(), e);
+            return Err(());
+        }
+
+        let saved_files: Vec<RepoPath> = changes.iter()
+            .filter(|change| change.kind != ChangeKind::Removed)
+            .map(|change| change.path.clone())
+            .collect();
+        let removed_files: Vec<RepoPath> = changes.iter()
+            .filter(|change| change.kind == ChangeKind::Removed)
+            .map(|change| change.path.clone())
+            .collect();
+        let chunked_files = match self.store_entries(&saved_files, &stash_entry_dir) {
+            Ok(chunked_files) => chunked_files,
+            Err(()) => {
+                self.remove_stash_dirs(stash_id);
+                return Err(());
+            }
+        };
+        let chunked_snapshots = match self.store_staged_entries(&self.staged_snapshots, &self.stash_index_dir(stash_id)) {
+            Ok(chunked_snapshots) => chunked_snapshots,
+            Err(()) => {
+                self.remove_stash_dirs(stash_id);
+                return Err(());
+            }
+        };
+
+        let message = message.unwrap_or_else(|| match branch_config.get_commit(branch_config.current_commit) {
+            Some(commit) => format!("WIP on {}: {} {}", branch_config.name, commit.id, commit.message),
+            None => format!("WIP on {}", branch_config.name)
+        });
+        stash.push_entry(StashEntry {
+            id: stash_id,
+            message,
+            creation_datetime: chrono::Utc::now().to_rfc3339(),
+            branch: branch_config.name.clone(),
+            base_commit: branch_config.current_commit,
+            staged_files: self.staged_files.clone(),
+            saved_files: saved_files.clone(),
+            removed_files,
+            chunked_files,
+            staged_snapshots: self.staged_snapshots.clone(),
+            chunked_snapshots,
+            staged_removals: self.staged_removals.clone(),
+            staged_renames: self.staged_renames.clone(),
+        });
+        stash.close();
+
+        // put the working tree back to the state of the current commit, contents before their directories
+        for change in changes.iter().rev().filter(|change| change.kind == ChangeKind::Added) {
+            self.remove_working_entry(&change.path)?;
+        }
+        for change in changes.iter().filter(|change| change.kind != ChangeKind::Added) {
+            if let Some(entry) = head_tree.get(&change.path) {
+                restore_entry(entry, &self.root_dir().join(change.path.to_path_buf()))?;
+            }
+        }
+        self.unstage_all();
+        Ok(saved_files.len())
+    }
+
+    // Entries that were changed in the working tree since the stash entry was created
+    pub fn stash_conflicts(&self, index: usize) -> Result<Vec<RepoPath>, ()> {
+        let stash = self.get_stash();
+        let entry = match stash.get_entry(index) {
+            Some(entry) => entry,
+            None => {
+                dprintln!("[ERROR] Stash entry {} does not exist", index);
+                return Err(());
+            }
+        };
+        // commit ids are only unique within a branch
+        let branch_config = match self.get_branch(&entry.branch) {
+            Ok(branch_config) => branch_config,
+            Err(()) => {
+                dprintln!("[ERROR] Branch {} of stash entry {} does not exist anymore", entry.branch, index);
+                return Err(());
+            }
+        };
+        if entry.base_commit != 0 && branch_config.get_commit(entry.base_commit).is_none() {
+            dprintln!("[ERROR] Commit {} of branch {} that stash entry {} is based on does not exist anymore", entry.base_commit, entry.branch, index);
+            return Err(());
+        }
+        let base_tree = self.get_tree(&branch_config, entry.base_commit)?;
+        let stash_tree = self.stash_tree(entry);
+
+        let mut conflicts: Vec<RepoPath> = vec![];
+        for path in entry.saved_files.iter().chain(entry.removed_files.iter()) {
+            let working_entry = self.working_entry(path);
+            let unchanged = match (&working_entry, base_tree.get(path)) {
+                (None, None) => true,
+                (Some(working_entry), Some(base_entry)) => working_entry.same_content(base_entry),
+                _ => false
+            };
+            // the working tree may already contain exactly what was stashed
+            let already_applied = match (&working_entry, stash_tree.get(path)) {
+                (None, None) => true,
+                (Some(working_entry), Some(stashed_entry)) => working_entry.same_content(stashed_entry),
+                _ => false
+            };
+            if !unchanged && !already_applied {
+                conflicts.push(path.clone());
+            }
+        }
+        Ok(conflicts)
+    }
+
+    // Writes the stashed entries back into the working tree and stages what was staged before,
+    // with the staged snapshots put back into the index. Callers are expected to check stash_conflicts first
+    pub fn stash_apply(&mut self, index: usize) -> Result<(), ()> {
+        let stash = self.get_stash();
+        let entry = match stash.get_entry(index) {
+            Some(entry) => entry.clone(),
+            None => {
+                dprintln!("[ERROR] Stash entry {} does not exist", index);
+                return Err(());
+            }
+        };
+        drop(stash);
+
+        for path in entry.removed_files.iter().rev() {
+            self.remove_working_entry(path)?;
+        }
+        let stash_tree = self.stash_tree(&entry);
+        for (path, stashed_entry) in stash_tree.iter() {
+            if entry.saved_files.contains(path) {
+                restore_entry(stashed_entry, &self.root_dir().join(path.to_path_buf()))?;
+            }
+        }
+
+        let mut staged_files = self.staged_files.clone();
+        for file in entry.staged_files.iter() {
+            if !staged_files.contains(file) {
+                staged_files.push(file.clone());
+            }
+        }
+        self.set_staged_files(staged_files);
+        for file in entry.staged_removals.iter() {
+            if !self.staged_removals.contains(file) {
+                self.staged_removals.push(file.clone());
+            }
+        }
+        for renamed_file in entry.staged_renames.iter() {
+            if !self.staged_renames.contains(renamed_file) {
+                self.staged_renames.push(renamed_file.clone());
+            }
+        }
+        let index_tree = self.stash_index_tree(&entry);
+        self.restore_snapshots(&index_tree, &entry.staged_snapshots)
+    }
+
+    pub fn stash_drop(&mut self, index: usize) -> Result<StashEntry, ()> {
+        let mut stash = self.get_stash();
+        let entry = match stash.remove_entry(index) {
+            Some(entry) => entry,
+            None => {
+                dprintln!("[ERROR] Stash entry {} does not exist", index);
+                return Err(());
+            }
+        };
+        stash.close();
+        self.remove_stash_dirs(entry.id);
+        self.prune_chunks();
+        Ok(entry)
+    }
+
+    // Manifests of chunked files that are referenced by the stash
+    pub(crate) fn stash_manifests(&self) -> Vec<PathBuf> {
+        let mut manifest_paths: Vec<PathBuf> = vec![];
+        for entry in self.get_stash().get_entries() {
+            let stash_entry_dir = self.stash_entry_dir(entry.id);
+            for file in entry.chunked_files.iter() {
+                manifest_paths.push(stash_entry_dir.join(file.to_path_buf()));
+            }
+            let stash_index_dir = self.stash_index_dir(entry.id);
+            for file in entry.chunked_snapshots.iter() {
+                manifest_paths.push(stash_index_dir.join(file.to_path_buf()));
+            }
+        }
+        manifest_paths
+    }
+
+    // Removes a file or symlink, directories only go away once they are empty
+    pub(crate) fn remove_working_entry(&self, path: &RepoPath) -> Result<(), ()> {
+        let working_path = self.root_dir().join(path.to_path_buf());
+        match EntryKind::of(&working_path) {
+            None => Ok(()),
+            Some(EntryKind::Directory) => {
+                let _ = std::fs::remove_dir(&working_path);
+                Ok(())
+            },
+            Some(_) => match remove_entry(&working_path) {
+                Ok(()) => Ok(()),
+                Err(e) => {
+                    dprintln!("[ERROR] Cannot remove {}: {}", working_path.display(), e);
+                    Err(())
+                }
+            }
+        }
+    }
+}
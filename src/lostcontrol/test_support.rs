@@ -0,0 +1,59 @@
+// Fixtures shared by the unit tests of the lostcontrol modules
+use std::path::{Path, PathBuf};
+
+use crate::lostcontrol::{Repo, RepoPath};
+use crate::lostcontrol::tree::{EntryKind, Tree, TreeEntry};
+
+// A scratch directory below the system temp dir, removed again when dropped
+pub struct TestDir {
+    pub path: PathBuf,
+}
+
+impl TestDir {
+    // name has to be unique across all tests, they run in parallel
+    pub fn new(name: &str) -> TestDir {
+        let path = std::env::temp_dir().join(format!("lostcontrol-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        // the repository root is compared with canonical paths
+        TestDir {
+            path: std::fs::canonicalize(&path).unwrap()
+        }
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+// Initializes a repository the way `init` does and loads it back with its branch config
+pub fn open_repo(name: &str, root: &Path) -> Repo {
+    std::fs::create_dir_all(root).unwrap();
+    drop(Repo::new(name, root).unwrap());
+    Repo::from_file(Some(&root.display().to_string())).unwrap()
+}
+
+// Stages the entries by their names below root and commits them
+pub fn commit_entries(repo: &mut Repo, root: &Path, names: &[&str], message: &str) -> usize {
+    let paths: Vec<PathBuf> = names.iter().map(|name| root.join(name)).collect();
+    repo.stage_files(&paths);
+    repo.commit(message.to_string()).unwrap()
+}
+
+// Writes the files into dir and returns a tree of them
+pub fn tree_with<C: AsRef<[u8]>>(dir: &Path, files: &[(&str, C)]) -> Tree {
+    std::fs::create_dir_all(dir).unwrap();
+    let mut tree = Tree::new();
+    for (name, contents) in files {
+        let source = dir.join(name);
+        std::fs::write(&source, contents).unwrap();
+        tree.insert(RepoPath::from_path(Path::new(name)), TreeEntry {
+            kind: EntryKind::File,
+            source,
+            chunks: None,
+        });
+    }
+    tree
+}
@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+use crate::lostcontrol::RepoPath;
+use crate::lostcontrol::chunks::{ChunkStore, Manifest};
+use crate::lostcontrol::fs_operations::expand_directory;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+impl EntryKind {
+    // Looks at the entry itself, symlinks are not followed
+    pub fn of(path: &Path) -> Option<EntryKind> {
+        let file_type = match std::fs::symlink_metadata(path) {
+            Ok(metadata) => metadata.file_type(),
+            Err(_) => return None
+        };
+        if file_type.is_symlink() {
+            Some(EntryKind::Symlink)
+        }
+        else if file_type.is_dir() {
+            Some(EntryKind::Directory)
+        }
+        else if file_type.is_file() {
+            Some(EntryKind::File)
+        }
+        else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TreeEntry {
+    pub kind: EntryKind,
+    // where the contents live, either in the working tree or in a commit directory
+    pub source: PathBuf,
+    // set for large files, source is then a manifest of chunks in this store
+    pub chunks: Option<ChunkStore>,
+}
+
+impl TreeEntry {
+    // Directories have no contents, symlinks yield their target
+    pub fn read(&self) -> std::io::Result<Vec<u8>> {
+        match self.kind {
+            EntryKind::File => match &self.chunks {
+                Some(chunk_store) => chunk_store.read_file(&self.source),
+                None => std::fs::read(&self.source)
+            },
+            EntryKind::Symlink => {
+                let target = std::fs::read_link(&self.source)?;
+                Ok(target.as_os_str().as_encoded_bytes().to_vec())
+            },
+            EntryKind::Directory => Ok(vec![])
+        }
+    }
+
+    pub fn same_content(&self, other: &TreeEntry) -> bool {
+        if self.kind != other.kind {
+            return false;
+        }
+        match self.kind {
+            EntryKind::Directory => true,
+            EntryKind::Symlink => {
+                std::fs::read_link(&self.source).ok() == std::fs::read_link(&other.source).ok()
+            },
+            EntryKind::File => {
+                match (&self.chunks, &other.chunks) {
+                    (Some(_), Some(_)) => return match (Manifest::from_file(&self.source), Manifest::from_file(&other.source)) {
+                        (Ok(own), Ok(others)) => own.same_contents(&others),
+                        _ => false
+                    },
+                    (Some(chunk_store), None) => return chunk_store.matches(&self.source, &other.source),
+                    (None, Some(chunk_store)) => return chunk_store.matches(&other.source, &self.source),
+                    (None, None) => {}
+                }
+                match (self.read(), other.read()) {
+                    (Ok(own), Ok(others)) => own == others,
+                    _ => false
+                }
+            }
+        }
+    }
+
+    // Directories are shown with a trailing slash
+    pub fn format_path(&self, path: &RepoPath) -> String {
+        match self.kind {
+            EntryKind::Directory => format!("{}/", path),
+            _ => path.to_string()
+        }
+    }
+}
+
+// Turns a path relative to the repository root into a tree key
+pub fn normalize_key(path: &Path) -> RepoPath {
+    let mut parts: Vec<&[u8]> = vec![];
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            component => parts.push(component.as_os_str().as_encoded_bytes())
+        }
+    }
+    RepoPath::from_bytes(parts.join(&b'/'))
+}
+
+// The full set of entries of a commit or of the working directory, keyed by their relative path
+#[derive(Debug, Clone, Default)]
+pub struct Tree {
+    entries: BTreeMap<RepoPath, TreeEntry>,
+}
+
+impl Tree {
+    pub fn new() -> Tree {
+        Tree {
+            entries: BTreeMap::new()
+        }
+    }
+
+    // Adds every entry below dir, replacing entries that are already present
+    pub fn overlay_directory(&mut self, dir: &Path, ignored_dirs: &[PathBuf]) {
+        for entry_path in expand_directory(dir, ignored_dirs) {
+            let kind = match EntryKind::of(&entry_path) {
+                Some(kind) => kind,
+                None => continue
+            };
+            let relative_path = match pathdiff::diff_paths(&entry_path, dir) {
+                Some(relative_path) => relative_path,
+                None => continue
+            };
+            self.insert(normalize_key(&relative_path), TreeEntry {
+                kind,
+                source: entry_path,
+                chunks: None,
+            });
+        }
+    }
+
+    pub fn insert(&mut self, path: RepoPath, entry: TreeEntry) {
+        // an entry replacing a directory also replaces everything that was inside it
+        if entry.kind != EntryKind::Directory {
+            self.remove_children(&path);
+        }
+        self.entries.insert(path, entry);
+    }
+
+    // Removes the entry and, if it is a directory, everything inside it
+    pub fn remove(&mut self, path: &RepoPath) -> Option<TreeEntry> {
+        self.remove_children(path);
+        self.entries.remove(path)
+    }
+
+    fn remove_children(&mut self, path: &RepoPath) {
+        self.entries.retain(|key, _| !key.is_inside(path));
+    }
+
+    pub fn get(&self, path: &RepoPath) -> Option<&TreeEntry> {
+        self.entries.get(path)
+    }
+
+    pub fn get_mut(&mut self, path: &RepoPath) -> Option<&mut TreeEntry> {
+        self.entries.get_mut(path)
+    }
+
+    pub fn contains(&self, path: &RepoPath) -> bool {
+        self.entries.contains_key(path)
+    }
+
+    // Entries are sorted so that directories always come before their contents
+    pub fn iter(&self) -> std::collections::btree_map::Iter<'_, RepoPath, TreeEntry> {
+        self.entries.iter()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Modified,
+    Removed,
+}
+
+#[derive(Debug, Clone)]
+pub struct TreeChange {
+    pub kind: ChangeKind,
+    pub path: RepoPath,
+    pub old: Option<TreeEntry>,
+    pub new: Option<TreeEntry>,
+}
+
+impl TreeChange {
+    pub fn format_path(&self) -> String {
+        match (&self.new, &self.old) {
+            (Some(entry), _) | (None, Some(entry)) => entry.format_path(&self.path),
+            (None, None) => self.path.to_string()
+        }
+    }
+}
+
+// True if both entries are missing or have the same kind and contents
+pub fn same_entry(a: Option<&TreeEntry>, b: Option<&TreeEntry>) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) => a.same_content(b),
+        _ => false
+    }
+}
+
+// Lists every entry that differs between the two trees, sorted by path
+pub fn compare(old: &Tree, new: &Tree) -> Vec<TreeChange> {
+    let mut changes: Vec<TreeChange> = vec![];
+    for (path, old_entry) in old.iter() {
+        match new.get(path) {
+            Some(new_entry) => {
+                if !old_entry.same_content(new_entry) {
+                    changes.push(TreeChange {
+                        kind: ChangeKind::Modified,
+                        path: path.clone(),
+                        old: Some(old_entry.clone()),
+                        new: Some(new_entry.clone()),
+                    });
+                }
+            },
+            None => changes.push(TreeChange {
+                kind: ChangeKind::Removed,
+                path: path.clone(),
+                old: Some(old_entry.clone()),
+                new: None,
+            })
+        }
+    }
+    for (path, new_entry) in new.iter() {
+        if !old.contains(path) {
+            changes.push(TreeChange {
+                kind: ChangeKind::Added,
+                path: path.clone(),
+                old: None,
+                new: Some(new_entry.clone()),
+            });
+        }
+    }
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    changes
+}
@@ -1,9 +1,20 @@
 mod lostcontrol;
 
 //use libfct4::fct_archive::FctArchive;
-use std::path::PathBuf;
-use std::process::exit;
-use lostcontrol::{Repo};
+use std::ffi::OsString;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command};
+use lostcontrol::{CommitSource, Repo, RepoPath};
+use lostcontrol::tree::{compare, ChangeKind, EntryKind, TreeChange};
+use lostcontrol::diff::{format_change, format_numstat, format_rename, format_stat, is_binary, DiffOptions, DiffStyle, FilePatch, FileStat, Hunk, DEFAULT_WIDTH};
+use lostcontrol::rename::{detect_renames, DetectedRename, RenameKind};
+use lostcontrol::rebase::parse_todo;
+use lostcontrol::bisect::{BisectMark, BisectStep};
+use lostcontrol::grep::GrepScope;
+use lostcontrol::restore::{RestoreAction, RestoreStep};
+use lostcontrol::archive::ArchiveFormat;
+use lostcontrol::patch::{patch_file_name, CommitPatch};
 
 /*
 fn test_archive() {
@@ -23,15 +34,32 @@ fn usage(prg_loc: &str) {
     println!("Options:");
     println!("  -i, init <repo name>\t\tInitialize a new repository");
     println!("  -l, list\t\t\tList information about the repository");
+    println!("  status\t\t\tShow staged, modified and untracked entries");
+    println!("  -d, diff (options) (commit) (commit)\tShow changes between commits or the working directory");
     println!("  -h, help\t\t\t\tDisplay this help message");
     println!("  -b, branch <option> <branch name>\tManage branches");
     println!("  -c, commit <option> <commit message>\tManage commits");
     println!("  -s, stage <option> <files>\t\tStage files for commit");
+    println!("  config <key> (value)\t\tShow or change a repository setting");
+    println!("  stash <option> (entry)\t\tShelve uncommitted changes and reapply them later");
+    println!("  revert <commit>|continue|abort\tCreate a commit that undoes the changes of a commit");
+    println!("  cherry-pick <commit>...|continue|abort\tApply commits of another branch to the current one");
+    println!("  rebase (-i) <branch>|continue|abort\tReplay the commits of the current branch on top of another branch");
+    println!("  reflog (branch)|restore <entry> (branch)\tShow or go back to earlier heads of a branch");
+    println!("  bisect <option> (commits)\t\tSearch the history for the commit that introduced a regression");
+    println!("  restore <commit> (options) -- <paths>\tRestore single files or directories of a commit");
+    println!("  archive (commit) -o <file> (--format tar|tar.gz|zip) (--prefix <dir>)\tExport the tree of a commit as an archive");
+    println!("  log (--follow) (path)\t\tShow the commits of the current branch that changed a path, newest first");
+    println!("  format-patch <commit>|<first>..(last) (-o <dir>)\tWrite commits as patch files");
+    println!("  apply-patch <files>\t\t\tApply patch files and commit them with their author, message and time");
+    println!("  grep (options) <pattern> (commit) (-- paths)\tSearch the stored file contents of a commit or the whole history");
+    println!("  blame <file> (commit) (-L start,end)\tShow which commit last changed each line of a file");
+    println!("  undo, redo\t\t\t\tTake back the last stage, commit or branch switch, or apply it again");
 }
 
 fn init_repo(args: Vec<String>){
     if args.len() > 2 {
-        let repo = match Repo::new(&args[2], &PathBuf::new()) {
+        let repo = match Repo::new(&args[2], &std::env::current_dir().unwrap()) {
             Ok(repo) => repo,
             Err(_) => {
                 println!("[ERROR] A repository already exists in this folder! Exiting...");
@@ -80,7 +108,7 @@ fn list_repo(args: Vec<String>){
         println!("      Commits: {}", commits.len());
         println!("      Last updated: {}", last_update_time);
     }
-    if repo.staged_files.len() > 0 {
+    if !repo.staged_files.is_empty() {
         println!("Staged Files:");
         for file in repo.staged_files.iter() {
             println!("    {}", file);
@@ -90,26 +118,1780 @@ fn list_repo(args: Vec<String>){
     exit(0);
 }
 
-fn add_stage_files(repo: &mut Repo, args: Vec<String>){
+fn status_repo(repo: &Repo){
+    let changes = match repo.working_changes() {
+        Ok(changes) => changes,
+        Err(()) => {
+            println!("[ERROR] Failed to compare the working directory with the current commit!");
+            exit(1);
+        }
+    };
+    let staged_keys = &repo.staged_files;
+    // moves recorded with stage mv are shown as they are, the others are found by comparing contents.
+    // A move is staged once both its removal and the new file are, otherwise it is not staged at all
+    let removal_staged = |path: &RepoPath| repo.staged_removals.iter().any(|removal| path == removal || path.is_inside(removal));
+    let candidates: Vec<TreeChange> = changes.iter()
+        .filter(|change| !repo.staged_renames.iter().any(|renamed_file| renamed_file.from == change.path || renamed_file.to == change.path))
+        .cloned()
+        .collect();
+    let (staged_renames, unstaged_renames): (Vec<DetectedRename>, Vec<DetectedRename>) = detect_renames(&candidates, None, repo.rename_threshold).into_iter()
+        .filter(|rename| staged_keys.contains(&rename.to) == removal_staged(&rename.from))
+        .partition(|rename| staged_keys.contains(&rename.to));
+
+    println!("On branch {}", repo.current_branch);
+    if let Some(pending_revert) = &repo.pending_revert {
+        println!("Reverting commit {}, resolve the conflicts and run \"revert continue\" or \"revert abort\"", pending_revert.commit_id);
+    }
+    if repo.rebase_in_progress() {
+        if let Ok(rebase_state) = repo.get_rebase_state() {
+            println!("Rebasing {} onto {} with {} steps left, resolve the conflicts and run \"rebase continue\" or \"rebase abort\"",
+                rebase_state.branch, rebase_state.onto, rebase_state.steps.len());
+        }
+    }
+    if repo.bisect_in_progress() {
+        if let Ok(bisect_state) = repo.get_bisect_state() {
+            println!("Bisecting, the working directory holds commit {}, mark it with \"bisect good\", \"bisect bad\" or \"bisect skip\", or run \"bisect reset\"",
+                bisect_state.current);
+        }
+    }
+    if let Some(pending_cherry_pick) = &repo.pending_cherry_pick {
+        println!("Cherry-picking commit {}, resolve the conflicts and run \"cherry-pick continue\" or \"cherry-pick abort\"", pending_cherry_pick.current);
+    }
+    if !repo.staged_files.is_empty() || !repo.staged_removals.is_empty() {
+        println!("Staged:");
+        for renamed_file in repo.staged_renames.iter() {
+            println!("    renamed: {}", renamed_file);
+        }
+        for rename in staged_renames.iter() {
+            println!("    {}", rename);
+        }
+        for file in repo.staged_files.iter() {
+            if repo.staged_renames.iter().any(|renamed_file| renamed_file.to == *file)
+                || staged_renames.iter().any(|rename| rename.to == *file) {
+                continue;
+            }
+            let suffix = match EntryKind::of(&repo.root_dir().join(file.to_path_buf())) {
+                Some(EntryKind::Directory) => "/",
+                _ => ""
+            };
+            println!("    {}{}", file, suffix);
+        }
+        for file in repo.staged_removals.iter() {
+            if repo.staged_renames.iter().any(|renamed_file| renamed_file.from == *file)
+                || staged_renames.iter().any(|rename| rename.from == *file) {
+                continue;
+            }
+            println!("    deleted: {}", file);
+        }
+    }
+
+    let unstaged_changes: Vec<_> = changes.iter()
+        .filter(|change| change.kind != ChangeKind::Added && !staged_keys.contains(&change.path))
+        .filter(|change| !removal_staged(&change.path))
+        .filter(|change| !unstaged_renames.iter().any(|rename| rename.from == change.path))
+        .collect();
+    // the commit takes the staged snapshot, so later edits are not part of it
+    let changed_since_staged = repo.changed_since_staged();
+    if !unstaged_changes.is_empty() || !changed_since_staged.is_empty() || !unstaged_renames.is_empty() {
+        println!("Changes not staged:");
+        for rename in unstaged_renames.iter() {
+            println!("    {}", rename);
+        }
+        for change in changed_since_staged.iter() {
+            let label = match change.kind {
+                ChangeKind::Removed => "staged, then deleted:",
+                _ => "staged, then modified again:"
+            };
+            println!("    {} {}", label, change.format_path());
+        }
+        for change in unstaged_changes {
+            let label = match change.kind {
+                ChangeKind::Removed => "deleted:",
+                _ => "modified:"
+            };
+            println!("    {} {}", label, change.format_path());
+        }
+    }
+
+    // untracked directories are listed once instead of with all their contents
+    let mut untracked: Vec<&TreeChange> = vec![];
+    for change in changes.iter().filter(|change| change.kind == ChangeKind::Added) {
+        if staged_keys.contains(&change.path) || unstaged_renames.iter().any(|rename| rename.to == change.path) {
+            continue;
+        }
+        if untracked.iter().any(|dir| change.path.is_inside(&dir.path)) {
+            continue;
+        }
+        untracked.push(change);
+    }
+    if !untracked.is_empty() {
+        println!("Untracked:");
+        for change in untracked {
+            println!("    {}", change.format_path());
+        }
+    }
+}
+
+// Colours only make sense on a terminal, NO_COLOR turns them off there as well
+fn use_color(forced: Option<bool>) -> bool {
+    forced.unwrap_or_else(|| std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none())
+}
+
+// Falls back to COLUMNS when stdout is not a terminal, e.g. when piped into a pager
+fn terminal_width() -> usize {
+    match terminal_size::terminal_size() {
+        Some((terminal_size::Width(width), _)) => width as usize,
+        None => std::env::var("COLUMNS").ok()
+            .and_then(|columns| columns.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_WIDTH)
+    }
+}
+
+fn diff_repo(repo: &Repo, args: Vec<String>){
+    let mut commit_ids: Vec<usize> = vec![];
+    let mut options = DiffOptions::default();
+    let mut forced_color: Option<bool> = None;
+    let mut stat = false;
+    let mut numstat = false;
+    for arg in &args[2..] {
+        match arg.as_str() {
+            "--word-diff" => options.style = DiffStyle::Words,
+            "--side-by-side" => options.style = DiffStyle::SideBySide,
+            "--stat" => stat = true,
+            "--numstat" => numstat = true,
+            "--color" => forced_color = Some(true),
+            "--no-color" => forced_color = Some(false),
+            arg => match arg.parse::<usize>() {
+                Ok(commit_id) => commit_ids.push(commit_id),
+                Err(_) => {
+                    println!("[ERROR] Failed to parse commit number!");
+                    exit(1);
+                }
+            }
+        }
+    }
+    if args[2..].iter().filter(|arg| *arg == "--word-diff" || *arg == "--side-by-side" || *arg == "--stat" || *arg == "--numstat").count() > 1 {
+        println!("[ERROR] Only one of --word-diff, --side-by-side, --stat and --numstat can be used at a time!");
+        exit(1);
+    }
+    options.color = use_color(forced_color);
+    options.width = terminal_width();
+    let branch_config = match repo.get_branch(&repo.current_branch) {
+        Ok(branch_config) => branch_config,
+        Err(()) => {
+            println!("[ERROR] Failed to load branch {}!", repo.current_branch);
+            exit(1);
+        }
+    };
+
+    let old_commit = commit_ids.first().copied().unwrap_or(branch_config.current_commit);
+    let old_tree = match repo.get_tree(&branch_config, old_commit) {
+        Ok(tree) => tree,
+        Err(()) => {
+            println!("[ERROR] Failed to get commit {}!", old_commit);
+            exit(1);
+        }
+    };
+    let changes = match commit_ids.get(1) {
+        Some(new_commit) => {
+            match repo.get_tree(&branch_config, *new_commit) {
+                Ok(new_tree) => compare(&old_tree, &new_tree),
+                Err(()) => {
+                    println!("[ERROR] Failed to get commit {}!", new_commit);
+                    exit(1);
+                }
+            }
+        },
+        None => {
+            // untracked entries only show up once they are staged
+            compare(&old_tree, &repo.get_working_tree()).into_iter()
+                .filter(|change| change.kind != ChangeKind::Added || repo.staged_files.contains(&change.path))
+                .collect()
+        }
+    };
+
+    let renames = detect_renames(&changes, Some(&old_tree), repo.rename_threshold);
+    let mut stats: Vec<FileStat> = vec![];
+    for change in changes.iter() {
+        if let Some(rename) = renames.iter().find(|rename| rename.to == change.path) {
+            match stat || numstat {
+                true => stats.push(FileStat::of_rename(rename)),
+                false => print!("{}", format_rename(rename, &options))
+            }
+            continue;
+        }
+        let moved_away = renames.iter().any(|rename| rename.from == change.path && rename.kind == RenameKind::Renamed);
+        if change.kind == ChangeKind::Removed && moved_away {
+            continue;
+        }
+        match stat || numstat {
+            true => stats.extend(FileStat::of_change(change)),
+            false => print!("{}", format_change(change, &options))
+        }
+    }
+    if numstat {
+        print!("{}", format_numstat(&stats));
+    }
+    else if stat && !stats.is_empty() {
+        print!("{}", format_stat(&stats, &options));
+    }
+}
+
+// Paths are taken as raw OS strings so names that aren't valid UTF-8 work,
+// a leading "--" ends option parsing for names starting with a dash
+fn path_args(args: &[OsString]) -> Vec<PathBuf> {
+    let args = match args.first() {
+        Some(first) if first == "--" => &args[1..],
+        _ => args
+    };
+    args.iter().map(PathBuf::from).collect()
+}
+
+// Staging skips paths outside of the repository, tell the user which ones
+fn warn_outside_paths(repo: &Repo, paths: &[PathBuf]){
+    for path in repo.outside_paths(paths) {
+        println!("[WARN] {} is outside of the repository, skipping...", path.display());
+    }
+}
+
+fn check_outside_paths(repo: &Repo, paths: &[PathBuf]){
+    if let Some(path) = repo.outside_paths(paths).first() {
+        println!("[ERROR] {} is outside of the repository!", path.display());
+        exit(1);
+    }
+}
+
+fn parse_stash_index(args: &[String]) -> usize {
+    match args.get(3) {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(index) => index,
+            Err(_) => {
+                println!("[ERROR] Failed to parse stash entry number!");
+                exit(1);
+            }
+        },
+        None => 0
+    }
+}
+
+fn stash_push(repo: &mut Repo, args: Vec<String>){
+    let message = match args.iter().position(|arg| arg == "-m") {
+        Some(position) if position + 1 < args.len() => Some(args[position + 1..].join(" ")),
+        Some(_) => {
+            println!("[ERROR] No stash message specified!");
+            exit(1);
+        },
+        None => None
+    };
+    check_snapshots(repo);
+    match repo.stash_push(message) {
+        Ok(saved_count) => println!("[INFO] Stashed {} entries, the working directory is back at the current commit!", saved_count),
+        Err(()) => {
+            println!("[ERROR] Nothing to stash or failed to save changes!");
+            exit(1);
+        }
+    }
+}
+
+fn stash_list(repo: &Repo){
+    let stash = repo.get_stash();
+    if stash.get_entries().is_empty() {
+        println!("[INFO] The stash is empty");
+        return;
+    }
+    for (index, entry) in stash.get_entries().iter().enumerate() {
+        println!("{}: On {}: {} ({})", index, entry.branch, entry.message, entry.get_time_formatted());
+    }
+}
+
+fn stash_show(repo: &Repo, args: Vec<String>){
+    let index = parse_stash_index(&args);
+    match repo.get_stash().get_entry(index) {
+        Some(entry) => print!("{}", entry),
+        None => {
+            println!("[ERROR] Stash entry {} does not exist!", index);
+            exit(1);
+        }
+    }
+}
+
+fn stash_apply(repo: &mut Repo, args: Vec<String>){
+    let index = parse_stash_index(&args);
+    let conflicts = match repo.stash_conflicts(index) {
+        Ok(conflicts) => conflicts,
+        Err(()) => {
+            println!("[ERROR] Stash entry {} or the commit it is based on does not exist!", index);
+            exit(1);
+        }
+    };
+    if !conflicts.is_empty() {
+        println!("[ERROR] These entries were changed since they were stashed:");
+        for path in conflicts.iter() {
+            println!("    {}", path);
+        }
+        println!("[ERROR] Commit, stash or restore them first, nothing was applied!");
+        repo.close();
+        exit(1);
+    }
+    if repo.stash_apply(index).is_err() {
+        println!("[ERROR] Failed to apply stash entry {}!", index);
+        repo.close();
+        exit(1);
+    }
+    println!("[INFO] Applied stash entry {}!", index);
+    if args[2] == "pop" {
+        stash_drop(repo, args);
+    }
+}
+
+fn stash_drop(repo: &mut Repo, args: Vec<String>){
+    let index = parse_stash_index(&args);
+    match repo.stash_drop(index) {
+        Ok(entry) => println!("[INFO] Dropped stash entry {} ({})", index, entry.message),
+        Err(()) => {
+            println!("[ERROR] Failed to drop stash entry {}!", index);
+            exit(1);
+        }
+    }
+}
+
+fn print_head_commit(repo: &Repo) {
+    let branch_config = repo.get_branch(&repo.current_branch).unwrap();
+    println!("{}", branch_config.get_commit(branch_config.current_commit).unwrap());
+}
+
+fn revert_commit(repo: &mut Repo, args: Vec<String>){
+    let commit_id = match args.get(2).map(|arg| arg.parse::<usize>()) {
+        Some(Ok(commit_id)) => commit_id,
+        Some(Err(_)) => {
+            println!("[ERROR] Failed to parse commit number!");
+            exit(1);
+        },
+        None => {
+            println!("[ERROR] No commit specified!");
+            exit(1);
+        }
+    };
+    if let Some(operation) = repo.pending_operation() {
+        println!("[ERROR] A {} is in progress, continue or abort it first!", operation);
+        exit(1);
+    }
+    if !repo.staged_files.is_empty() || !repo.staged_removals.is_empty() {
+        println!("[ERROR] Commit or unstage the staged files first!");
+        exit(1);
+    }
+    let blockers = match repo.revert_blockers(commit_id) {
+        Ok(blockers) => blockers,
+        Err(()) => {
+            println!("[ERROR] Commit {} does not exist!", commit_id);
+            exit(1);
+        }
+    };
+    if !blockers.is_empty() {
+        println!("[ERROR] These entries have uncommitted changes:");
+        for path in blockers.iter() {
+            println!("    {}", path);
+        }
+        println!("[ERROR] Commit, stash or restore them first, nothing was reverted!");
+        exit(1);
+    }
+
+    match repo.revert(commit_id) {
+        Ok(conflicts) if conflicts.is_empty() => {
+            println!("[INFO] Reverted commit {}!", commit_id);
+            print_head_commit(repo);
+        },
+        Ok(conflicts) => {
+            println!("[WARN] These entries could not be reverted cleanly:");
+            for path in conflicts.iter() {
+                println!("    {}", path);
+            }
+            println!("[WARN] Resolve the conflicts, stage them and run \"revert continue\", or \"revert abort\" to give up!");
+        },
+        Err(()) => {
+            println!("[ERROR] Failed to revert commit {}!", commit_id);
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+fn revert_continue(repo: &mut Repo){
+    if repo.pending_revert.is_none() {
+        println!("[ERROR] No revert in progress!");
+        exit(1);
+    }
+    let unresolved = repo.revert_unresolved();
+    if !unresolved.is_empty() {
+        println!("[ERROR] These entries are not resolved and staged yet:");
+        for path in unresolved.iter() {
+            println!("    {}", path);
+        }
+        exit(1);
+    }
+    match repo.revert_continue() {
+        Ok(_) => {
+            println!("[INFO] Revert committed!");
+            print_head_commit(repo);
+        },
+        Err(()) => {
+            println!("[ERROR] Failed to commit the revert!");
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+fn revert_abort(repo: &mut Repo){
+    match repo.revert_abort() {
+        Ok(()) => println!("[INFO] Revert aborted!"),
+        Err(()) => {
+            println!("[ERROR] No revert in progress or failed to restore the touched entries!");
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+fn branch_list(repo: &Repo){
+    let branches = match repo.get_branches() {
+        Ok(branches) => branches,
+        Err(()) => {
+            println!("[ERROR] Failed to load the branches!");
+            exit(1);
+        }
+    };
+    for branch in branches.iter() {
+        let marker = if branch.name == repo.current_branch { "*" } else { " " };
+        match branch.get_commit(branch.current_commit) {
+            Some(commit) => println!("{} {} ({}: {})", marker, branch.name, commit.id, commit.message),
+            None => println!("{} {} (no commits)", marker, branch.name)
+        }
+    }
+}
+
+fn branch_name_arg(args: &[String]) -> String {
+    match args.get(3) {
+        Some(name) => name.clone(),
+        None => {
+            println!("[ERROR] No branch name specified!");
+            exit(1);
+        }
+    }
+}
+
+fn branch_add(repo: &mut Repo, args: Vec<String>){
+    let name = branch_name_arg(&args);
+    match repo.create_branch(&name) {
+        Ok(()) => println!("[INFO] Created branch {} from {}!", name, repo.current_branch),
+        Err(()) => {
+            println!("[ERROR] Failed to create branch {}, the name is invalid or already taken!", name);
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+fn branch_switch(repo: &mut Repo, args: Vec<String>){
+    let name = branch_name_arg(&args);
+    if !repo.branches.contains(&name) {
+        println!("[ERROR] Branch {} does not exist!", name);
+        exit(1);
+    }
+    if let Some(operation) = repo.pending_operation() {
+        println!("[ERROR] A {} is in progress, continue or abort it first!", operation);
+        exit(1);
+    }
+    if !repo.staged_files.is_empty() || !repo.staged_removals.is_empty() {
+        println!("[ERROR] Commit or unstage the staged files first!");
+        exit(1);
+    }
+    let blockers = match repo.switch_blockers(&name) {
+        Ok(blockers) => blockers,
+        Err(()) => {
+            println!("[ERROR] Failed to compare the working directory with branch {}!", name);
+            exit(1);
+        }
+    };
+    if !blockers.is_empty() {
+        println!("[ERROR] These entries have uncommitted changes or would be overwritten:");
+        for path in blockers.iter() {
+            println!("    {}", path);
+        }
+        println!("[ERROR] Commit, stash or remove them first, the branch was not switched!");
+        exit(1);
+    }
+    match repo.switch_branch(&name) {
+        Ok(()) => println!("[INFO] Switched to branch {}!", name),
+        Err(()) => {
+            println!("[ERROR] Failed to switch to branch {}!", name);
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+fn branch_remove(repo: &mut Repo, args: Vec<String>){
+    let name = branch_name_arg(&args);
+    match repo.remove_branch(&name) {
+        Ok(()) => println!("[INFO] Removed branch {}!", name),
+        Err(()) => {
+            println!("[ERROR] Failed to remove branch {}, it doesn't exist or is the current branch!", name);
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+// Commits are passed as <branch>:<id>, a plain id refers to the current branch
+fn parse_commit_source(repo: &Repo, arg: &str) -> CommitSource {
+    let (branch, commit_id) = match arg.rsplit_once(':') {
+        Some((branch, commit_id)) => (branch.to_string(), commit_id),
+        None => (repo.current_branch.clone(), arg)
+    };
+    match commit_id.parse::<usize>() {
+        Ok(commit_id) => CommitSource {
+            branch,
+            commit_id
+        },
+        Err(_) => {
+            println!("[ERROR] Failed to parse commit {}!", arg);
+            exit(1);
+        }
+    }
+}
+
+fn report_pick_result(repo: &mut Repo, result: Result<Vec<RepoPath>, ()>){
+    match result {
+        Ok(conflicts) if conflicts.is_empty() => {
+            println!("[INFO] Cherry-picked every commit!");
+            print_head_commit(repo);
+        },
+        Ok(conflicts) => {
+            let current = &repo.pending_cherry_pick.as_ref().unwrap().current;
+            println!("[WARN] These entries could not be picked cleanly from commit {}:", current);
+            for path in conflicts.iter() {
+                println!("    {}", path);
+            }
+            println!("[WARN] Resolve the conflicts, stage them and run \"cherry-pick continue\", or \"cherry-pick abort\" to give up!");
+        },
+        Err(()) => {
+            println!("[ERROR] Failed to cherry-pick!");
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+fn cherry_pick_commits(repo: &mut Repo, args: Vec<String>){
+    if args.len() < 3 {
+        println!("[ERROR] No commit specified!");
+        exit(1);
+    }
+    let sources: Vec<CommitSource> = args[2..].iter().map(|arg| parse_commit_source(repo, arg)).collect();
+    if let Some(operation) = repo.pending_operation() {
+        println!("[ERROR] A {} is in progress, continue or abort it first!", operation);
+        exit(1);
+    }
+    if !repo.staged_files.is_empty() || !repo.staged_removals.is_empty() {
+        println!("[ERROR] Commit or unstage the staged files first!");
+        exit(1);
+    }
+    let blockers = match repo.cherry_pick_blockers(&sources) {
+        Ok(blockers) => blockers,
+        Err(()) => {
+            println!("[ERROR] One of the commits does not exist!");
+            exit(1);
+        }
+    };
+    if !blockers.is_empty() {
+        println!("[ERROR] These entries have uncommitted changes:");
+        for path in blockers.iter() {
+            println!("    {}", path);
+        }
+        println!("[ERROR] Commit, stash or restore them first, nothing was picked!");
+        exit(1);
+    }
+    let result = repo.cherry_pick(sources);
+    report_pick_result(repo, result);
+}
+
+fn cherry_pick_continue(repo: &mut Repo){
+    if repo.pending_cherry_pick.is_none() {
+        println!("[ERROR] No cherry-pick in progress!");
+        exit(1);
+    }
+    let unresolved = repo.cherry_pick_unresolved();
+    if !unresolved.is_empty() {
+        println!("[ERROR] These entries are not resolved and staged yet:");
+        for path in unresolved.iter() {
+            println!("    {}", path);
+        }
+        exit(1);
+    }
+    let result = repo.cherry_pick_continue();
+    report_pick_result(repo, result);
+}
+
+fn cherry_pick_abort(repo: &mut Repo){
+    match repo.cherry_pick_abort() {
+        Ok(()) => println!("[INFO] Cherry-pick aborted, the picked commits were removed!"),
+        Err(()) => {
+            println!("[ERROR] No cherry-pick in progress or failed to restore the touched entries!");
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+// Opens the file in $EDITOR and waits for it to be closed
+fn run_editor(path: &Path) -> bool {
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| String::from("vi"));
+    let mut editor_parts = editor.split_whitespace();
+    let program = match editor_parts.next() {
+        Some(program) => program,
+        None => return false
+    };
+    match Command::new(program).args(editor_parts).arg(path).status() {
+        Ok(status) => status.success(),
+        Err(_) => false
+    }
+}
+
+fn report_rebase_result(repo: &mut Repo, result: Result<Vec<RepoPath>, ()>){
+    match result {
+        Ok(conflicts) if conflicts.is_empty() => {
+            println!("[INFO] Rebased branch {}!", repo.current_branch);
+            branch_list(repo);
+        },
+        Ok(conflicts) => {
+            let rebase_state = repo.get_rebase_state().unwrap();
+            println!("[WARN] These entries could not be replayed cleanly from commit {}:", rebase_state.steps[0].commit_id);
+            for path in conflicts.iter() {
+                println!("    {}", path);
+            }
+            println!("[WARN] Resolve the conflicts, stage them and run \"rebase continue\", or \"rebase abort\" to give up!");
+        },
+        Err(()) => {
+            println!("[ERROR] Failed to rebase!");
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+fn rebase_branch(repo: &mut Repo, args: Vec<String>){
+    let interactive = args[2..].iter().any(|arg| arg == "-i" || arg == "--interactive");
+    let onto = match args[2..].iter().find(|arg| !arg.starts_with('-')) {
+        Some(onto) => onto.clone(),
+        None => {
+            println!("[ERROR] No branch to rebase onto specified!");
+            exit(1);
+        }
+    };
+    if !repo.branches.contains(&onto) || onto == repo.current_branch {
+        println!("[ERROR] Branch {} does not exist or is the current branch!", onto);
+        exit(1);
+    }
+    if let Some(operation) = repo.pending_operation() {
+        println!("[ERROR] A {} is in progress, continue or abort it first!", operation);
+        exit(1);
+    }
+    if !repo.staged_files.is_empty() || !repo.staged_removals.is_empty() {
+        println!("[ERROR] Commit or unstage the staged files first!");
+        exit(1);
+    }
+    let blockers = repo.switch_blockers(&onto).unwrap_or_default();
+    if !blockers.is_empty() {
+        println!("[ERROR] These entries have uncommitted changes or would be overwritten:");
+        for path in blockers.iter() {
+            println!("    {}", path);
+        }
+        println!("[ERROR] Commit, stash or remove them first, nothing was rebased!");
+        exit(1);
+    }
+
+    let mut steps = match repo.rebase_steps(&onto) {
+        Ok(steps) => steps,
+        Err(()) => {
+            println!("[ERROR] Failed to list the commits to rebase!");
+            exit(1);
+        }
+    };
+    if interactive {
+        let todo_path = match repo.write_rebase_todo(&onto, &steps) {
+            Ok(todo_path) => todo_path,
+            Err(()) => {
+                println!("[ERROR] Failed to write the rebase todo list!");
+                exit(1);
+            }
+        };
+        if !run_editor(&todo_path) {
+            println!("[ERROR] The editor failed, nothing was rebased!");
+            exit(1);
+        }
+        let contents = std::fs::read_to_string(&todo_path).unwrap_or_default();
+        steps = match parse_todo(&contents) {
+            Ok(steps) => steps,
+            Err(e) => {
+                println!("[ERROR] Invalid todo list, {}!", e);
+                exit(1);
+            }
+        };
+        if steps.is_empty() {
+            println!("[INFO] The todo list is empty, nothing was rebased!");
+            exit(0);
+        }
+    }
+    let result = repo.rebase(&onto, steps);
+    report_rebase_result(repo, result);
+}
+
+fn rebase_continue(repo: &mut Repo){
+    if !repo.rebase_in_progress() {
+        println!("[ERROR] No rebase in progress!");
+        exit(1);
+    }
+    let unresolved = repo.rebase_unresolved();
+    if !unresolved.is_empty() {
+        println!("[ERROR] These entries are not resolved and staged yet:");
+        for path in unresolved.iter() {
+            println!("    {}", path);
+        }
+        exit(1);
+    }
+    let result = repo.rebase_continue();
+    report_rebase_result(repo, result);
+}
+
+fn rebase_abort(repo: &mut Repo){
+    match repo.rebase_abort() {
+        Ok(()) => println!("[INFO] Rebase aborted, branch {} is back to its original commits!", repo.current_branch),
+        Err(()) => {
+            println!("[ERROR] No rebase in progress or failed to restore the original commits!");
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+// Line ranges are passed as <start>,<end>, counting from 1, either end may be left out
+fn parse_line_range(arg: &str) -> (usize, usize) {
+    let (start, end) = arg.split_once(',').unwrap_or((arg, arg));
+    let parse = |value: &str, default: usize| -> usize {
+        if value.is_empty() {
+            return default;
+        }
+        match value.parse::<usize>() {
+            Ok(line) if line > 0 => line,
+            _ => {
+                println!("[ERROR] Failed to parse line range {}!", arg);
+                exit(1);
+            }
+        }
+    };
+    (parse(start, 1), parse(end, usize::MAX))
+}
+
+fn blame_file(repo: &Repo, args: &[OsString]){
+    let mut path: Option<PathBuf> = None;
+    let mut commit_id: Option<usize> = None;
+    let mut line_range = (1, usize::MAX);
+    let mut index = 2;
+    while index < args.len() {
+        let arg = args[index].to_string_lossy();
+        if arg == "-L" {
+            match args.get(index + 1) {
+                Some(range) => line_range = parse_line_range(&range.to_string_lossy()),
+                None => {
+                    println!("[ERROR] No line range specified!");
+                    exit(1);
+                }
+            }
+            index += 2;
+            continue;
+        }
+        if path.is_none() {
+            path = Some(PathBuf::from(&args[index]));
+        }
+        else {
+            match arg.parse::<usize>() {
+                Ok(id) => commit_id = Some(id),
+                Err(_) => {
+                    println!("[ERROR] Failed to parse commit number!");
+                    exit(1);
+                }
+            }
+        }
+        index += 1;
+    }
+    let path = match path {
+        Some(path) => path,
+        None => {
+            println!("[ERROR] No file specified!");
+            exit(1);
+        }
+    };
+    check_outside_paths(repo, std::slice::from_ref(&path));
+    let commit_id = match commit_id {
+        Some(commit_id) => commit_id,
+        None => match repo.get_branch(&repo.current_branch) {
+            Ok(branch_config) => branch_config.current_commit,
+            Err(()) => {
+                println!("[ERROR] Failed to load branch {}!", repo.current_branch);
+                exit(1);
+            }
+        }
+    };
+
+    let lines = match repo.blame(&path, commit_id) {
+        Ok(lines) => lines,
+        Err(()) => {
+            println!("[ERROR] Failed to blame {} in commit {}!", path.display(), commit_id);
+            exit(1);
+        }
+    };
+    let (start, end) = line_range;
+    if start > lines.len() || start > end {
+        println!("[ERROR] {} has only {} lines!", path.display(), lines.len());
+        exit(1);
+    }
+    let shown = &lines[start - 1..end.min(lines.len())];
+    let id_width = shown.iter().map(|line| line.commit.id.to_string().len()).max().unwrap_or(1);
+    let author_width = shown.iter().map(|line| line.commit.author.chars().count()).max().unwrap_or(0);
+    let number_width = end.min(lines.len()).to_string().len();
+    for (offset, line) in shown.iter().enumerate() {
+        println!("{:>id_width$} {:<author_width$} {} {:>number_width$}) {}",
+            line.commit.id,
+            line.commit.author,
+            line.commit.get_time_formatted(),
+            start + offset,
+            line.content,
+        );
+    }
+}
+
+// A single commit, <first>..<last> with both ends included, or <first>.. up to the last commit of the branch
+fn parse_commit_range(repo: &Repo, arg: &str) -> Vec<usize> {
+    let branch_config = match repo.get_branch(&repo.current_branch) {
+        Ok(branch_config) => branch_config,
+        Err(()) => {
+            println!("[ERROR] Failed to load branch {}!", repo.current_branch);
+            exit(1);
+        }
+    };
+    let (first, last) = match arg.split_once("..") {
+        Some((first, "")) => (parse_commit_arg(first), usize::MAX),
+        Some((first, last)) => (parse_commit_arg(first), parse_commit_arg(last)),
+        None => (parse_commit_arg(arg), parse_commit_arg(arg))
+    };
+    let commit_ids: Vec<usize> = branch_config.get_commits().iter()
+        .map(|commit| commit.id)
+        .filter(|commit_id| *commit_id >= first && *commit_id <= last)
+        .collect();
+    if commit_ids.is_empty() {
+        println!("[ERROR] No commit of branch {} is in {}!", repo.current_branch, arg);
+        exit(1);
+    }
+    commit_ids
+}
+
+fn format_patches(repo: &Repo, args: &[OsString]){
+    let mut range: Option<String> = None;
+    let mut output_dir = PathBuf::from(".");
+    let mut index = 2;
+    while index < args.len() {
+        let arg = args[index].to_string_lossy();
+        match arg.as_ref() {
+            "-o" | "--output-directory" => {
+                output_dir = match args.get(index + 1) {
+                    Some(value) => PathBuf::from(value),
+                    None => {
+                        println!("[ERROR] No value specified for {}!", arg);
+                        exit(1);
+                    }
+                };
+                index += 1;
+            },
+            _ if range.is_none() => range = Some(arg.into_owned()),
+            _ => {
+                println!("[ERROR] Unexpected argument {}!", arg);
+                exit(1);
+            }
+        }
+        index += 1;
+    }
+    let range = match range {
+        Some(range) => range,
+        None => {
+            println!("[ERROR] No commit range specified!");
+            exit(1);
+        }
+    };
+    let commit_ids = parse_commit_range(repo, &range);
+    if let Err(e) = std::fs::create_dir_all(&output_dir) {
+        println!("[ERROR] Failed to create {}: {}!", output_dir.display(), e);
+        exit(1);
+    }
+    let branch_config = repo.get_branch(&repo.current_branch).unwrap();
+    for (number, commit_id) in commit_ids.iter().enumerate() {
+        let patch = match repo.format_patch(*commit_id) {
+            Ok(patch) => patch,
+            Err(()) => {
+                println!("[ERROR] Failed to export commit {}!", commit_id);
+                exit(1);
+            }
+        };
+        let file_name = patch_file_name(number + 1, branch_config.get_commit(*commit_id).unwrap());
+        let patch_path = output_dir.join(file_name);
+        if let Err(e) = std::fs::write(&patch_path, patch) {
+            println!("[ERROR] Failed to write {}: {}!", patch_path.display(), e);
+            exit(1);
+        }
+        println!("{}", patch_path.display());
+    }
+}
+
+fn apply_patches(repo: &mut Repo, args: &[OsString]){
+    let paths = path_args(&args[2..]);
+    if paths.is_empty() {
+        println!("[ERROR] No patch files specified!");
+        exit(1);
+    }
+    let mut patches: Vec<CommitPatch> = vec![];
+    for path in paths.iter() {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                println!("[ERROR] Failed to read {}: {}!", path.display(), e);
+                exit(1);
+            }
+        };
+        match CommitPatch::parse(&text) {
+            Some(patch) => patches.push(patch),
+            None => {
+                println!("[ERROR] {} is not a valid patch!", path.display());
+                exit(1);
+            }
+        }
+    }
+    if let Some(operation) = repo.pending_operation() {
+        println!("[ERROR] A {} is in progress, continue or abort it first!", operation);
+        exit(1);
+    }
+    if !repo.staged_files.is_empty() || !repo.staged_removals.is_empty() {
+        println!("[ERROR] Commit or unstage the staged files first!");
+        exit(1);
+    }
+    let blockers = match repo.patch_blockers(&patches) {
+        Ok(blockers) => blockers,
+        Err(()) => {
+            println!("[ERROR] Failed to compare the working directory with the current commit!");
+            exit(1);
+        }
+    };
+    if !blockers.is_empty() {
+        println!("[ERROR] These entries have uncommitted changes:");
+        for path in blockers.iter() {
+            println!("    {}", path);
+        }
+        println!("[ERROR] Commit, stash or restore them first, nothing was applied!");
+        exit(1);
+    }
+
+    for (index, (path, patch)) in paths.iter().zip(patches.iter()).enumerate() {
+        let conflicts = repo.patch_conflicts(patch);
+        if !conflicts.is_empty() {
+            println!("[ERROR] {} does not apply, these entries don't match:", path.display());
+            for conflict in conflicts.iter() {
+                println!("    {}", conflict);
+            }
+            if index > 0 {
+                println!("[ERROR] The {} patches before it were committed, nothing of it or the ones after it was applied!", index);
+            }
+            repo.close();
+            exit(1);
+        }
+        match repo.apply_patch(patch, &path.to_string_lossy()) {
+            Ok(commit_id) => println!("[INFO] Applied {} ({}) as commit {}!", path.display(), patch.source, commit_id),
+            Err(()) => {
+                println!("[ERROR] Failed to apply {}!", path.display());
+                repo.close();
+                exit(1);
+            }
+        }
+    }
+}
+
+fn parse_commit_arg(arg: &str) -> usize {
+    match arg.parse::<usize>() {
+        Ok(commit_id) => commit_id,
+        Err(_) => {
+            println!("[ERROR] Failed to parse commit number {}!", arg);
+            exit(1);
+        }
+    }
+}
+
+fn report_bisect_step(repo: &Repo, result: Result<BisectStep, ()>){
+    match result {
+        Ok(BisectStep::NeedMarks) => println!("[INFO] Mark a good and a bad commit to start searching!"),
+        Ok(BisectStep::Testing { commit_id, remaining }) => {
+            let steps = usize::BITS - remaining.leading_zeros();
+            println!("[INFO] Restored commit {}, {} commits left to test (about {} steps)", commit_id, remaining, steps);
+        },
+        Ok(BisectStep::Found(commit_id)) => {
+            println!("[INFO] Commit {} is the first bad commit:", commit_id);
+            let branch_config = repo.get_branch(&repo.current_branch).unwrap();
+            if let Some(commit) = branch_config.get_commit(commit_id) {
+                print!("{}", commit);
+            }
+        },
+        Ok(BisectStep::Ambiguous(commit_ids)) => {
+            println!("[WARN] Skipped commits are in the way, the first bad commit is one of these:");
+            for commit_id in commit_ids.iter() {
+                println!("    {}", commit_id);
+            }
+        },
+        Err(()) => {
+            println!("[ERROR] Failed to bisect, marks that contradict each other, marked commits that are no longer on the branch or changes in the working directory are in the way!");
+            exit(1);
+        }
+    }
+}
+
+fn bisect_start(repo: &mut Repo, args: Vec<String>){
+    if let Some(operation) = repo.pending_operation() {
+        println!("[ERROR] A {} is in progress, finish or abort it first!", operation);
+        exit(1);
+    }
+    let blockers = match repo.bisect_blockers() {
+        Ok(blockers) => blockers,
+        Err(()) => {
+            println!("[ERROR] Failed to compare the working directory with the current commit!");
+            exit(1);
+        }
+    };
+    if !blockers.is_empty() {
+        println!("[ERROR] These entries have uncommitted changes:");
+        for path in blockers.iter() {
+            println!("    {}", path);
+        }
+        println!("[ERROR] Commit, stash or restore them first, bisect was not started!");
+        exit(1);
+    }
+    let bad = args.get(3).map(|arg| parse_commit_arg(arg));
+    let good: Vec<usize> = args.iter().skip(4).map(|arg| parse_commit_arg(arg)).collect();
+    let result = repo.bisect_start(bad, good);
+    report_bisect_step(repo, result);
+}
+
+fn bisect_mark(repo: &mut Repo, args: Vec<String>){
+    let mark = match args[2].as_str() {
+        "good" => BisectMark::Good,
+        "bad" => BisectMark::Bad,
+        _ => BisectMark::Skip
+    };
+    let commit_id = args.get(3).map(|arg| parse_commit_arg(arg));
+    let result = repo.bisect_mark(mark, commit_id);
+    report_bisect_step(repo, result);
+}
+
+fn bisect_reset(repo: &mut Repo){
+    match repo.bisect_reset() {
+        Ok(commit_id) => println!("[INFO] Stopped bisecting, the working directory is back at commit {}!", commit_id),
+        Err(()) => {
+            println!("[ERROR] No bisect in progress or failed to restore the working directory!");
+            exit(1);
+        }
+    }
+}
+
+// Marks each restored commit by the exit status of the command: 0 is good, 125 skips the commit,
+// any other status up to 127 is bad and everything else stops the run
+fn bisect_run(repo: &mut Repo, args: Vec<String>){
+    if args.len() < 4 {
+        println!("[ERROR] No command specified!");
+        exit(1);
+    }
+    let command = args[3..].join(" ");
+    let mut step = repo.bisect_step();
+    while let Ok(BisectStep::Testing { commit_id, .. }) = step {
+        println!("[INFO] Running {} on commit {}...", command, commit_id);
+        let status = match Command::new(&args[3]).args(&args[4..]).current_dir(repo.root_dir()).status() {
+            Ok(status) => status,
+            Err(e) => {
+                println!("[ERROR] Cannot run {}: {}", command, e);
+                exit(1);
+            }
+        };
+        let mark = match status.code() {
+            Some(0) => BisectMark::Good,
+            Some(125) => BisectMark::Skip,
+            Some(code) if (1..128).contains(&code) => BisectMark::Bad,
+            _ => {
+                println!("[ERROR] {} failed with {}, stopping the run at commit {}!", command, status, commit_id);
+                exit(1);
+            }
+        };
+        step = repo.bisect_mark(mark, None);
+    }
+    report_bisect_step(repo, step);
+}
+
+fn grep_history(repo: &Repo, args: &[OsString]){
+    let mut pattern: Option<String> = None;
+    let mut commit_id: Option<usize> = None;
+    let mut all_history = false;
+    let mut ignore_case = false;
+    let mut fixed_strings = false;
+    let mut paths: Vec<PathBuf> = vec![];
+    let mut index = 2;
+    while index < args.len() {
+        let arg = args[index].to_string_lossy();
+        match arg.as_ref() {
+            "--" => {
+                paths = args[index + 1..].iter().map(PathBuf::from).collect();
+                break;
+            },
+            "-i" | "--ignore-case" => ignore_case = true,
+            "-F" | "--fixed-strings" => fixed_strings = true,
+            "--all-history" => all_history = true,
+            _ if pattern.is_none() => pattern = Some(arg.into_owned()),
+            _ => commit_id = Some(parse_commit_arg(&arg))
+        }
+        index += 1;
+    }
+    let pattern = match pattern {
+        Some(pattern) if fixed_strings => regex::escape(&pattern),
+        Some(pattern) => pattern,
+        None => {
+            println!("[ERROR] No pattern specified!");
+            exit(1);
+        }
+    };
+    let pattern = match regex::RegexBuilder::new(&pattern).case_insensitive(ignore_case).build() {
+        Ok(pattern) => pattern,
+        Err(e) => {
+            println!("[ERROR] Invalid pattern: {}", e);
+            exit(1);
+        }
+    };
+    let scope = match (all_history, commit_id) {
+        (true, Some(_)) => {
+            println!("[ERROR] Pass either a commit or --all-history, not both!");
+            exit(1);
+        },
+        (true, None) => GrepScope::AllHistory,
+        (false, Some(commit_id)) => GrepScope::Commit(commit_id),
+        (false, None) => match repo.get_branch(&repo.current_branch) {
+            Ok(branch_config) => GrepScope::Commit(branch_config.current_commit),
+            Err(()) => {
+                println!("[ERROR] Failed to load branch {}!", repo.current_branch);
+                exit(1);
+            }
+        }
+    };
+
+    check_outside_paths(repo, &paths);
+    let matches = match repo.grep(&pattern, scope, &paths) {
+        Ok(matches) => matches,
+        Err(()) => {
+            println!("[ERROR] Failed to search the commit!");
+            exit(1);
+        }
+    };
+    if matches.is_empty() {
+        // like grep, finding nothing is reported through the exit status
+        exit(1);
+    }
+    for found in matches.iter() {
+        println!("{}:{}:{}:{}", found.commit_id, found.path, found.line_number, found.line);
+    }
+}
+
+// Lists the plan on a dry run and refuses to overwrite uncommitted changes unless forced or backed up first.
+// Returns true if the restore should go ahead
+fn check_restore(repo: &Repo, plan: &[RestoreStep], blockers: &[RepoPath], destination: Option<&Path>, dry_run: bool, force: bool, backup: bool) -> bool {
+    if dry_run {
+        if plan.is_empty() {
+            println!("[INFO] Nothing would change!");
+        }
+        for step in plan.iter() {
+            println!("    {}", step);
+        }
+        if !blockers.is_empty() && !force && !backup {
+            match destination {
+                Some(_) => println!("[WARN] {} existing entries would need --force or --backup!", blockers.len()),
+                None => println!("[WARN] {} entries with uncommitted changes would need --force or --backup!", blockers.len())
+            }
+        }
+        return false;
+    }
+    if blockers.is_empty() {
+        return true;
+    }
+    if !force && !backup {
+        match destination {
+            Some(destination) => println!("[ERROR] These entries already exist in {} and would be overwritten:", destination.display()),
+            None => println!("[ERROR] These entries have uncommitted changes and would be overwritten or deleted:")
+        }
+        for path in blockers.iter() {
+            println!("    {}", path);
+        }
+        println!("[ERROR] Nothing was restored, pass --force to throw the changes away or --backup to keep a copy!");
+        exit(1);
+    }
+    if backup {
+        match repo.backup_working_entries(blockers, destination) {
+            Ok(backup_dir) => println!("[INFO] Backed up the changed entries to {}!", backup_dir.display()),
+            Err(()) => {
+                println!("[ERROR] Failed to back up the changed entries, nothing was restored!");
+                exit(1);
+            }
+        }
+    }
+    true
+}
+
+fn restore_paths(repo: &Repo, args: &[OsString]){
+    let mut commit_id: Option<usize> = None;
+    let mut destination: Option<PathBuf> = None;
+    let mut to_stdout = false;
+    let mut dry_run = false;
+    let mut force = false;
+    let mut backup = false;
+    let mut paths: Vec<PathBuf> = vec![];
+    let mut index = 2;
+    while index < args.len() {
+        let arg = args[index].to_string_lossy();
+        match arg.as_ref() {
+            "--" => {
+                paths.extend(args[index + 1..].iter().map(PathBuf::from));
+                break;
+            },
+            "--stdout" => to_stdout = true,
+            "--dry-run" => dry_run = true,
+            "--force" => force = true,
+            "--backup" => backup = true,
+            "--to" => {
+                match args.get(index + 1) {
+                    Some(dir) => destination = Some(PathBuf::from(dir)),
+                    None => {
+                        println!("[ERROR] No target directory specified!");
+                        exit(1);
+                    }
+                }
+                index += 1;
+            },
+            _ if commit_id.is_none() => commit_id = Some(parse_commit_arg(&arg)),
+            _ => paths.push(PathBuf::from(&args[index]))
+        }
+        index += 1;
+    }
+    let commit_id = match commit_id {
+        Some(commit_id) => commit_id,
+        None => {
+            println!("[ERROR] No commit specified!");
+            exit(1);
+        }
+    };
+    if paths.is_empty() {
+        println!("[ERROR] No paths specified, use \"commit restore\" to restore a whole commit!");
+        exit(1);
+    }
+    check_outside_paths(repo, &paths);
+
+    if to_stdout {
+        if paths.len() > 1 || destination.is_some() {
+            println!("[ERROR] --stdout takes a single file and no target directory!");
+            exit(1);
+        }
+        match repo.read_committed_file(commit_id, &paths[0]) {
+            Ok(contents) => {
+                let mut stdout = std::io::stdout();
+                if stdout.write_all(&contents).and_then(|_| stdout.flush()).is_err() {
+                    exit(1);
+                }
+            },
+            Err(()) => {
+                println!("[ERROR] Failed to read {} from commit {}!", paths[0].display(), commit_id);
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    let plan = repo.restore_paths_plan(commit_id, &paths, destination.as_deref());
+    let blockers = match (&plan, &destination) {
+        // a different directory holds no uncommitted changes, but whatever is in the way gets overwritten
+        (Ok(plan), Some(_)) => Ok(plan.iter()
+            .filter(|step| step.action == RestoreAction::Overwrite)
+            .map(|step| step.path.clone())
+            .collect()),
+        _ => repo.restore_paths_blockers(commit_id, &paths)
+    };
+    let (plan, blockers) = match (plan, blockers) {
+        (Ok(plan), Ok(blockers)) => (plan, blockers),
+        _ => {
+            println!("[ERROR] Failed to restore the paths from commit {}!", commit_id);
+            exit(1);
+        }
+    };
+    if !check_restore(repo, &plan, &blockers, destination.as_deref(), dry_run, force, backup) {
+        return;
+    }
+
+    match repo.restore_paths(commit_id, &paths, destination.as_deref()) {
+        Ok(restored) => {
+            let target = match &destination {
+                Some(destination) => destination.display().to_string(),
+                None => "the working directory".to_string()
+            };
+            println!("[INFO] Restored {} entries of commit {} into {}!", restored.len(), commit_id, target);
+        },
+        Err(()) => {
+            println!("[ERROR] Failed to restore the paths from commit {}!", commit_id);
+            exit(1);
+        }
+    }
+}
+
+fn archive_commit(repo: &Repo, args: &[OsString]){
+    let mut commit_id: Option<usize> = None;
+    let mut format: Option<ArchiveFormat> = None;
+    let mut output: Option<PathBuf> = None;
+    let mut prefix: Option<String> = None;
+    let mut index = 2;
+    while index < args.len() {
+        let arg = args[index].to_string_lossy();
+        match arg.as_ref() {
+            "--format" | "-o" | "--output" | "--prefix" => {
+                let value = match args.get(index + 1) {
+                    Some(value) => value,
+                    None => {
+                        println!("[ERROR] No value specified for {}!", arg);
+                        exit(1);
+                    }
+                };
+                match arg.as_ref() {
+                    "--format" => match ArchiveFormat::from_name(&value.to_string_lossy()) {
+                        Some(parsed) => format = Some(parsed),
+                        None => {
+                            println!("[ERROR] Unknown archive format {}, use tar, tar.gz or zip!", value.to_string_lossy());
+                            exit(1);
+                        }
+                    },
+                    "--prefix" => prefix = Some(value.to_string_lossy().into_owned()),
+                    _ => output = Some(PathBuf::from(value))
+                }
+                index += 1;
+            },
+            _ if commit_id.is_none() => commit_id = Some(parse_commit_arg(&arg)),
+            _ => {
+                println!("[ERROR] Unexpected argument {}!", arg);
+                exit(1);
+            }
+        }
+        index += 1;
+    }
+    let output = match output {
+        Some(output) => output,
+        None => {
+            println!("[ERROR] No output file specified, pass it with -o!");
+            exit(1);
+        }
+    };
+    // without --format the extension of the output decides
+    let format = format.unwrap_or_else(|| {
+        let name = output.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default();
+        match name.rsplit_once('.') {
+            Some((rest, "gz")) if rest.ends_with(".tar") => ArchiveFormat::TarGz,
+            Some((_, extension)) => ArchiveFormat::from_name(extension).unwrap_or(ArchiveFormat::Tar),
+            None => ArchiveFormat::Tar
+        }
+    });
+    let commit_id = match commit_id {
+        Some(commit_id) => commit_id,
+        None => match repo.get_branch(&repo.current_branch) {
+            Ok(branch_config) => branch_config.current_commit,
+            Err(()) => {
+                println!("[ERROR] Failed to load the current branch!");
+                exit(1);
+            }
+        }
+    };
+
+    match repo.archive(commit_id, format, &output, prefix.as_deref()) {
+        Ok(count) => println!("[INFO] Archived {} entries of commit {} into {}!", count, commit_id, output.display()),
+        Err(()) => {
+            println!("[ERROR] Failed to archive commit {}!", commit_id);
+            exit(1);
+        }
+    }
+}
+
+fn undo_operation(repo: &mut Repo, redo: bool){
+    let blockers = match repo.undo_blockers(redo) {
+        Ok(blockers) => blockers,
+        Err(()) => {
+            println!("[ERROR] Failed to compare the working directory with the other branch!");
+            exit(1);
+        }
+    };
+    if !blockers.is_empty() {
+        println!("[ERROR] These entries have uncommitted changes or would be overwritten:");
+        for path in blockers.iter() {
+            println!("    {}", path);
+        }
+        println!("[ERROR] Commit, stash or remove them first, nothing was changed!");
+        exit(1);
+    }
+    match repo.undo_operation(redo) {
+        Ok(operation) => println!("[INFO] {} {}!", if redo { "Redid" } else { "Undid" }, operation),
+        Err(()) => {
+            println!("[ERROR] Nothing to {}, or a command that can't be undone changed the repository since!",
+                if redo { "redo" } else { "undo" });
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+fn reflog_list(repo: &Repo, args: Vec<String>){
+    let branch = args.get(2).cloned().unwrap_or_else(|| repo.current_branch.clone());
+    let entries = match repo.get_reflog(&branch) {
+        Ok(entries) => entries,
+        Err(()) => {
+            println!("[ERROR] Failed to load the reflog of branch {}!", branch);
+            exit(1);
+        }
+    };
+    if entries.is_empty() {
+        println!("[INFO] The reflog of branch {} is empty", branch);
+        return;
+    }
+    // the newest entry comes first and has number 0
+    for (index, entry) in entries.iter().rev().enumerate() {
+        println!("{}: {}", index, entry);
+    }
+}
+
+fn reflog_restore(repo: &mut Repo, args: Vec<String>){
+    let index = match args.get(3).map(|arg| arg.parse::<usize>()) {
+        Some(Ok(index)) => index,
+        Some(Err(_)) => {
+            println!("[ERROR] Failed to parse reflog entry number!");
+            exit(1);
+        },
+        None => {
+            println!("[ERROR] No reflog entry specified!");
+            exit(1);
+        }
+    };
+    let branch = args.get(4).cloned().unwrap_or_else(|| repo.current_branch.clone());
+    let entries = match repo.get_reflog(&branch) {
+        Ok(entries) => entries,
+        Err(()) => {
+            println!("[ERROR] Failed to load the reflog of branch {}!", branch);
+            exit(1);
+        }
+    };
+    let entry = match entries.iter().rev().nth(index) {
+        Some(entry) => entry.clone(),
+        None => {
+            println!("[ERROR] Reflog entry {} does not exist!", index);
+            exit(1);
+        }
+    };
+    if let Some(operation) = repo.pending_operation() {
+        println!("[ERROR] A {} is in progress, continue or abort it first!", operation);
+        exit(1);
+    }
+    let blockers = match repo.reflog_restore_blockers(&branch, &entry) {
+        Ok(blockers) => blockers,
+        Err(()) => {
+            println!("[ERROR] The commits of reflog entry {} are no longer stored!", index);
+            exit(1);
+        }
+    };
+    if !blockers.is_empty() {
+        println!("[ERROR] These entries have uncommitted changes or would be overwritten:");
+        for path in blockers.iter() {
+            println!("    {}", path);
+        }
+        println!("[ERROR] Commit, stash or remove them first, the branch was not restored!");
+        exit(1);
+    }
+    match repo.reflog_restore(&branch, &entry, &format!("entry {} ({})", index, entry.operation)) {
+        Ok(()) => println!("[INFO] Branch {} is back at commit {}!", branch, entry.new_commit),
+        Err(()) => {
+            println!("[ERROR] Failed to restore branch {} to reflog entry {}!", branch, index);
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+fn config_repo(repo: &mut Repo, args: Vec<String>){
+    if args.len() < 3 {
+        println!("[ERROR] No setting specified!");
+        exit(1);
+    }
+
+    match args[2].as_str() {
+        "chunk_threshold" => {
+            match args.get(3) {
+                Some(value) => {
+                    let chunk_threshold = match value.parse::<u64>() {
+                        Ok(chunk_threshold) => chunk_threshold,
+                        Err(_) => {
+                            println!("[ERROR] Failed to parse size in bytes!");
+                            exit(1);
+                        }
+                    };
+                    repo.set_chunk_threshold(chunk_threshold);
+                    println!("[INFO] Files of {} bytes or more are now stored in chunks!", chunk_threshold);
+                },
+                None => println!("{}", repo.chunk_threshold)
+            }
+        },
+        "rename_threshold" => {
+            match args.get(3) {
+                Some(value) => {
+                    let rename_threshold = match value.trim_end_matches('%').parse::<u8>() {
+                        Ok(rename_threshold) if rename_threshold <= 100 => rename_threshold,
+                        _ => {
+                            println!("[ERROR] Failed to parse a percentage between 0 and 100!");
+                            exit(1);
+                        }
+                    };
+                    repo.set_rename_threshold(rename_threshold);
+                    println!("[INFO] Files that are {}% similar or more now count as moved or copied!", rename_threshold);
+                },
+                None => println!("{}", repo.rename_threshold)
+            }
+        },
+        "author" => {
+            match args.get(3) {
+                Some(_) => {
+                    let author = args[3..].join(" ");
+                    println!("[INFO] New commits are now written by {}!", author);
+                    repo.set_author(author);
+                },
+                None => println!("{}", repo.author())
+            }
+        },
+        _ => {
+            println!("[ERROR] Unknown setting {}!", args[2]);
+            exit(1);
+        }
+    }
+}
+
+// Asks for every hunk whether it should be staged, returns None if the user quit
+fn select_hunks(patch: &FilePatch, path: &RepoPath) -> Option<Vec<Hunk>> {
+    let mut pending: Vec<Hunk> = patch.hunks.iter().rev().cloned().collect();
+    let mut accepted: Vec<Hunk> = vec![];
+    println!("--- a/{}\n+++ b/{}", path, path);
+    while let Some(hunk) = pending.pop() {
+        print!("{}", patch.format_hunk(&hunk));
+        print!("Stage this hunk [y,n,s,q,?]? ");
+        let _ = std::io::stdout().flush();
+        let mut answer = String::new();
+        // the end of the input ends the selection like q does
+        if std::io::stdin().read_line(&mut answer).unwrap_or(0) == 0 {
+            println!();
+            return None;
+        }
+        match answer.trim() {
+            "y" => accepted.push(hunk),
+            "n" => {},
+            "s" => match hunk.split() {
+                Some(parts) => {
+                    println!("Split into {} hunks.", parts.len());
+                    pending.extend(parts.into_iter().rev());
+                },
+                None => {
+                    println!("This hunk can't be split any further.");
+                    pending.push(hunk);
+                }
+            },
+            "q" => return None,
+            _ => {
+                println!("y - stage this hunk\nn - do not stage this hunk\ns - split this hunk into smaller ones\nq - quit, nothing of this file is staged");
+                pending.push(hunk);
+            }
+        }
+    }
+    Some(accepted)
+}
+
+fn add_stage_patch(repo: &mut Repo, paths: Vec<PathBuf>){
+    if paths.is_empty() {
+        println!("[ERROR] No files specified!");
+        exit(1);
+    }
+    warn_outside_paths(repo, &paths);
+    for path in paths.iter() {
+        let (key, base, working) = match repo.patch_contents(path) {
+            Ok(contents) => contents,
+            Err(()) => {
+                println!("[ERROR] Failed to read {}, only files can be staged with --patch!", path.display());
+                continue;
+            }
+        };
+        if is_binary(&base) || is_binary(&working) {
+            println!("[WARN] {} is a binary file, stage it as a whole instead!", key);
+            continue;
+        }
+        // hunks are built from lines of text, bytes that aren't valid UTF-8 would be staged as replacement characters
+        let (base_text, working_text) = match (std::str::from_utf8(&base), std::str::from_utf8(&working)) {
+            (Ok(base_text), Ok(working_text)) => (base_text, working_text),
+            _ => {
+                println!("[WARN] {} is not valid UTF-8, stage it as a whole instead!", key);
+                continue;
+            }
+        };
+        let patch = FilePatch::new(base_text, working_text);
+        if patch.hunks.is_empty() {
+            println!("[INFO] {} has no changes to stage!", key);
+            continue;
+        }
+        let accepted = match select_hunks(&patch, &key) {
+            Some(accepted) => accepted,
+            None => {
+                println!("[INFO] Nothing of {} was staged!", key);
+                break;
+            }
+        };
+        if accepted.is_empty() {
+            println!("[INFO] Nothing of {} was staged!", key);
+            continue;
+        }
+        match repo.stage_contents(&key, patch.apply(&accepted).as_bytes()) {
+            Ok(()) => println!("[INFO] Staged {} hunks of {}!", accepted.len(), key),
+            Err(()) => println!("[ERROR] Failed to stage {}!", key)
+        }
+    }
+}
+
+fn add_stage_files(repo: &mut Repo, args: &[OsString]){
+    if matches!(args.get(3).and_then(|arg| arg.to_str()), Some("-p") | Some("--patch")) {
+        add_stage_patch(repo, path_args(&args[4..]));
+        return;
+    }
+    let tracked_only = match args.get(3).and_then(|arg| arg.to_str()) {
+        Some("-A") | Some("--all") => Some(false),
+        Some("-u") | Some("--update") => Some(true),
+        _ => None
+    };
+    if let Some(tracked_only) = tracked_only {
+        match repo.stage_changes(tracked_only) {
+            Ok(counts) if tracked_only => println!("[INFO] Staged {} modified and {} deleted files!", counts.modified, counts.removed),
+            Ok(counts) => println!("[INFO] Staged {} new, {} modified and {} deleted files!", counts.added, counts.modified, counts.removed),
+            Err(()) => {
+                println!("[ERROR] Failed to compare the working directory with the current commit!");
+                repo.close();
+                exit(1);
+            }
+        }
+        return;
+    }
     let staged_count_prev = repo.staged_files.len();
-    let staged_files_args = &args[3..];
-    let mut staged_files: Vec<PathBuf> = Vec::new();
-    for arg in staged_files_args {
-        let arg_path = PathBuf::from(arg);
-        if arg_path.exists() {
-            staged_files.push(arg_path);
+    let removed_count_prev = repo.staged_removals.len();
+    // paths that are gone are passed on as well, tracked ones get staged as removed
+    let paths = path_args(&args[3..]);
+    warn_outside_paths(repo, &paths);
+    repo.stage_files(&paths);
+    println!("[INFO] Staged {} files!", repo.staged_files.len().saturating_sub(staged_count_prev));
+    if repo.staged_removals.len() > removed_count_prev {
+        println!("[INFO] Staged {} removals!", repo.staged_removals.len() - removed_count_prev);
+    }
+}
+
+fn rm_stage_files(repo: &mut Repo, args: &[OsString]){
+    let mut keep_working = false;
+    let mut force = false;
+    let mut path_start = 3;
+    for arg in args[3..].iter() {
+        match arg.to_str() {
+            Some("--cached") => keep_working = true,
+            Some("-f") | Some("--force") => force = true,
+            _ => break
+        }
+        path_start += 1;
+    }
+    let paths = path_args(&args[path_start..]);
+    if paths.is_empty() {
+        println!("[ERROR] No paths specified!");
+        exit(1);
+    }
+    check_outside_paths(repo, &paths);
+    if !keep_working && !force {
+        let blockers = match repo.removal_blockers(&paths) {
+            Ok(blockers) => blockers,
+            Err(()) => {
+                println!("[ERROR] Failed to compare the paths with the current commit!");
+                repo.close();
+                exit(1);
+            }
+        };
+        if !blockers.is_empty() {
+            println!("[ERROR] These entries have changes that would be lost, use --cached to keep them or --force to delete them anyway:");
+            for blocker in blockers.iter() {
+                println!("    {}", blocker);
+            }
+            repo.close();
+            exit(1);
+        }
+    }
+    match repo.stage_removals(&paths, keep_working) {
+        Ok(removed) => println!("[INFO] Staged {} removals!", removed.len()),
+        Err(()) => {
+            println!("[ERROR] Failed to stage the removal, only entries of the current commit can be removed!");
+            repo.close();
+            exit(1);
+        }
+    }
+}
+
+fn mv_stage_files(repo: &mut Repo, args: &[OsString]){
+    let paths = path_args(&args[3..]);
+    if paths.len() != 2 {
+        println!("[ERROR] Expected the current and the new path!");
+        exit(1);
+    }
+    check_outside_paths(repo, &paths);
+    match repo.stage_rename(&paths[0], &paths[1]) {
+        Ok(renamed_file) if renamed_file.from == renamed_file.to => println!("[INFO] Moved {} back, its staged move is cancelled!", renamed_file.to),
+        Ok(renamed_file) => println!("[INFO] Staged move {}!", renamed_file),
+        Err(()) => {
+            println!("[ERROR] Failed to move {}!", paths[0].display());
+            repo.close();
+            exit(1);
         }
     }
-    repo.stage_files(&staged_files);
-    println!("[INFO] Staged {} files!", repo.staged_files.len() - staged_count_prev);
 }
 
-fn remove_stage_files(repo: &mut Repo, args: Vec<String>){
-    let mut files: Vec<PathBuf> = Vec::new();
-    for file in &args[3..] {
-        files.push(PathBuf::from(file));
+fn remove_stage_files(repo: &mut Repo, args: &[OsString]){
+    let paths = path_args(&args[3..]);
+    warn_outside_paths(repo, &paths);
+    repo.unstage_files(&paths);
+}
+
+// Staged contents whose snapshot is gone can't be committed or stashed
+fn check_snapshots(repo: &Repo){
+    let missing = repo.missing_snapshots();
+    if missing.is_empty() {
+        return;
     }
-    repo.unstage_files(&files);
+    println!("[ERROR] The staged snapshots of these files are missing:");
+    for path in missing.iter() {
+        println!("    {}", path);
+    }
+    println!("[ERROR] Stage them again or unstage them first!");
+    exit(1);
 }
 
 fn commit_add(repo: &mut Repo, args: Vec<String>){
@@ -122,21 +1904,50 @@ fn commit_add(repo: &mut Repo, args: Vec<String>){
     let mut commit_message = String::new();
     for arg in commit_args {
         commit_message.push_str(arg);
-        commit_message.push_str(" ");
+        commit_message.push(' ');
     }
     commit_message.pop();
+    check_snapshots(repo);
     match repo.commit(commit_message) {
         Ok(staged_files_count) => {
             println!("[INFO] Committed {} files!", staged_files_count);
         },
         Err(()) => {
             println!("[ERROR] Failed to commit!");
+            exit(1);
         }
     };
 
     println!("{}", repo.get_branch(&repo.current_branch).unwrap().get_commits().last().unwrap());
 }
 
+fn commit_amend(repo: &mut Repo, args: Vec<String>){
+    let commit_message = match args.iter().position(|arg| arg == "-m") {
+        Some(position) if position + 1 < args.len() => Some(args[position + 1..].join(" ")),
+        Some(_) => {
+            println!("[ERROR] No commit message specified!");
+            exit(1);
+        },
+        None => None
+    };
+    if let Some(operation) = repo.pending_operation() {
+        println!("[ERROR] A {} is in progress, continue or abort it first!", operation);
+        exit(1);
+    }
+    check_snapshots(repo);
+    match repo.amend_commit(commit_message) {
+        Ok(staged_files_count) => {
+            println!("[INFO] Amended commit with {} files!", staged_files_count);
+        },
+        Err(()) => {
+            println!("[ERROR] Failed to amend commit!");
+            exit(1);
+        }
+    };
+
+    print_head_commit(repo);
+}
+
 fn commit_remove(repo: &mut Repo, args: Vec<String>){
     if args.len() < 4 {
         println!("[ERROR] Not enough arguments specified!");
@@ -153,7 +1964,7 @@ fn commit_remove(repo: &mut Repo, args: Vec<String>){
 
     match repo.remove_commit(commit_number) {
         Ok(()) => {
-            println!("[INFO] Removed commit {}!", commit_number);
+            println!("[INFO] Removed commit {}, the reflog can bring it back!", commit_number);
         },
         Err(()) => {
             println!("[ERROR] Failed to remove commit {}!", commit_number);
@@ -166,8 +1977,11 @@ fn commit_restore(repo: &mut Repo, args: Vec<String>){
         println!("[ERROR] Not enough arguments specified!");
         exit(1);
     }
+    let dry_run = args[3..].iter().any(|arg| arg == "--dry-run");
+    let force = args[3..].iter().any(|arg| arg == "--force");
+    let backup = args[3..].iter().any(|arg| arg == "--backup");
 
-    let commit_number = match args.get(3) {
+    let commit_number = match args[3..].iter().find(|arg| !arg.starts_with("--")) {
         Some(arg) => {
             match arg.parse::<usize>() {
                 Ok(commit_number) => commit_number,
@@ -180,6 +1994,19 @@ fn commit_restore(repo: &mut Repo, args: Vec<String>){
         None => repo.get_branch(&repo.current_branch).unwrap().current_commit
     };
 
+    let plan = repo.restore_commit_plan(commit_number);
+    let blockers = repo.restore_commit_blockers(commit_number);
+    let (plan, blockers) = match (plan, blockers) {
+        (Ok(plan), Ok(blockers)) => (plan, blockers),
+        _ => {
+            println!("[ERROR] Failed to restore commit {}!", commit_number);
+            exit(1);
+        }
+    };
+    if !check_restore(repo, &plan, &blockers, None, dry_run, force, backup) {
+        return;
+    }
+
     match repo.restore_commit(commit_number) {
         Ok(()) => {
             println!("[INFO] Restored commit {}!", commit_number);
@@ -217,7 +2044,7 @@ fn commit_list(repo: &Repo, args: Vec<String>){
         None => {
             let commits = repo.get_branch(&repo.current_branch).unwrap().get_commits();        
 
-            if commits.len() > 0 {
+            if !commits.is_empty() {
                 for commit in commits.iter() {
                     print!("{}", commit);
                     println!("----------------------------------------");
@@ -231,8 +2058,63 @@ fn commit_list(repo: &Repo, args: Vec<String>){
 
 }
 
+fn log_history(repo: &Repo, args: &[OsString]){
+    let follow = matches!(args.get(2).and_then(|arg| arg.to_str()), Some("--follow"));
+    let paths = path_args(&args[if follow { 3 } else { 2 }..]);
+    if paths.len() > 1 {
+        println!("[ERROR] Only a single path can be followed!");
+        exit(1);
+    }
+    check_outside_paths(repo, &paths);
+    let branch_config = match repo.get_branch(&repo.current_branch) {
+        Ok(branch_config) => branch_config,
+        Err(()) => {
+            println!("[ERROR] Failed to load branch {}!", repo.current_branch);
+            exit(1);
+        }
+    };
+    let path = match paths.first() {
+        Some(path) => path,
+        None if follow => {
+            println!("[ERROR] --follow needs a path!");
+            exit(1);
+        },
+        None => {
+            for commit in branch_config.get_commits().iter().rev() {
+                print!("{}", commit);
+                println!("----------------------------------------");
+            }
+            return;
+        }
+    };
+
+    let history = match repo.path_history(path, follow) {
+        Ok(history) => history,
+        Err(()) => {
+            println!("[ERROR] Failed to go through the history of {}!", path.display());
+            exit(1);
+        }
+    };
+    if history.is_empty() {
+        println!("[INFO] No commit of branch {} changed {}", repo.current_branch, path.display());
+        return;
+    }
+    for entry in history.iter() {
+        let commit = match branch_config.get_commit(entry.commit_id) {
+            Some(commit) => commit,
+            None => continue
+        };
+        print!("{}", commit);
+        if let Some(renamed) = &entry.renamed {
+            println!("Followed: {}", renamed);
+        }
+        println!("----------------------------------------");
+    }
+}
+
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
+    let args_os: Vec<OsString> = std::env::args_os().collect();
+    let args: Vec<String> = args_os.iter().map(|arg| arg.to_string_lossy().into_owned()).collect();
     if args.len() > 1 {
         match args[1].as_str() {
             "-i" | "init" => {
@@ -241,6 +2123,21 @@ fn main() {
             "-l" | "list" => {
                 list_repo(args);
             },
+            "status" | "-d" | "diff" => {
+                let repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                if args[1] == "status" {
+                    status_repo(&repo);
+                }
+                else {
+                    diff_repo(&repo, args);
+                }
+            },
             "-s" | "stage" => {
                 if args.len() < 3 {
                     println!("[ERROR] Not enough arguments specified!");
@@ -254,12 +2151,19 @@ fn main() {
                         exit(1);
                     }
                 };
+                let before = repo.capture_configs();
                 match args[2].as_str() {
                     "add" => {
-                        add_stage_files(&mut repo, args);
+                        add_stage_files(&mut repo, &args_os);
                     },
                     "remove" => {
-                        remove_stage_files(&mut repo, args);
+                        remove_stage_files(&mut repo, &args_os);
+                    },
+                    "rm" => {
+                        rm_stage_files(&mut repo, &args_os);
+                    },
+                    "mv" => {
+                        mv_stage_files(&mut repo, &args_os);
                     },
                     "clear" => {
                         repo.unstage_all();
@@ -272,6 +2176,7 @@ fn main() {
                     }
                 }
                 repo.close();
+                repo.record_operation(&format!("stage {}", args[2]), before);
             },
             "-c" | "commit" => {
                 
@@ -282,11 +2187,16 @@ fn main() {
                         exit(1);
                     }
                 };
-                
+
+                let command = format!("commit {}", args[2]);
+                let before = repo.capture_configs();
                 match args[2].as_str() {
                     "add" => {
                         commit_add(&mut repo, args);
                     },
+                    "amend" => {
+                        commit_amend(&mut repo, args);
+                    }
                     "remove" => {
                         commit_remove(&mut repo, args);
                     }
@@ -303,17 +2213,298 @@ fn main() {
                     }
                 }
                 repo.close();
-                
+                if command == "commit add" || command == "commit amend" || command == "commit remove" {
+                    repo.record_operation(&command, before);
+                }
             },
             "-b" | "branch" => {
-                if args.len() > 2 {
-                    match args[2].as_str() {
-                        _ => {
-                            // TODO: Implement branch commands
-                            todo!();
-                        }
+                let mut repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                let before = repo.capture_configs();
+                match args.get(2).map(|arg| arg.as_str()) {
+                    Some("list") | None => {
+                        branch_list(&repo);
+                    },
+                    Some("add") => {
+                        branch_add(&mut repo, args);
+                        repo.close();
+                        repo.record_operation("branch add", before);
+                    },
+                    Some("switch") => {
+                        branch_switch(&mut repo, args);
+                        repo.close();
+                        repo.record_operation("branch switch", before);
+                    },
+                    // the commits of a removed branch are deleted, undo couldn't bring them back
+                    Some("remove") => {
+                        branch_remove(&mut repo, args);
+                    },
+                    _ => {
+                        println!("[ERROR] Invalid branch option!");
+                        repo.close();
+                        exit(1);
+                    }
+                }
+                repo.close();
+            },
+            "cherry-pick" => {
+                let mut repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                let before = repo.capture_configs();
+                let command = match args.get(2).map(|arg| arg.as_str()) {
+                    Some("continue") => {
+                        cherry_pick_continue(&mut repo);
+                        "cherry-pick continue"
+                    },
+                    Some("abort") => {
+                        cherry_pick_abort(&mut repo);
+                        "cherry-pick abort"
+                    },
+                    _ => {
+                        cherry_pick_commits(&mut repo, args);
+                        "cherry-pick"
+                    }
+                };
+                repo.close();
+                repo.record_operation(command, before);
+            },
+            "config" => {
+                let mut repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                let before = repo.capture_configs();
+                config_repo(&mut repo, args);
+                repo.close();
+                repo.record_operation("config", before);
+            },
+            // stash and rebase aren't in the operation log: they move entries and commits around outside of the configs,
+            // which putting the configs back wouldn't undo. Undo refuses to go past them once they changed a config
+            "stash" => {
+                let mut repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                match args.get(2).map(|arg| arg.as_str()) {
+                    Some("push") | None => {
+                        stash_push(&mut repo, args);
+                    },
+                    Some("list") => {
+                        stash_list(&repo);
+                    },
+                    Some("show") => {
+                        stash_show(&repo, args);
+                    },
+                    Some("apply") | Some("pop") => {
+                        stash_apply(&mut repo, args);
+                    },
+                    Some("drop") => {
+                        stash_drop(&mut repo, args);
+                    },
+                    _ => {
+                        println!("[ERROR] Invalid stash option!");
+                        repo.close();
+                        exit(1);
+                    }
+                }
+                repo.close();
+            },
+            "revert" => {
+                let mut repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                let before = repo.capture_configs();
+                let command = match args.get(2).map(|arg| arg.as_str()) {
+                    Some("continue") => {
+                        revert_continue(&mut repo);
+                        "revert continue"
+                    },
+                    Some("abort") => {
+                        revert_abort(&mut repo);
+                        "revert abort"
+                    },
+                    _ => {
+                        revert_commit(&mut repo, args);
+                        "revert"
+                    }
+                };
+                repo.close();
+                repo.record_operation(command, before);
+            },
+            "rebase" => {
+                let mut repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                match args.get(2).map(|arg| arg.as_str()) {
+                    Some("continue") => {
+                        rebase_continue(&mut repo);
+                    },
+                    Some("abort") => {
+                        rebase_abort(&mut repo);
+                    },
+                    _ => {
+                        rebase_branch(&mut repo, args);
+                    }
+                }
+                repo.close();
+            },
+            "bisect" => {
+                let mut repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                match args.get(2).map(|arg| arg.as_str()) {
+                    Some("start") => {
+                        bisect_start(&mut repo, args);
+                    },
+                    Some("good") | Some("bad") | Some("skip") => {
+                        bisect_mark(&mut repo, args);
+                    },
+                    Some("reset") => {
+                        bisect_reset(&mut repo);
+                    },
+                    Some("run") => {
+                        bisect_run(&mut repo, args);
+                    },
+                    _ => {
+                        println!("[ERROR] Invalid bisect option!");
+                        repo.close();
+                        exit(1);
+                    }
+                }
+                repo.close();
+            },
+            "restore" => {
+                let repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                restore_paths(&repo, &args_os);
+            },
+            "format-patch" => {
+                let repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                format_patches(&repo, &args_os);
+            },
+            "apply-patch" => {
+                let mut repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                let before = repo.capture_configs();
+                apply_patches(&mut repo, &args_os);
+                repo.close();
+                repo.record_operation("apply-patch", before);
+            },
+            "archive" => {
+                let repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                archive_commit(&repo, &args_os);
+            },
+            "grep" => {
+                let repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                grep_history(&repo, &args_os);
+            },
+            "log" => {
+                let repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                log_history(&repo, &args_os);
+            },
+            "blame" => {
+                let repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                blame_file(&repo, &args_os);
+            },
+            "undo" | "redo" => {
+                let mut repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                undo_operation(&mut repo, args[1] == "redo");
+                repo.close();
+            },
+            "reflog" => {
+                let mut repo = match Repo::from_file(None) {
+                    Ok(repo) => repo,
+                    Err(_) => {
+                        println!("[ERROR] Failed to load repository metafile! Exiting...");
+                        exit(1);
+                    }
+                };
+                match args.get(2).map(|arg| arg.as_str()) {
+                    Some("restore") => {
+                        let before = repo.capture_configs();
+                        reflog_restore(&mut repo, args);
+                        repo.close();
+                        repo.record_operation("reflog restore", before);
+                    },
+                    _ => {
+                        reflog_list(&repo, args);
                     }
                 }
+                repo.close();
             },
             "-h" | "help" => {
                 usage(args[0].as_str());